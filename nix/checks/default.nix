@@ -4,4 +4,8 @@
 }: {
   formatted = import ./formatted.nix {inherit inputs system;};
   nixos = import ./nixos.nix {inherit inputs system;};
+  nixos-postgres = import ./nixos.nix {
+    inherit inputs system;
+    postgres = true;
+  };
 }
@@ -3,9 +3,13 @@
   system ? builtins.currentSystem or "unknown-system",
   pkgs ? import ../nixpkgs.nix {inherit inputs system;},
   typhon ? import ../nixos/typhon.nix {inherit inputs;},
+  postgres ? false,
 }:
 pkgs.testers.nixosTest ({pkgs, ...}: {
-  name = "typhon-test";
+  name =
+    if postgres
+    then "typhon-postgres-test"
+    else "typhon-test";
 
   nodes = {
     typhon = {...}: {
@@ -17,6 +21,17 @@ pkgs.testers.nixosTest ({pkgs, ...}: {
         hashedPasswordFile = builtins.toString (pkgs.runCommand "password" {} ''
           echo -n "password" | ${pkgs.libargon2}/bin/argon2 "Guérande" -id -e > $out
         '');
+        databaseUrl = pkgs.lib.mkIf postgres "postgresql:///typhon?host=/run/postgresql";
+      };
+      services.postgresql = pkgs.lib.mkIf postgres {
+        enable = true;
+        ensureDatabases = ["typhon"];
+        ensureUsers = [
+          {
+            name = "typhon";
+            ensureDBOwnership = true;
+          }
+        ];
       };
       services.nginx = {
         enable = true;
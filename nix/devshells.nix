@@ -28,8 +28,10 @@ in {
         leptosfmt
         libargon2
         nix
+        nix-eval-jobs
         nodejs # npm
         pkg-config
+        postgresql
         rust-analyzer
         rustfmt
         sqlite
@@ -6,5 +6,9 @@ in {
     doc = import ./packages/doc.nix {inherit inputs system;};
     formatted = import ./checks/formatted.nix {inherit inputs system;};
     nixos = import ./checks/nixos.nix {inherit inputs system;};
+    nixos-postgres = import ./checks/nixos.nix {
+      inherit inputs system;
+      postgres = true;
+    };
   };
 }
@@ -2,9 +2,11 @@ utils: lib: {
   mkProject = args @ {
     actions ? {},
     meta ? {},
+    reporters ? [],
+    sandbox ? {},
     secrets ? null,
   }: {
-    inherit meta;
+    inherit meta reporters sandbox;
     actions = lib.eachSystem (
       system: let
         pkgs = utils.pkgs.${system};
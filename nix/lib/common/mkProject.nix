@@ -5,6 +5,7 @@ _: lib: {
     deploy,
     description,
     flake,
+    forge,
     homepage,
     owner,
     repo,
@@ -27,36 +28,23 @@ _: lib: {
         urlPrefix
         ;
     };
-    status = common.mkStatus {
-      inherit
-        api
-        authorizationKeyword
-        owner
-        repo
-        tokenName
-        typhonUrl
-        ;
-    };
     webhook = common.mkWebhook {inherit webhookSecretName;};
   in
     lib.builders.mkProject {
       meta = {inherit title description homepage;};
+      # statuses are posted by the reporters, the steps are kept for deploys
       actions = {
         inherit jobsets webhook;
-        begin = lib.compose.steps [
-          {
-            name = "Set status";
-            value = status;
-          }
-        ];
-        end = lib.compose.steps ([
-            {
-              name = "Set status";
-              value = status;
-            }
-          ]
-          ++ deploy);
+        begin = lib.compose.steps [];
+        end = lib.compose.steps deploy;
       };
+      reporters = [
+        {
+          type = forge;
+          token = tokenName;
+          inherit api owner repo typhonUrl;
+        }
+      ];
       inherit secrets;
     };
 }
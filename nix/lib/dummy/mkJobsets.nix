@@ -3,13 +3,14 @@ utils: lib: {
     url,
     flake ? true,
     refs ? {},
+    schedule ? null,
   }: let
     jobsets = utils.lib.genAttrs refs (
       ref: {
         url = builtins.flakeRefToString (
           (builtins.parseFlakeRef url) // {inherit ref;}
         );
-        inherit flake;
+        inherit flake schedule;
       }
     );
   in
@@ -3,6 +3,7 @@ _: lib: {
     url,
     flake ? true,
     refs ? ["main"],
+    schedule ? null,
     title ? "",
     description ? "",
     homepage ? "",
@@ -10,7 +11,7 @@ _: lib: {
     lib.builders.mkProject {
       meta = {inherit title description homepage;};
       actions = {
-        jobsets = lib.dummy.mkJobsets {inherit url flake refs;};
+        jobsets = lib.dummy.mkJobsets {inherit url flake refs schedule;};
         begin = lib.dummy.status;
         end = lib.dummy.status;
         webhook = lib.dummy.webhook;
@@ -15,6 +15,7 @@ _: lib: {
       // {
         inherit deploy description flake homepage title;
         api = "${instance}/api/v1";
+        forge = "gitea";
         authorizationKeyword = "token";
         tokenName = "gitea_token";
         urlPrefix = "git+https://${instance}/${owner}/${repo}?ref=";
@@ -14,6 +14,7 @@ _: lib: {
       // {
         inherit deploy description flake homepage title;
         api = "api.github.com";
+        forge = "github";
         authorizationKeyword = "Bearer";
         tokenName = "github_token";
         urlPrefix = "github:${owner}/${repo}/";
@@ -31,6 +31,12 @@ in {
       default = "/var/lib/typhon";
       description = "Home directory for the Typhon instance";
     };
+    databaseUrl = mkOption {
+      type = types.str;
+      default = "typhon.sqlite";
+      example = "postgresql:///typhon?host=/run/postgresql";
+      description = "The database of the Typhon instance: a path to an SQLite file, relative to the home directory, or a PostgreSQL URL";
+    };
     hashedPassword = mkOption {
       type = types.nullOr types.str;
       default = null;
@@ -78,18 +84,18 @@ in {
     systemd.services.typhon = {
       description = "Typhon service";
       wantedBy = ["multi-user.target"];
-      path = [pkgs.nix pkgs.git pkgs.bubblewrap pkgs.openssh];
+      path = [pkgs.nix pkgs.nix-eval-jobs pkgs.git pkgs.bubblewrap pkgs.curl pkgs.openssh];
       serviceConfig = {
         ExecStart = pkgs.writeShellScript "typhon-start" ''
           cd ${cfg.home}
-          DATABASE_URL="typhon.sqlite" ${cfg.package}/bin/typhon -p "$(cat ${cfg.hashedPasswordFile})" -v
+          DATABASE_URL=${lib.escapeShellArg cfg.databaseUrl} ${cfg.package}/bin/typhon -p "$(cat ${cfg.hashedPasswordFile})" -v
         '';
         Type = "simple";
         User = "typhon";
         Group = "typhon";
       };
       requires = ["typhon-init.service"];
-      after = ["typhon-init.service"];
+      after = ["typhon-init.service" "postgresql.service"];
     };
   };
 }
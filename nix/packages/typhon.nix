@@ -16,6 +16,8 @@
       "Cargo.lock"
       "typhon.*"
     ];
+    buildInputs = [pkgs.postgresql.lib];
+    PQ_LIB_DIR = "${pkgs.postgresql.lib}/lib";
   };
 
   cargoArtifacts = craneLib.buildDepsOnly args;
@@ -0,0 +1,195 @@
+use typhon_types::agent::{AgentMsg, ServerMsg};
+
+use clap::Parser;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+/// Typhon build agent, builds derivations on behalf of a Typhon instance
+#[derive(Parser)]
+#[command(name = "Typhon agent")]
+pub struct Args {
+    /// The Unix socket on which Typhon accepts agents, usually forwarded from
+    /// the server with `ssh -L`
+    #[arg(long, short, env)]
+    pub socket: String,
+
+    /// The password of build agents
+    #[arg(long, short, env)]
+    pub password: String,
+
+    /// The systems this agent builds for (defaults to the current system)
+    #[arg(long, value_delimiter = ',', env)]
+    pub systems: Vec<String>,
+
+    /// The system features supported by this agent, e.g. `kvm,big-parallel`
+    #[arg(long, value_delimiter = ',', env)]
+    pub features: Vec<String>,
+
+    /// The number of builds run at once
+    #[arg(long, env, default_value_t = 1)]
+    pub max_jobs: usize,
+
+    /// A Nix store shared with Typhon, e.g. `ssh://typhon.example.org`.
+    /// Derivations are copied from it before being built, and their outputs
+    /// are copied back to it. Leave empty if the agent and Typhon share a store.
+    #[arg(long, env)]
+    pub store: Option<String>,
+}
+
+async fn current_system() -> String {
+    let output = Command::new("nix")
+        .args([
+            "eval",
+            "--impure",
+            "--raw",
+            "--expr",
+            "builtins.currentSystem",
+        ])
+        .output()
+        .await
+        .expect("failed to run nix");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+async fn copy(args: &[&str]) -> Option<()> {
+    let status = Command::new("nix")
+        .arg("copy")
+        .args(args)
+        .stdin(Stdio::null())
+        .status()
+        .await
+        .ok()?;
+    status.success().then_some(())
+}
+
+async fn run(
+    args: &Args,
+    build: u64,
+    drv: &str,
+    sender: &mpsc::UnboundedSender<AgentMsg>,
+) -> Option<HashMap<String, String>> {
+    let mut command = Command::new("nix");
+    command.args([
+        "build",
+        "--log-format",
+        "internal-json",
+        "--json",
+        "--no-link",
+    ]);
+    if let Some(store) = &args.store {
+        copy(&["--derivation", "--from", store.as_str(), drv]).await?;
+        command.args(["--extra-substituters", store]);
+    }
+    let mut child = command
+        .arg(format!("{}^*", drv))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .ok()?;
+    let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let _ = sender.send(AgentMsg::Log { build, line });
+    }
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .await
+        .ok()?;
+    if !child.wait().await.ok()?.success() {
+        return None;
+    }
+    let json: serde_json::Value = serde_json::from_str(&stdout).ok()?;
+    let outputs: HashMap<String, String> = json[0]["outputs"]
+        .as_object()?
+        .iter()
+        .filter_map(|(name, path)| Some((name.clone(), path.as_str()?.to_string())))
+        .collect();
+    if let Some(store) = &args.store {
+        let mut copy_args = vec!["--no-check-sigs", "--to", store.as_str()];
+        copy_args.extend(outputs.values().map(|path| path.as_str()));
+        copy(&copy_args).await?;
+    }
+    Some(outputs)
+}
+
+#[tokio::main]
+async fn main() {
+    tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::new()).unwrap();
+
+    let mut args = Args::parse();
+    if args.systems.is_empty() {
+        args.systems = vec![current_system().await];
+    }
+    let args = Arc::new(args);
+
+    let stream = UnixStream::connect(&args.socket)
+        .await
+        .expect("failed to connect to Typhon");
+    let (reader, mut writer) = stream.into_split();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let _ = sender.send(AgentMsg::Hello {
+        password: args.password.clone(),
+        systems: args.systems.clone(),
+        features: args.features.clone(),
+        max_jobs: args.max_jobs,
+    });
+    tokio::spawn(async move {
+        while let Some(msg) = receiver.recv().await {
+            let line = format!("{}\n", serde_json::to_string(&msg).unwrap());
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut builds: HashMap<u64, AbortHandle> = HashMap::new();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let msg = match serde_json::from_str(&line) {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::warn!("unexpected message from Typhon: {}", e);
+                continue;
+            }
+        };
+        match msg {
+            ServerMsg::Welcome => {
+                tracing::info!(
+                    "connected to {} for systems {:?}",
+                    args.socket,
+                    args.systems
+                )
+            }
+            ServerMsg::Build { build, drv } => {
+                tracing::info!("building {}", drv);
+                builds.retain(|_, handle| !handle.is_finished());
+                let args = args.clone();
+                let sender = sender.clone();
+                let handle = tokio::spawn(async move {
+                    let outputs = run(&args, build, &drv, &sender).await;
+                    let _ = sender.send(AgentMsg::Done { build, outputs });
+                });
+                builds.insert(build, handle.abort_handle());
+            }
+            ServerMsg::Cancel { build } => {
+                // aborting the task kills the `nix build` process
+                if let Some(handle) = builds.remove(&build) {
+                    handle.abort();
+                }
+            }
+        }
+    }
+    tracing::error!("disconnected from Typhon");
+    std::process::exit(1);
+}
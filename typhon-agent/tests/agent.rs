@@ -0,0 +1,81 @@
+//! Drives a `typhon-agent` process from the socket it connects to, in place
+//! of Typhon
+
+use typhon_types::agent::{AgentMsg, ServerMsg};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixListener;
+use tokio::process::Command;
+
+use std::time::Duration;
+
+async fn recv(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> AgentMsg {
+    let line = tokio::time::timeout(Duration::from_secs(60), lines.next_line())
+        .await
+        .expect("the agent did not answer")
+        .unwrap()
+        .expect("the agent disconnected");
+    serde_json::from_str(&line).unwrap()
+}
+
+async fn send(writer: &mut OwnedWriteHalf, msg: ServerMsg) {
+    let line = format!("{}\n", serde_json::to_string(&msg).unwrap());
+    writer.write_all(line.as_bytes()).await.unwrap();
+}
+
+#[tokio::test]
+async fn agent() {
+    let dir = std::env::temp_dir().join(format!("typhon-agent-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("agents.sock");
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket).unwrap();
+
+    let mut agent = Command::new(env!("CARGO_BIN_EXE_typhon-agent"))
+        .args(["--socket", socket.to_str().unwrap()])
+        .args(["--password", "secret"])
+        .args(["--systems", "aarch64-linux,x86_64-linux"])
+        .args(["--features", "kvm"])
+        .args(["--max-jobs", "2"])
+        .env_remove("STORE")
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let hello = AgentMsg::Hello {
+        password: "secret".to_string(),
+        systems: vec!["aarch64-linux".to_string(), "x86_64-linux".to_string()],
+        features: vec!["kvm".to_string()],
+        max_jobs: 2,
+    };
+    assert_eq!(recv(&mut lines).await, hello);
+
+    // the derivation does not exist, the build fails whether Nix is installed
+    // or not
+    send(&mut writer, ServerMsg::Welcome).await;
+    let drv = "/nix/store/00000000000000000000000000000000-missing.drv".to_string();
+    send(&mut writer, ServerMsg::Build { build: 7, drv }).await;
+    loop {
+        match recv(&mut lines).await {
+            AgentMsg::Log { build, .. } => assert_eq!(build, 7),
+            AgentMsg::Done { build, outputs } => {
+                assert_eq!((build, outputs), (7, None));
+                break;
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    // the agent exits when Typhon goes away
+    drop((lines, writer));
+    let status = tokio::time::timeout(Duration::from_secs(60), agent.wait())
+        .await
+        .expect("the agent did not exit")
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    let _ = std::fs::remove_dir_all(dir);
+}
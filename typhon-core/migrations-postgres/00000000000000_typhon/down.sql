@@ -0,0 +1,9 @@
+DROP TABLE runs;
+DROP TABLE actions;
+DROP TABLE builds;
+DROP TABLE jobs;
+DROP TABLE evaluations;
+DROP TABLE jobsets;
+DROP TABLE projects;
+DROP TABLE tasks;
+DROP TABLE logs;
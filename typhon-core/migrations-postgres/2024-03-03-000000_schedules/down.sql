@@ -0,0 +1 @@
+ALTER TABLE jobsets DROP COLUMN schedule;
@@ -0,0 +1,5 @@
+CREATE TABLE events (
+    data TEXT NOT NULL,
+    id BIGSERIAL PRIMARY KEY,
+    time_created BIGINT NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE logs ADD COLUMN path TEXT;
+ALTER TABLE logs ADD COLUMN size BIGINT;
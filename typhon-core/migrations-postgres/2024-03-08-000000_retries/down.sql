@@ -0,0 +1,5 @@
+ALTER TABLE runs DROP COLUMN retry_reason;
+ALTER TABLE jobs DROP COLUMN retry_at;
+ALTER TABLE jobs DROP COLUMN retry;
+ALTER TABLE evaluations DROP COLUMN retry;
+ALTER TABLE jobsets DROP COLUMN retry;
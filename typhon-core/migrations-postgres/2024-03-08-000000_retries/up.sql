@@ -0,0 +1,5 @@
+ALTER TABLE jobsets ADD COLUMN retry TEXT;
+ALTER TABLE evaluations ADD COLUMN retry TEXT;
+ALTER TABLE jobs ADD COLUMN retry TEXT;
+ALTER TABLE jobs ADD COLUMN retry_at BIGINT;
+ALTER TABLE runs ADD COLUMN retry_reason TEXT;
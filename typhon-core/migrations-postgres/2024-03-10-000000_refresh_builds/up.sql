@@ -0,0 +1 @@
+ALTER TABLE projects ADD COLUMN last_refresh_build_id INTEGER REFERENCES builds (id);
@@ -0,0 +1,15 @@
+CREATE TABLE users (
+    admin BOOL NOT NULL,
+    id INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    password TEXT NOT NULL,
+    UNIQUE (name)
+);
+
+CREATE TABLE roles (
+    id INTEGER NOT NULL PRIMARY KEY,
+    project_id INTEGER NOT NULL REFERENCES projects (id),
+    role INTEGER NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id),
+    UNIQUE (project_id, user_id)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE tokens (
+    expiration BIGINT,
+    hash TEXT NOT NULL,
+    id INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    project_id INTEGER REFERENCES projects (id),
+    role INTEGER,
+    user_id INTEGER NOT NULL REFERENCES users (id),
+    UNIQUE (hash),
+    UNIQUE (user_id, name)
+);
@@ -0,0 +1 @@
+ALTER TABLE jobsets ADD COLUMN schedule TEXT;
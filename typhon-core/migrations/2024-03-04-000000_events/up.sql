@@ -0,0 +1,5 @@
+CREATE TABLE events (
+    data TEXT NOT NULL,
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    time_created BIGINT NOT NULL
+);
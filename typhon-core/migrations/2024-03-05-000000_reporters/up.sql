@@ -0,0 +1 @@
+ALTER TABLE projects ADD COLUMN reporters TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,2 @@
+ALTER TABLE logs DROP COLUMN path;
+ALTER TABLE logs DROP COLUMN size;
@@ -0,0 +1,3 @@
+ALTER TABLE actions DROP COLUMN output_error;
+ALTER TABLE actions DROP COLUMN output;
+ALTER TABLE actions DROP COLUMN exit_code;
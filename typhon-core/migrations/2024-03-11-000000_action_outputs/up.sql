@@ -0,0 +1,3 @@
+ALTER TABLE actions ADD COLUMN exit_code INTEGER;
+ALTER TABLE actions ADD COLUMN output TEXT;
+ALTER TABLE actions ADD COLUMN output_error TEXT;
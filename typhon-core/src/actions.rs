@@ -1,15 +1,22 @@
 use crate::error;
+use crate::jobsets;
 use crate::models;
 use crate::projects;
+use crate::queue;
+use crate::sandbox;
 use crate::schema;
 use crate::tasks;
+use crate::timeouts;
 use crate::Conn;
+use crate::Settings;
+use crate::POOL;
 
 use typhon_types::data::TaskStatusKind;
 use typhon_types::*;
 
 use diesel::prelude::*;
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::iter;
@@ -22,8 +29,10 @@ pub enum Error {
     InvalidKey,
     InvalidSecrets,
     NonUtf8,
+    SandboxUnavailable,
     ScriptNotFound,
     SecretsNotFound,
+    TimedOut,
     WrongRecipient,
     Unexpected,
 }
@@ -35,45 +44,19 @@ impl std::fmt::Display for Error {
             InvalidKey => write!(f, "Invalid key"),
             InvalidSecrets => write!(f, "Wrong secrets format"),
             NonUtf8 => write!(f, "Action outputted non-UTF8 characters"),
+            SandboxUnavailable => write!(f, "Action sandbox failed to start"),
             ScriptNotFound => write!(f, "Action script not found"),
             SecretsNotFound => write!(f, "Secrets file not found"),
+            TimedOut => write!(f, "Action timed out"),
             WrongRecipient => write!(f, "Secrets file uncrypted with wrong key"),
             Unexpected => write!(f, "Unexpected error"),
         }
     }
 }
 
-mod sandboxed_command {
-    use tokio::process::Command;
-    pub fn new() -> Command {
-        let mut command = Command::new("bwrap");
-        command
-            .kill_on_drop(true)
-            .args(["--proc", "/proc"])
-            .args(["--dev", "/dev"])
-            .args(["--ro-bind", "/nix/store", "/nix/store"])
-            .args(["--ro-bind", "/nix/var/nix", "/nix/var/nix"])
-            .args(["--ro-bind", "/etc/resolv.conf", "/etc/resolv.conf"])
-            .arg("--clearenv")
-            .arg("--unshare-pid");
-        command
-    }
-}
-
-async fn action(
-    project: &projects::Project,
-    path: &String,
-    name: &String,
-    input: &Value,
-    sender: mpsc::UnboundedSender<String>,
-) -> Result<String, Error> {
-    use tokio::io::AsyncBufReadExt;
-    use tokio::io::AsyncReadExt;
-    use tokio::io::AsyncWriteExt;
-    use tokio::io::BufReader;
-
-    let key =
-        age::x25519::Identity::from_str(&project.project.key).map_err(|_| Error::InvalidKey)?;
+/// Decrypts the secrets of a project, found in its actions directory `path`.
+pub fn secrets(key: &str, path: &str) -> Result<Value, Error> {
+    let key = age::x25519::Identity::from_str(key).map_err(|_| Error::InvalidKey)?;
 
     let decrypted = File::open(&format!("{}/secrets", path))
         .map(|encrypted| {
@@ -95,20 +78,79 @@ async fn action(
             Ok(decrypted)
         })
         .unwrap_or(Ok::<String, Error>("{}".to_string()))?;
-    let secrets: Value = serde_json::from_str(&decrypted).map_err(|_| Error::InvalidSecrets)?;
+    serde_json::from_str(&decrypted).map_err(|_| Error::InvalidSecrets)
+}
+
+/// What an action script returned
+pub struct Output {
+    /// `None` if the script was killed by a signal
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+}
+
+/// Checks the output of an action against the format expected for its name.
+/// Only the outputs of `jobsets` and `webhook` are used, the others can be
+/// empty.
+pub fn validate(name: &str, stdout: &str) -> Result<Value, String> {
+    fn parse<T: serde::de::DeserializeOwned>(stdout: &str) -> Result<Value, String> {
+        serde_json::from_str::<T>(stdout).map_err(|e| e.to_string())?;
+        serde_json::from_str(stdout).map_err(|e| e.to_string())
+    }
+    match name {
+        "jobsets" => parse::<HashMap<String, jobsets::JobsetDecl>>(stdout),
+        "webhook" => parse::<webhooks::Output>(stdout),
+        _ if stdout.trim().is_empty() => Ok(Value::Null),
+        _ => parse::<Value>(stdout),
+    }
+}
+
+async fn action(
+    project: &projects::Project,
+    path: &String,
+    name: &String,
+    input: &Value,
+    sender: mpsc::UnboundedSender<String>,
+) -> Result<Output, Error> {
+    use tokio::io::AsyncBufReadExt;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::io::BufReader;
+
+    // a project only has the actions it defines, a missing one is skipped
+    let script = format!("{}/{}", path, name);
+    if !std::path::Path::new(&script).exists() {
+        let _ = sender.send(format!("typhon: no `{}` action, skipped", name));
+        return Ok(Output {
+            exit_code: Some(0),
+            stdout: String::new(),
+        });
+    }
+
+    let secrets = secrets(&project.project.key, path)?;
 
     let action_input = json!({
         "input": input,
         "secrets": secrets,
     });
 
-    let mut child = sandboxed_command::new()
-        .arg(&format!("{}/{}", path, name))
-        .stdin(Stdio::piped())
-        .stderr(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("command bwrap failed to start");
+    let narrowing: sandbox::Narrowing = project
+        .project
+        .sandbox
+        .as_ref()
+        .and_then(|sandbox| serde_json::from_str(sandbox).ok())
+        .unwrap_or_default();
+    let (mut child, group) = timeouts::ProcessGroup::spawn(
+        Settings::get()
+            .sandbox
+            .command(&narrowing, &script)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped()),
+    )
+    .map_err(|e| {
+        let _ = sender.send(format!("typhon: failed to start the sandbox: {}", e));
+        Error::SandboxUnavailable
+    })?;
     let mut stdin = child.stdin.take().ok_or(Error::Unexpected)?;
     let mut stdout = child.stdout.take().ok_or(Error::Unexpected)?;
     let stderr = child.stderr.take().ok_or(Error::Unexpected)?;
@@ -118,19 +160,48 @@ async fn action(
         .map_err(|_| Error::Unexpected)?;
     drop(stdin); // send EOF
 
-    let buffer = BufReader::new(stderr);
-    let mut lines = buffer.lines();
-    while let Some(line) = lines.next_line().await.unwrap() {
-        let _ = sender.send(line);
-    }
-
+    // both pipes are read at once, so that neither blocks the script
+    let log = async {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let _ = sender.send(line);
+        }
+    };
     let mut res = String::new();
-    stdout
-        .read_to_string(&mut res)
-        .await
-        .map_err(|_| Error::NonUtf8)?;
+    let (read, ()) = tokio::join!(stdout.read_to_string(&mut res), log);
+    read.map_err(|_| Error::NonUtf8)?;
+
+    let status = child.wait().await.map_err(|_| Error::Unexpected)?;
+    group.release();
+
+    Ok(Output {
+        exit_code: status.code(),
+        stdout: res,
+    })
+}
 
-    Ok(res)
+/// Builds the `ActionInfo` of an action of `project`
+pub fn info(
+    project: handles::Project,
+    action: models::Action,
+    status: responses::TaskStatus,
+) -> responses::ActionInfo {
+    let result = match (&action.output, &action.output_error) {
+        (Some(output), None) => validate(&action.name, output).ok(),
+        _ => None,
+    };
+    responses::ActionInfo {
+        handle: handles::action(uuid::Uuid::from_str(&action.uuid).unwrap()),
+        exit_code: action.exit_code,
+        input: action.input,
+        name: action.name,
+        output: action.output,
+        output_error: action.output_error,
+        path: action.path,
+        project,
+        result,
+        status,
+    }
 }
 
 #[derive(Clone)]
@@ -162,50 +233,88 @@ impl Action {
     }
 
     pub fn info(&self) -> responses::ActionInfo {
-        responses::ActionInfo {
-            handle: self.handle(),
-            input: self.action.input.clone(),
-            name: self.action.name.clone(),
-            path: self.action.path.clone(),
-            project: handles::project(self.project.name.clone()),
-            status: self.task.status(),
-        }
+        info(
+            handles::project(self.project.name.clone()),
+            self.action.clone(),
+            self.task.status(),
+        )
+    }
+
+    /// Records the output of the script, returns whether the action succeeded:
+    /// the script exited with code 0 and its output is valid
+    fn set_output(&self, conn: &mut Conn, output: &Output) -> Result<bool, error::Error> {
+        let output_error = validate(&self.action.name, &output.stdout).err();
+        diesel::update(&self.action)
+            .set((
+                schema::actions::exit_code.eq(output.exit_code),
+                schema::actions::output.eq(&output.stdout),
+                schema::actions::output_error.eq(&output_error),
+            ))
+            .execute(conn)?;
+        Ok(output.exit_code == Some(0) && output_error.is_none())
     }
 
     pub fn spawn<F: (FnOnce(Option<String>) -> TaskStatusKind) + Send + Sync + 'static>(
         &self,
         conn: &mut Conn,
+        priority: queue::Priority,
         finish: F,
     ) -> Result<(), error::Error> {
         use crate::log_event;
 
         let run = {
             let self_ = self.clone();
-            move |sender| async move {
-                action(
-                    &projects::Project {
-                        refresh_task: None, // FIXME?
-                        project: self_.project.clone(),
-                    },
-                    &self_.action.path,
-                    &self_.action.name,
-                    &Value::from_str(&self_.action.input).unwrap(),
-                    sender,
-                )
+            move |sender, gate: tasks::Gate| async move {
+                let _slot = gate.enter(None).await;
+                let project = projects::Project {
+                    refresh_task: None, // FIXME?
+                    project: self_.project.clone(),
+                };
+                let input = Value::from_str(&self_.action.input).unwrap();
+                let timeout = Settings::get().timeouts.actions;
+                timeouts::run(timeout, sender, |sender| {
+                    action(
+                        &project,
+                        &self_.action.path,
+                        &self_.action.name,
+                        &input,
+                        sender,
+                    )
+                })
                 .await
+                .unwrap_or(Err(Error::TimedOut))
                 .map_err(|e| e.into())
             }
         };
 
         let finish = {
+            let self_ = self.clone();
             let handle = self.handle();
-            move |res: Option<Result<String, error::Error>>| {
+            move |res: Option<Result<Output, error::Error>>| {
                 let status = match res {
+                    Some(Err(error::Error::ActionError(Error::TimedOut))) => {
+                        let _ = finish(None);
+                        TaskStatusKind::TimedOut
+                    }
                     Some(Err(_)) => {
                         let _ = finish(None);
                         TaskStatusKind::Failure
                     }
-                    Some(Ok(stdout)) => finish(Some(stdout)),
+                    Some(Ok(output)) => {
+                        let mut conn = POOL.get().unwrap();
+                        match self_.set_output(&mut conn, &output) {
+                            Ok(true) => finish(Some(output.stdout)),
+                            Ok(false) => {
+                                let _ = finish(None);
+                                TaskStatusKind::Failure
+                            }
+                            Err(e) => {
+                                tracing::error!("failed to record action output: {}", e);
+                                let _ = finish(None);
+                                TaskStatusKind::Failure
+                            }
+                        }
+                    }
                     None => {
                         let _ = finish(None);
                         TaskStatusKind::Canceled
@@ -217,7 +326,8 @@ impl Action {
 
         log_event(Event::ActionNew(self.handle()));
 
-        self.task.run(conn, run, finish)?;
+        let ticket = Some((queue::Kind::Action, priority));
+        self.task.run(conn, ticket, run, finish)?;
 
         Ok(())
     }
@@ -258,3 +368,79 @@ pub mod webhooks {
 
     pub type Output = Vec<Command>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_jobsets() {
+        let stdout = r#"{"main": {"flake": true, "url": "github:typhon-ci/typhon"}}"#;
+        assert_eq!(
+            validate("jobsets", stdout),
+            Ok(json!({"main": {"flake": true, "url": "github:typhon-ci/typhon"}}))
+        );
+        assert!(validate("jobsets", r#"{"main": {"flake": true}}"#).is_err());
+        assert!(validate("jobsets", "").is_err());
+    }
+
+    #[test]
+    fn validate_webhook() {
+        let stdout = r#"[{"command": "EvaluateJobset", "name": "main"}]"#;
+        assert!(validate("webhook", stdout).is_ok());
+        assert!(validate("webhook", r#"[{"command": "Deploy"}]"#).is_err());
+    }
+
+    #[test]
+    fn validate_others() {
+        assert_eq!(validate("begin", ""), Ok(Value::Null));
+        assert_eq!(validate("end", " \n"), Ok(Value::Null));
+        assert_eq!(validate("begin", r#"{"a": 1}"#), Ok(json!({"a": 1})));
+        assert!(validate("end", "not json").is_err());
+    }
+
+    /// The project of a run whose evaluation has no actions
+    fn project() -> projects::Project {
+        projects::Project {
+            refresh_task: None,
+            project: models::Project {
+                actions_path: None,
+                description: String::new(),
+                flake: true,
+                homepage: String::new(),
+                id: 1,
+                key: String::new(),
+                last_refresh_build_id: None,
+                last_refresh_task_id: None,
+                name: "test".into(),
+                reporters: "[]".into(),
+                sandbox: None,
+                title: String::new(),
+                url: String::new(),
+                url_locked: String::new(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_actions() {
+        for name in ["begin", "end"] {
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            let output = action(
+                &project(),
+                &"/dev/null".to_string(),
+                &name.to_string(),
+                &Value::Null,
+                sender,
+            )
+            .await
+            .unwrap();
+            assert_eq!(output.exit_code, Some(0));
+            assert_eq!(validate(name, &output.stdout), Ok(Value::Null));
+            assert_eq!(
+                receiver.recv().await.unwrap(),
+                format!("typhon: no `{}` action, skipped", name)
+            );
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::nix;
+use crate::nix::{DrvOutputs, DrvPath};
+use crate::AGENTS;
+use crate::RUNTIME;
+
+use typhon_types::agent::{AgentMsg, ServerMsg};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+struct Agent {
+    systems: Vec<String>,
+    features: Vec<String>,
+    sender: mpsc::UnboundedSender<ServerMsg>,
+    /// Number of builds currently dispatched to the agent
+    load: usize,
+    /// Number of builds the agent runs at once
+    max_jobs: usize,
+}
+
+struct Pending {
+    agent: u64,
+    filter: nix::LogFilter,
+    logs: mpsc::UnboundedSender<String>,
+    result: oneshot::Sender<Option<DrvOutputs>>,
+}
+
+#[derive(Default)]
+struct State {
+    agents: HashMap<u64, Agent>,
+    builds: HashMap<u64, Pending>,
+    next_agent: u64,
+    next_build: u64,
+}
+
+/// The registry of connected build agents.
+pub struct Agents {
+    state: Mutex<State>,
+    /// Notified when an agent may have room for a new build
+    capacity: Notify,
+}
+
+enum Dispatch {
+    Started(Dispatched, oneshot::Receiver<Option<DrvOutputs>>),
+    /// The agents supporting the build are all at capacity
+    Busy,
+    /// No agent supports the build
+    Unsupported,
+}
+
+/// A build dispatched to an agent, canceled on the agent when dropped before
+/// completion.
+struct Dispatched {
+    build: u64,
+}
+
+impl Drop for Dispatched {
+    fn drop(&mut self) {
+        AGENTS.cancel(self.build);
+    }
+}
+
+impl Agents {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            capacity: Notify::new(),
+        }
+    }
+
+    fn connect(
+        &self,
+        systems: Vec<String>,
+        features: Vec<String>,
+        max_jobs: usize,
+        sender: mpsc::UnboundedSender<ServerMsg>,
+    ) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_agent;
+        state.next_agent += 1;
+        state.agents.insert(
+            id,
+            Agent {
+                systems,
+                features,
+                sender,
+                load: 0,
+                max_jobs,
+            },
+        );
+        self.capacity.notify_waiters();
+        id
+    }
+
+    /// Removes an agent, its pending builds fail.
+    fn disconnect(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.agents.remove(&id);
+        state.builds.retain(|_, pending| pending.agent != id);
+        // builds waiting for this agent may go to another one, or fail
+        self.capacity.notify_waiters();
+    }
+
+    fn dispatch(
+        &self,
+        drv: &DrvPath,
+        system: &str,
+        features: &[String],
+        logs: mpsc::UnboundedSender<String>,
+    ) -> Dispatch {
+        let mut state = self.state.lock().unwrap();
+        let supported: Vec<(&u64, &Agent)> = state
+            .agents
+            .iter()
+            .filter(|(_, agent)| {
+                agent.systems.iter().any(|s| s == system)
+                    && features.iter().all(|f| agent.features.contains(f))
+            })
+            .collect();
+        if supported.is_empty() {
+            return Dispatch::Unsupported;
+        }
+        let Some(agent_id) = supported
+            .into_iter()
+            .filter(|(_, agent)| agent.load < agent.max_jobs)
+            .min_by_key(|(_, agent)| agent.load)
+            .map(|(id, _)| *id)
+        else {
+            return Dispatch::Busy;
+        };
+        let build = state.next_build;
+        state.next_build += 1;
+        let agent = state.agents.get_mut(&agent_id).unwrap();
+        let msg = ServerMsg::Build {
+            build,
+            drv: drv.to_string(),
+        };
+        if agent.sender.send(msg).is_err() {
+            // the agent is disconnecting
+            return Dispatch::Busy;
+        }
+        agent.load += 1;
+        let (result, receiver) = oneshot::channel();
+        state.builds.insert(
+            build,
+            Pending {
+                agent: agent_id,
+                filter: nix::LogFilter::new(drv),
+                logs,
+                result,
+            },
+        );
+        Dispatch::Started(Dispatched { build }, receiver)
+    }
+
+    fn cancel(&self, build: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = state.builds.remove(&build) {
+            if let Some(agent) = state.agents.get_mut(&pending.agent) {
+                agent.load -= 1;
+                let _ = agent.sender.send(ServerMsg::Cancel { build });
+                self.capacity.notify_waiters();
+            }
+        }
+    }
+
+    fn handle(&self, agent: u64, msg: AgentMsg) {
+        let mut state = self.state.lock().unwrap();
+        match msg {
+            AgentMsg::Hello { .. } => (),
+            AgentMsg::Log { build, line } => {
+                if let Some(pending) = state.builds.get_mut(&build) {
+                    if let Some(line) = pending.filter.filter(line) {
+                        let _ = pending.logs.send(line);
+                    }
+                }
+            }
+            AgentMsg::Done { build, outputs } => {
+                if state.builds.get(&build).map(|pending| pending.agent) == Some(agent) {
+                    let pending = state.builds.remove(&build).unwrap();
+                    let _ = pending.result.send(outputs);
+                    if let Some(agent) = state.agents.get_mut(&agent) {
+                        agent.load -= 1;
+                        self.capacity.notify_waiters();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds a derivation on an agent that supports its system and features, or
+/// locally if there is none. If the agents are all at capacity, waits for one
+/// of them.
+pub async fn build(
+    drv: &DrvPath,
+    system: Option<String>,
+    features: Vec<String>,
+    sender: mpsc::UnboundedSender<String>,
+) -> Result<DrvOutputs, nix::Error> {
+    let Some(system) = system else {
+        return nix::build(drv, sender).await;
+    };
+    loop {
+        // created before dispatching, so that no notification is missed
+        let capacity = AGENTS.capacity.notified();
+        match AGENTS.dispatch(drv, &system, &features, sender.clone()) {
+            Dispatch::Started(_dispatched, receiver) => {
+                return receiver.await.ok().flatten().ok_or(nix::Error::BuildFailed)
+            }
+            Dispatch::Busy => capacity.await,
+            Dispatch::Unsupported => return nix::build(drv, sender).await,
+        }
+    }
+}
+
+async fn serve(stream: UnixStream, password: PasswordHash<'static>) -> Option<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let hello = serde_json::from_str(&lines.next_line().await.ok()??).ok()?;
+    let AgentMsg::Hello {
+        password: secret,
+        systems,
+        features,
+        max_jobs,
+    } = hello
+    else {
+        return None;
+    };
+    if Argon2::default()
+        .verify_password(secret.as_bytes(), &password)
+        .is_err()
+    {
+        tracing::warn!("agent rejected: wrong password");
+        return None;
+    }
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let _ = sender.send(ServerMsg::Welcome);
+    tracing::info!("agent connected for systems {:?}", systems);
+    let id = AGENTS.connect(systems, features, max_jobs, sender);
+    let write = async move {
+        while let Some(msg) = receiver.recv().await {
+            let line = format!("{}\n", serde_json::to_string(&msg).unwrap());
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    };
+    let read = async {
+        while let Ok(Some(line)) = lines.next_line().await {
+            match serde_json::from_str(&line) {
+                Ok(msg) => AGENTS.handle(id, msg),
+                Err(e) => tracing::warn!("unexpected message from agent: {}", e),
+            }
+        }
+    };
+    tokio::select! {
+        _ = write => (),
+        _ = read => (),
+    }
+    AGENTS.disconnect(id);
+    tracing::info!("agent disconnected");
+    Some(())
+}
+
+/// Accepts connections from agents on the Unix socket at `path`. Agents
+/// authenticate with the password whose Argon2 hash is `password`. The socket
+/// is not meant to be exposed: remote agents reach it through an SSH tunnel.
+pub fn listen(path: String, password: PasswordHash<'static>) {
+    RUNTIME.spawn(async move {
+        // the socket of a previous run is replaced
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::error!("failed to remove socket {}: {}", path, e);
+                return;
+            }
+            _ => (),
+        }
+        let listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("failed to listen for agents on {}: {}", path, e);
+                return;
+            }
+        };
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    RUNTIME.spawn(serve(stream, password.clone()));
+                }
+                Err(e) => tracing::warn!("failed to accept agent: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(agents: &Agents, features: &[&str], max_jobs: usize) -> u64 {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        // the messages to the agent are dropped
+        std::mem::forget(receiver);
+        let features = features.iter().map(|f| f.to_string()).collect();
+        agents.connect(vec!["aarch64-linux".into()], features, max_jobs, sender)
+    }
+
+    fn dispatch(agents: &Agents, system: &str, features: &[&str]) -> Dispatch {
+        let drv = DrvPath::new("/nix/store/00000000000000000000000000000000-test.drv");
+        let features: Vec<String> = features.iter().map(|f| f.to_string()).collect();
+        let (logs, _) = mpsc::unbounded_channel();
+        agents.dispatch(&drv, system, &features, logs)
+    }
+
+    fn started(dispatch: Dispatch) -> u64 {
+        match dispatch {
+            Dispatch::Started(dispatched, _) => {
+                let build = dispatched.build;
+                // dropping it would cancel the build
+                std::mem::forget(dispatched);
+                build
+            }
+            _ => panic!("the build was not dispatched"),
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        let agents = Agents::new();
+        agent(&agents, &["kvm"], 1);
+        let res = dispatch(&agents, "x86_64-linux", &[]);
+        assert!(matches!(res, Dispatch::Unsupported));
+        let res = dispatch(&agents, "aarch64-linux", &["big-parallel"]);
+        assert!(matches!(res, Dispatch::Unsupported));
+        started(dispatch(&agents, "aarch64-linux", &["kvm"]));
+    }
+
+    #[test]
+    fn capacity() {
+        let agents = Agents::new();
+        let small = agent(&agents, &[], 1);
+        let big = agent(&agents, &[], 2);
+        let builds: Vec<u64> = (0..3)
+            .map(|_| started(dispatch(&agents, "aarch64-linux", &[])))
+            .collect();
+        assert!(matches!(
+            dispatch(&agents, "aarch64-linux", &[]),
+            Dispatch::Busy
+        ));
+        let on = |agent| {
+            let state = agents.state.lock().unwrap();
+            builds
+                .iter()
+                .filter(|build| state.builds[build].agent == agent)
+                .count()
+        };
+        assert_eq!((on(small), on(big)), (1, 2));
+
+        // a finished build makes room on its agent
+        let build = builds
+            .iter()
+            .copied()
+            .find(|build| agents.state.lock().unwrap().builds[build].agent == small)
+            .unwrap();
+        let outputs = None;
+        agents.handle(small, AgentMsg::Done { build, outputs });
+        let build = started(dispatch(&agents, "aarch64-linux", &[]));
+        assert_eq!(agents.state.lock().unwrap().builds[&build].agent, small);
+    }
+}
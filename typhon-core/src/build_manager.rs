@@ -1,12 +1,16 @@
+use crate::agents;
 use crate::builds;
 use crate::error::Error;
 use crate::log_event;
 use crate::models;
 use crate::nix;
 use crate::nix::DrvPath;
+use crate::queue;
 use crate::schema;
 use crate::tasks;
+use crate::timeouts::{self, Timeout};
 use crate::Conn;
+use crate::Settings;
 use crate::POOL;
 use crate::RUNTIME;
 
@@ -21,7 +25,9 @@ use tokio::{
     task::JoinSet,
 };
 
-type Output = Option<Option<()>>;
+/// The status of a finished build: a success, a failure, a timeout, or a
+/// cancellation
+type Output = TaskStatusKind;
 
 pub struct BuildHandle {
     pub abort: oneshot::Sender<()>,
@@ -37,8 +43,13 @@ impl BuildHandle {
 }
 
 enum Msg {
-    Abort(DrvPath),
-    Build(DrvPath, oneshot::Sender<BuildHandle>),
+    Abort(DrvPath, i32),
+    Build(
+        DrvPath,
+        queue::Priority,
+        Timeout,
+        oneshot::Sender<BuildHandle>,
+    ),
     Finished(DrvPath, Output),
     Shutdown,
 }
@@ -67,8 +78,9 @@ impl State {
     async fn new_build(
         &mut self,
         drv: DrvPath,
+        priority: queue::Priority,
+        timeout: Timeout,
         sender: &mpsc::UnboundedSender<Msg>,
-        abort_receiver: oneshot::Receiver<()>,
         res_sender: oneshot::Sender<Output>,
     ) -> Result<i32, Error> {
         use uuid::{timestamp, Uuid};
@@ -104,17 +116,10 @@ impl State {
 
         log_event(Event::BuildNew(build.handle()));
 
-        let abort = {
-            let drv = drv.clone();
-            let sender = sender.clone();
-            abort_thread(drv, sender, abort_receiver)
-        };
-        self.join_set.spawn(abort);
-
         let run = {
             let drv = drv.clone();
             let sender = sender.clone();
-            move |sender_log| run_build(drv, sender, sender_log)
+            move |sender_log, gate| run_build(drv, priority, timeout, sender, sender_log, gate)
         };
         let finish = {
             let drv = drv.clone();
@@ -125,35 +130,56 @@ impl State {
                 (status, Event::BuildFinished(handle))
             }
         };
-        build.task.run(&mut self.conn, run, finish)?;
+        let ticket = Some((queue::Kind::Build, priority));
+        build.task.run(&mut self.conn, ticket, run, finish)?;
 
         Ok(build.build.id)
     }
 }
 
-fn finish_build(drv: DrvPath, sender: mpsc::UnboundedSender<Msg>, res: Output) -> TaskStatusKind {
-    let _ = sender.send(Msg::Finished(drv, res.clone()));
-    match res {
-        Some(Some(())) => TaskStatusKind::Success,
-        Some(None) => TaskStatusKind::Failure,
-        None => TaskStatusKind::Canceled,
-    }
+fn finish_build(
+    drv: DrvPath,
+    sender: mpsc::UnboundedSender<Msg>,
+    res: Option<Output>,
+) -> TaskStatusKind {
+    let status = res.unwrap_or(TaskStatusKind::Canceled);
+    let _ = sender.send(Msg::Finished(drv, status));
+    status
 }
 
 async fn run_build(
     drv: DrvPath,
+    priority: queue::Priority,
+    timeout: Timeout,
     sender: mpsc::UnboundedSender<Msg>,
     sender_log: mpsc::UnboundedSender<String>,
-) -> Option<()> {
+    gate: tasks::Gate,
+) -> Output {
+    let mut system = None;
+    let mut features = Vec::new();
     if nix::is_cached(&drv).await == Ok(false) {
-        let json: serde_json::Value = nix::derivation_json(&nix::Expr::Path(drv.to_string()))
-            .await
-            .ok()?;
+        let Ok(json) = nix::derivation_json(&nix::Expr::Path(drv.to_string())).await else {
+            return TaskStatusKind::Failure;
+        };
+        system = json[&drv.to_string()]["system"]
+            .as_str()
+            .map(|system| system.to_string());
+        features = json[&drv.to_string()]["env"]["requiredSystemFeatures"]
+            .as_str()
+            .map(|features| features.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
         let input_drvs = json[&drv.to_string()]["inputDrvs"].as_object().unwrap();
         let mut handle_receivers: Vec<oneshot::Receiver<BuildHandle>> = Vec::new();
+        // the dependencies of a build get the default limits
+        let default = Settings::get().timeouts.builds;
         for (drv, _) in input_drvs {
             let (handle_sender, handle_receiver) = oneshot::channel();
-            let _ = sender.send(Msg::Build(DrvPath::new(drv), handle_sender));
+            let _ = sender.send(Msg::Build(
+                DrvPath::new(drv),
+                priority,
+                default,
+                handle_sender,
+            ));
             handle_receivers.push(handle_receiver);
         }
         let mut join_set = JoinSet::new();
@@ -164,21 +190,32 @@ async fn run_build(
         }
         while let Some(res) = join_set.join_next().await {
             if res.is_err() {
-                return None;
+                return TaskStatusKind::Failure;
             }
         }
     }
-    let _ = nix::build(&drv, sender_log).await.ok()?;
-    Some(())
+    // the slot is only taken once the dependencies are built, so that builds
+    // waiting for their dependencies do not starve the queue
+    let _slot = gate.enter(system.clone()).await;
+    let build = |sender| agents::build(&drv, system, features, sender);
+    match timeouts::run(timeout, sender_log, build).await {
+        Ok(Ok(_)) => TaskStatusKind::Success,
+        Ok(Err(_)) => TaskStatusKind::Failure,
+        Err(timeouts::TimedOut) => TaskStatusKind::TimedOut,
+    }
 }
 
+/// Releases one waiter of build `id` when its `BuildHandle` is dropped. The
+/// build id is checked on reception, so that a late release cannot decrement
+/// the waiters of a newer build of the same derivation.
 async fn abort_thread(
     drv: DrvPath,
+    id: i32,
     sender: mpsc::UnboundedSender<Msg>,
     receiver: oneshot::Receiver<()>,
 ) {
     let _ = receiver.await;
-    let _ = sender.send(Msg::Abort(drv));
+    let _ = sender.send(Msg::Abort(drv, id));
 }
 
 async fn main_thread(
@@ -188,21 +225,23 @@ async fn main_thread(
     let mut state = State::new();
     while let Some(msg) = receiver.recv().await {
         match msg {
-            Msg::Abort(drv) => {
+            Msg::Abort(drv, id) => {
                 if let Some(build) = state.builds.get_mut(&drv) {
-                    build.active_waiters = build.active_waiters - 1;
-                    if build.active_waiters == 0 {
-                        build.build.task.cancel();
+                    if build.build.build.id == id {
+                        build.active_waiters -= 1;
+                        if build.active_waiters == 0 {
+                            build.build.task.cancel();
+                        }
                     }
                 }
             }
-            Msg::Build(drv, handle_sender) => {
+            Msg::Build(drv, priority, timeout, handle_sender) => {
                 let (abort_sender, abort_receiver) = oneshot::channel();
                 let (res_sender, res_receiver) = oneshot::channel();
-                let id = if let Some(build) = state.builds.get_mut(&drv) {
+                let (id, waiting) = if let Some(build) = state.builds.get_mut(&drv) {
                     build.senders.push(res_sender);
-                    build.active_waiters = build.active_waiters + 1;
-                    build.build.build.id
+                    build.active_waiters += 1;
+                    (build.build.build.id, true)
                 } else {
                     let maybe_build: Option<builds::Build> =
                         builds::Build::last(&mut state.conn, &drv)?;
@@ -211,21 +250,35 @@ async fn main_thread(
                             if TaskStatusKind::from(&build.task.status()) == TaskStatusKind::Success
                                 && nix::is_built(&drv).await?
                             {
-                                let _ = res_sender.send(Some(Some(())));
-                                build.build.id
+                                let _ = res_sender.send(TaskStatusKind::Success);
+                                (build.build.id, false)
                             } else {
-                                state
-                                    .new_build(drv, &sender, abort_receiver, res_sender)
-                                    .await?
+                                (
+                                    state
+                                        .new_build(
+                                            drv.clone(),
+                                            priority,
+                                            timeout,
+                                            &sender,
+                                            res_sender,
+                                        )
+                                        .await?,
+                                    true,
+                                )
                             }
                         }
-                        None => {
+                        None => (
                             state
-                                .new_build(drv, &sender, abort_receiver, res_sender)
-                                .await?
-                        }
+                                .new_build(drv.clone(), priority, timeout, &sender, res_sender)
+                                .await?,
+                            true,
+                        ),
                     }
                 };
+                if waiting {
+                    let abort = abort_thread(drv, id, sender.clone(), abort_receiver);
+                    state.join_set.spawn(abort);
+                }
                 let handle = BuildHandle {
                     abort: abort_sender,
                     id,
@@ -236,7 +289,7 @@ async fn main_thread(
             Msg::Finished(drv, res) => {
                 if let Some(build) = state.builds.remove(&drv) {
                     for sender in build.senders {
-                        let _ = sender.send(res.clone());
+                        let _ = sender.send(res);
                     }
                 }
             }
@@ -247,7 +300,7 @@ async fn main_thread(
     for (_, build) in state.builds {
         build.build.task.cancel();
         for sender in build.senders {
-            let _ = sender.send(None);
+            let _ = sender.send(TaskStatusKind::Canceled);
         }
     }
     Ok(())
@@ -275,10 +328,34 @@ impl Builder {
         Self { sender, watch }
     }
 
-    pub fn run(&self, drv: DrvPath) -> BuildHandle {
+    /// Builds `drv`, unless it is already being built. `timeout` only applies
+    /// to a new build, falling back to the default limits.
+    pub fn run(&self, drv: DrvPath, priority: queue::Priority, timeout: Timeout) -> BuildHandle {
+        self.send(drv, priority, timeout).blocking_recv().unwrap() // FIXME
+    }
+
+    /// Like `run`, from an asynchronous context
+    pub async fn run_async(
+        &self,
+        drv: DrvPath,
+        priority: queue::Priority,
+        timeout: Timeout,
+    ) -> BuildHandle {
+        self.send(drv, priority, timeout).await.unwrap() // FIXME
+    }
+
+    fn send(
+        &self,
+        drv: DrvPath,
+        priority: queue::Priority,
+        timeout: Timeout,
+    ) -> oneshot::Receiver<BuildHandle> {
+        let timeout = timeout.or(Settings::get().timeouts.builds);
         let (handle_sender, handle_receiver) = oneshot::channel();
-        self.sender.send(Msg::Build(drv, handle_sender)).unwrap(); // FIXME
-        handle_receiver.blocking_recv().unwrap() // FIXME
+        self.sender
+            .send(Msg::Build(drv, priority, timeout, handle_sender))
+            .unwrap(); // FIXME
+        handle_receiver
     }
 
     pub async fn shutdown(&self) {
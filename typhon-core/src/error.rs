@@ -3,18 +3,24 @@ use crate::handles;
 use crate::nix;
 use crate::task_manager;
 
+use typhon_types::data::TaskStatusKind;
+
 #[derive(Debug)]
 pub enum Error {
     AccessDenied,
     ActionError(actions::Error),
     ActionNotFound(handles::Action),
+    ActionsBuildFailed(TaskStatusKind),
+    AdminPasswordIsFixed,
     BuildNotFound(handles::Build),
     RunNotFound(handles::Run),
     BadProjectDecl,
     BadJobsetDecl(String),
     EvaluationNotFound(handles::Evaluation),
+    EvaluationsNotComparable(handles::Evaluation, handles::Evaluation),
     IllegalProjectHandle(handles::Project),
     JobAlreadyRunning(handles::Job),
+    JobEvaluationFailed(handles::Job),
     JobNotFound(handles::Job),
     JobsetNotFound(handles::Jobset),
     LogNotFound(handles::Log),
@@ -27,6 +33,10 @@ pub enum Error {
     LoginError,
     TaskError(task_manager::Error),
     BadWebhookOutput,
+    TokenAlreadyExists(String),
+    TokenNotFound(String),
+    UserAlreadyExists(String),
+    UserNotFound(String),
 }
 
 impl Error {
@@ -50,6 +60,13 @@ impl std::fmt::Display for Error {
             AccessDenied => write!(f, "Access denied"),
             ActionError(e) => write!(f, "Action error: {}", e),
             ActionNotFound(h) => write!(f, "Action not found: {}", h),
+            ActionsBuildFailed(status) => {
+                write!(f, "The build of the actions ended with status {}", status)
+            }
+            AdminPasswordIsFixed => write!(
+                f,
+                "The password of admin is set on the command line, it cannot be changed"
+            ),
             BuildNotFound(h) => write!(f, "Build not found: {}", h),
             RunNotFound(h) => write!(f, "Run not found: {}", h),
             BadProjectDecl => write!(f, "Bad project declaration"),
@@ -60,6 +77,9 @@ impl std::fmt::Display for Error {
             JobAlreadyRunning(job_handle) => {
                 write!(f, "Job {} is already running", job_handle)
             }
+            JobEvaluationFailed(job_handle) => {
+                write!(f, "Job {} failed to evaluate", job_handle)
+            }
             JobNotFound(job_handle) => {
                 write!(f, "Job {} not found", job_handle)
             }
@@ -72,6 +92,11 @@ impl std::fmt::Display for Error {
             EvaluationNotFound(evaluation_handle) => {
                 write!(f, "Evaluation {} not found", evaluation_handle)
             }
+            EvaluationsNotComparable(head, base) => write!(
+                f,
+                "Evaluation {} cannot be compared with {}, which belongs to another jobset",
+                head, base
+            ),
             ProjectAlreadyExists(project_handle) => {
                 write!(f, "Project {} already exists", project_handle)
             }
@@ -83,6 +108,10 @@ impl std::fmt::Display for Error {
             UnexpectedTimeError(e) => write!(f, "Time error: {}", e),
             TaskError(e) => write!(f, "Task error: {}", e),
             BadWebhookOutput => write!(f, "Bad webhook output"),
+            TokenAlreadyExists(name) => write!(f, "Token {} already exists", name),
+            TokenNotFound(name) => write!(f, "Token {} not found", name),
+            UserAlreadyExists(name) => write!(f, "User {} already exists", name),
+            UserNotFound(name) => write!(f, "User {} not found", name),
         }
     }
 }
@@ -133,16 +162,24 @@ impl Into<typhon_types::responses::ResponseError> for Error {
             | ActionNotFound(_)
             | BuildNotFound(_)
             | RunNotFound(_)
+            | TokenNotFound(_)
+            | UserNotFound(_)
             | LogNotFound(_) => ResourceNotFound(format!("{}", self)),
             AccessDenied
             | ActionError(_)
+            | ActionsBuildFailed(_)
+            | AdminPasswordIsFixed
             | BadProjectDecl
             | BadJobsetDecl(_)
+            | EvaluationsNotComparable(..)
             | IllegalProjectHandle(_)
             | JobAlreadyRunning(_)
+            | JobEvaluationFailed(_)
             | NixError(_)
             | ProjectAlreadyExists(_)
             | LoginError
+            | TokenAlreadyExists(_)
+            | UserAlreadyExists(_)
             | BadWebhookOutput => BadRequest(format!("{}", self)),
         }
     }
@@ -1,12 +1,17 @@
+use crate::actions;
 use crate::error::Error;
+use crate::gcroots;
 use crate::jobs;
 use crate::models;
 use crate::nix;
+use crate::queue;
 use crate::responses;
 use crate::schema;
 use crate::tasks;
 use crate::Conn;
+use crate::Settings;
 use crate::POOL;
+use crate::RUNTIME;
 
 use std::collections::HashMap;
 use typhon_types::data::TaskStatusKind;
@@ -35,15 +40,9 @@ impl responses::RunInfo {
         build: Option<(models::Build, models::Task)>,
         end: Option<(models::Action, models::Task)>,
     ) -> Self {
-        let to_action_info =
-            |(action, task): (models::Action, models::Task)| responses::ActionInfo {
-                handle: handles::action(Uuid::from_str(&action.uuid).unwrap()),
-                input: action.input,
-                name: action.name,
-                path: action.path,
-                project: project_handle.clone(),
-                status: task.status(),
-            };
+        let to_action_info = |(action, task): (models::Action, models::Task)| {
+            actions::info(project_handle.clone(), action, task.status())
+        };
         responses::RunInfo {
             handle: handles::Run {
                 job: job_handle.clone(),
@@ -56,6 +55,7 @@ impl responses::RunInfo {
                 status: task.status(),
             }),
             end: end.map(to_action_info),
+            retry_reason: run.retry_reason,
         }
     }
 }
@@ -65,27 +65,41 @@ impl responses::JobInfo {
     /// Reshape raw database data into a structured `JobInfo`
     fn new(
         project_handle: &handles::Project,
-        eval_handle: &handles::Evaluation,
+        evaluation: &models::Evaluation,
         job: models::Job,
-        run: models::Run,
+        run: Option<models::Run>,
         begin: Option<(models::Action, models::Task)>,
         build: Option<(models::Build, models::Task)>,
         end: Option<(models::Action, models::Task)>,
-    ) -> Self {
+    ) -> Result<responses::JobInfo, Error> {
         let job_handle = handles::Job {
-            evaluation: eval_handle.clone(),
+            evaluation: handles::evaluation(Uuid::from_str(&evaluation.uuid).unwrap()),
             system: job.system.clone(),
             name: job.name.clone(),
         };
-        Self {
+        let error = match job.error {
+            Some(message) => Some(responses::JobError {
+                message,
+                time: time::OffsetDateTime::from_unix_timestamp(evaluation.time_created)?,
+            }),
+            None => None,
+        };
+        Ok(responses::JobInfo {
             handle: job_handle.clone(),
             dist: job.dist,
             drv: job.drv,
+            error,
             out: job.out,
+            retry: job
+                .retry
+                .as_deref()
+                .and_then(|retry| serde_json::from_str(retry).ok()),
             system: job.system,
-            last_run: responses::RunInfo::new(project_handle, &job_handle, run, begin, build, end),
+            last_run: run.map(|run| {
+                responses::RunInfo::new(project_handle, &job_handle, run, begin, build, end)
+            }),
             run_count: job.tries as u32,
-        }
+        })
     }
 }
 
@@ -94,18 +108,82 @@ impl Evaluation {
         self.task.cancel()
     }
 
-    pub fn finish(self, r: Option<Result<nix::NewJobs, nix::Error>>) -> TaskStatusKind {
-        let mut conn = POOL.get().unwrap();
+    pub fn finish(self, r: Option<Result<(), nix::Error>>) -> TaskStatusKind {
         match r {
-            Some(Ok(new_jobs)) => match self.create_new_jobs(&mut conn, new_jobs) {
-                Ok(()) => TaskStatusKind::Success,
-                Err(_) => TaskStatusKind::Failure,
-            },
+            Some(Ok(())) => {
+                let mut conn = POOL.get().unwrap();
+                gcroots::add_evaluation(&mut conn, self.evaluation.id, &self.evaluation.uuid);
+                TaskStatusKind::Success
+            }
             Some(Err(_)) => TaskStatusKind::Failure,
             None => TaskStatusKind::Canceled,
         }
     }
 
+    /// Compares the jobs of `self` with those of `base`, an evaluation of the
+    /// same jobset
+    pub fn compare(
+        &self,
+        conn: &mut Conn,
+        base: &handles::Evaluation,
+    ) -> Result<responses::EvaluationComparison, Error> {
+        let project = handles::project(self.project.name.clone());
+        let base_evaluation = Self::get(conn, base)?;
+        if base_evaluation.evaluation.project_id != self.evaluation.project_id
+            || base_evaluation.evaluation.jobset_name != self.evaluation.jobset_name
+        {
+            return Err(Error::EvaluationsNotComparable(self.handle(), base.clone()));
+        }
+        let head = Self::jobs(&project, &self.evaluation, None, None, conn)?;
+        let base = Self::jobs(&project, &base_evaluation.evaluation, None, None, conn)?;
+
+        let mut comparison = responses::EvaluationComparison {
+            base: base_evaluation.handle(),
+            head: self.handle(),
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+            failing: Vec::new(),
+            fixed: Vec::new(),
+        };
+        for (name, job) in head.iter() {
+            let Some(base_job) = base.get(name) else {
+                comparison.added.push(name.clone());
+                continue;
+            };
+            if job.drv != base_job.drv {
+                comparison.changed.push(name.clone());
+            }
+            match (
+                TaskStatusKind::from(responses::TaskStatus::from(base_job)),
+                TaskStatusKind::from(responses::TaskStatus::from(job)),
+            ) {
+                (TaskStatusKind::Success, TaskStatusKind::Failure | TaskStatusKind::TimedOut) => {
+                    comparison.failing.push(name.clone())
+                }
+                (TaskStatusKind::Failure | TaskStatusKind::TimedOut, TaskStatusKind::Success) => {
+                    comparison.fixed.push(name.clone())
+                }
+                _ => (),
+            }
+        }
+        comparison.removed = base
+            .into_keys()
+            .filter(|name| !head.contains_key(name))
+            .collect();
+        for jobs in [
+            &mut comparison.added,
+            &mut comparison.removed,
+            &mut comparison.changed,
+            &mut comparison.failing,
+            &mut comparison.fixed,
+        ] {
+            jobs.sort();
+        }
+
+        Ok(comparison)
+    }
+
     pub fn get(conn: &mut Conn, handle: &handles::Evaluation) -> Result<Self, Error> {
         let (evaluation, project, task) = schema::evaluations::table
             .inner_join(schema::projects::table)
@@ -125,11 +203,17 @@ impl Evaluation {
         handles::evaluation(Uuid::from_str(&self.evaluation.uuid).unwrap())
     }
 
+    pub fn jobset_handle(&self) -> handles::Jobset {
+        handles::jobset((
+            self.project.name.clone(),
+            self.evaluation.jobset_name.clone(),
+        ))
+    }
+
     /// Fetch all jobs attached to self
     pub fn jobs(
         project_handle: &handles::Project,
-        eval_handle: &handles::Evaluation,
-        eval_id: i32,
+        evaluation: &models::Evaluation,
         filter_system: Option<String>,
         filter_name: Option<String>,
         conn: &mut Conn,
@@ -142,8 +226,9 @@ impl Evaluation {
             schema::tasks as end_task,
             schema::runs as subruns,
         );
+        // jobs that failed to evaluate have no runs
         let mut query = schema::jobs::table
-            .inner_join(schema::runs::table)
+            .left_join(schema::runs::table)
             .left_join(
                 begin_action
                     .on(begin_action
@@ -166,13 +251,16 @@ impl Evaluation {
                     .inner_join(end_task),
             )
             .filter(
-                schema::runs::job_id.nullable().eq(subruns
-                    .filter(subruns.field(schema::runs::job_id).eq(schema::jobs::id))
-                    .group_by(subruns.field(schema::runs::job_id))
-                    .select(diesel::dsl::max(subruns.field(schema::runs::id)))
-                    .single_value()),
+                schema::runs::id
+                    .nullable()
+                    .is_null()
+                    .or(schema::runs::job_id.nullable().eq(subruns
+                        .filter(subruns.field(schema::runs::job_id).eq(schema::jobs::id))
+                        .group_by(subruns.field(schema::runs::job_id))
+                        .select(diesel::dsl::max(subruns.field(schema::runs::id)))
+                        .single_value())),
             )
-            .filter(schema::jobs::evaluation_id.eq(eval_id))
+            .filter(schema::jobs::evaluation_id.eq(evaluation.id))
             .into_boxed();
         if let Some(system) = filter_system {
             query = query.filter(schema::jobs::system.eq(system));
@@ -183,7 +271,7 @@ impl Evaluation {
         Ok(query
             .select((
                 schema::jobs::all_columns,
-                schema::runs::all_columns,
+                schema::runs::all_columns.nullable(),
                 (
                     begin_action.fields(schema::actions::all_columns),
                     begin_task.fields(schema::tasks::all_columns),
@@ -203,43 +291,48 @@ impl Evaluation {
             .load(conn)?
             .into_iter()
             .map(
-                |(job, run, begin, build, end): (models::Job, models::Run, _, _, _)| {
+                |(job, run, begin, build, end): (models::Job, Option<models::Run>, _, _, _)| {
                     let (system, name) = (job.system.clone(), job.name.clone());
-                    (
+                    Ok((
                         responses::JobSystemName { system, name },
                         responses::JobInfo::new(
                             project_handle,
-                            &eval_handle,
+                            evaluation,
                             job,
                             run,
                             begin,
                             build,
                             end,
-                        ),
-                    )
+                        )?,
+                    ))
                 },
             )
-            .collect())
+            .collect::<Result<_, Error>>()?)
     }
 
     pub fn info(&self, conn: &mut Conn) -> Result<responses::EvaluationInfo, Error> {
+        let previous = schema::evaluations::table
+            .filter(schema::evaluations::project_id.eq(self.evaluation.project_id))
+            .filter(schema::evaluations::jobset_name.eq(&self.evaluation.jobset_name))
+            .filter(schema::evaluations::id.lt(self.evaluation.id))
+            .order(schema::evaluations::id.desc())
+            .select(schema::evaluations::uuid)
+            .first::<String>(conn)
+            .optional()?;
         Ok(responses::EvaluationInfo {
             handle: self.handle(),
             actions_path: self.evaluation.actions_path.clone(),
             flake: self.evaluation.flake,
-            jobs: if self.task.status_kind() == TaskStatusKind::Success {
-                Self::jobs(
-                    &handles::project(self.project.name.clone()),
-                    &self.handle(),
-                    self.evaluation.id,
-                    None,
-                    None,
-                    conn,
-                )?
-            } else {
-                HashMap::new()
-            },
+            // jobs are created while the evaluation runs
+            jobs: Self::jobs(
+                &handles::project(self.project.name.clone()),
+                &self.evaluation,
+                None,
+                None,
+                conn,
+            )?,
             jobset_name: self.evaluation.jobset_name.clone(),
+            previous: previous.map(|uuid| handles::evaluation(Uuid::from_str(&uuid).unwrap())),
             project: handles::project(self.project.name.clone()),
             status: self.task.status(),
             time_created: time::OffsetDateTime::from_unix_timestamp(self.evaluation.time_created)?,
@@ -247,63 +340,111 @@ impl Evaluation {
         })
     }
 
-    pub async fn run(
-        self,
-        sender: mpsc::UnboundedSender<String>,
-    ) -> Result<nix::NewJobs, nix::Error> {
-        let res = nix::eval_jobs(&self.evaluation.url, self.evaluation.flake).await;
-        match &res {
-            Err(e) => {
-                for line in e.to_string().split("\n") {
-                    // TODO: hide internal error messages?
-                    // TODO: error management
-                    let _ = sender.send(line.to_string());
+    /// Evaluates the jobs, and creates them and starts their runs as soon as
+    /// they are evaluated
+    pub async fn run(self, sender: mpsc::UnboundedSender<String>) -> Result<(), nix::Error> {
+        let (jobs_send, mut jobs_recv) = mpsc::unbounded_channel();
+        let eval = nix::eval_jobs(
+            &self.evaluation.url,
+            self.evaluation.flake,
+            &Settings::get().evaluator,
+            jobs_send,
+            sender.clone(),
+        );
+        let create = async {
+            while let Some(job) = jobs_recv.recv().await {
+                let name = format!("{}.{}", job.system, job.name);
+                if let Err(error) = &job.result {
+                    let _ = sender.send(format!("error: evaluating {}:", name));
+                    for line in error.split('\n') {
+                        let _ = sender.send(line.to_string());
+                    }
+                }
+                let evaluation = self.clone();
+                let res = RUNTIME
+                    .spawn_blocking(move || evaluation.create_job(&mut POOL.get().unwrap(), job))
+                    .await;
+                match res {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => {
+                        let _ = sender.send(format!("failed to create job {}: {:?}", name, e));
+                    }
+                    Err(e) => {
+                        let _ = sender.send(format!("failed to create job {}: {}", name, e));
+                    }
                 }
             }
-            _ => (),
+        };
+        let (res, ()) = tokio::join!(eval, create);
+        if let Err(e) = &res {
+            for line in e.to_string().split("\n") {
+                // TODO: hide internal error messages?
+                // TODO: error management
+                let _ = sender.send(line.to_string());
+            }
         }
         res
     }
 
-    fn create_new_jobs(&self, conn: &mut Conn, new_jobs: nix::NewJobs) -> Result<(), Error> {
-        let created_runs = conn.transaction::<Vec<crate::runs::Run>, Error, _>(|conn| {
-            let created_jobs: Vec<crate::jobs::Job> = new_jobs
-                .into_iter()
-                .map(|((system, name), (drv, dist))| {
-                    let new_job = models::NewJob {
-                        dist,
-                        drv: &drv.path.to_string(),
-                        evaluation_id: self.evaluation.id,
-                        name: &name,
-                        out: drv
-                            .outputs
-                            .iter()
-                            .last()
-                            .expect("TODO: derivations can have multiple outputs")
-                            .1,
-                        system: &system,
-                        tries: 0,
-                    };
-                    let job = diesel::insert_into(schema::jobs::table)
-                        .values(&new_job)
-                        .get_result::<models::Job>(conn)?;
-                    Ok(jobs::Job {
-                        project: self.project.clone(),
-                        evaluation: self.evaluation.clone(),
-                        job,
-                    })
-                })
-                .collect::<Result<_, Error>>()?;
-            created_jobs
-                .into_iter()
-                .map(|job| job.new_run(conn))
-                .collect()
+    fn create_job(&self, conn: &mut Conn, job: nix::NewJob) -> Result<(), Error> {
+        let (drv, dist) = match job.result {
+            Ok(res) => res,
+            Err(error) => {
+                // the job is kept to report the error, but it has nothing to run
+                let new_job = models::NewJob {
+                    dist: false,
+                    drv: "",
+                    error: Some(&error),
+                    evaluation_id: self.evaluation.id,
+                    name: &job.name,
+                    out: "",
+                    retry: None,
+                    system: &job.system,
+                    timeout: None,
+                    tries: 0,
+                };
+                diesel::insert_into(schema::jobs::table)
+                    .values(&new_job)
+                    .execute(conn)?;
+                return Ok(());
+            }
+        };
+        // the policy of the job takes precedence over the one of the jobset
+        let retry = match job.retry {
+            Some(retry) => Some(serde_json::to_string(&retry).unwrap()),
+            None => self.evaluation.retry.clone(),
+        };
+        let timeout = job
+            .timeout
+            .map(|timeout| serde_json::to_string(&timeout).unwrap());
+        let run = conn.transaction::<crate::runs::Run, Error, _>(|conn| {
+            let new_job = models::NewJob {
+                dist,
+                drv: &drv.path.to_string(),
+                error: None,
+                evaluation_id: self.evaluation.id,
+                name: &job.name,
+                out: drv
+                    .outputs
+                    .iter()
+                    .last()
+                    .expect("TODO: derivations can have multiple outputs")
+                    .1,
+                retry: retry.as_deref(),
+                system: &job.system,
+                timeout: timeout.as_deref(),
+                tries: 0,
+            };
+            let job = diesel::insert_into(schema::jobs::table)
+                .values(&new_job)
+                .get_result::<models::Job>(conn)?;
+            jobs::Job {
+                project: self.project.clone(),
+                evaluation: self.evaluation.clone(),
+                job,
+            }
+            .new_run(conn, None)
         })?;
-
-        for run in created_runs {
-            run.run(conn)?;
-        }
-
-        Ok(())
+        run.run(conn, queue::Priority::Normal)
     }
 }
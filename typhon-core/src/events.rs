@@ -1,17 +1,80 @@
-use crate::RUNTIME;
+use crate::error::Error;
+use crate::models;
+use crate::schema;
+use crate::Conn;
+use crate::POOL;
 
-use typhon_types::Event;
+use typhon_types::{Event, LoggedEvent};
 
+use diesel::prelude::*;
 use futures_core::stream::Stream;
+use time::OffsetDateTime;
 use tokio::sync::mpsc;
 use tokio::sync::watch;
 
+/// The number of events kept for replays. Listeners that missed more events
+/// get a `Gap` followed by the most recent ones.
+const REPLAY: i64 = 10_000;
+
 pub enum Msg {
     Emit(Event),
-    Listen(mpsc::UnboundedSender<Event>),
+    Listen(Option<i64>, mpsc::UnboundedSender<LoggedEvent>),
     Shutdown,
 }
 
+/// Stores an event in the log and returns its id.
+fn store(conn: &mut Conn, event: &Event) -> Result<i64, Error> {
+    let data = serde_json::to_string(event).unwrap();
+    let new_event = models::NewEvent {
+        data: &data,
+        time_created: OffsetDateTime::now_utc().unix_timestamp(),
+    };
+    let event = diesel::insert_into(schema::events::table)
+        .values(&new_event)
+        .get_result::<models::Event>(conn)?;
+    Ok(event.id)
+}
+
+/// Returns the events logged after event `since`, at most `REPLAY` of them.
+/// They start with a `Gap` when older events were skipped.
+fn replay(conn: &mut Conn, since: i64) -> Result<Vec<LoggedEvent>, Error> {
+    let oldest = last(conn)? - REPLAY;
+    let mut replayed = Vec::new();
+    if since < oldest {
+        replayed.push(LoggedEvent {
+            id: oldest,
+            event: Event::Gap,
+        });
+    }
+    let events = schema::events::table
+        .filter(schema::events::id.gt(since.max(oldest)))
+        .order(schema::events::id.asc())
+        .load::<models::Event>(conn)?;
+    replayed.extend(events.into_iter().filter_map(|event| {
+        Some(LoggedEvent {
+            id: event.id,
+            event: serde_json::from_str(&event.data).ok()?,
+        })
+    }));
+    Ok(replayed)
+}
+
+fn last(conn: &mut Conn) -> Result<i64, Error> {
+    let id = schema::events::table
+        .select(diesel::dsl::max(schema::events::id))
+        .first::<Option<i64>>(conn)?;
+    Ok(id.unwrap_or(0))
+}
+
+/// Deletes the events that are too old to be replayed
+pub fn prune(conn: &mut Conn) -> Result<usize, Error> {
+    let last = last(conn)?;
+    Ok(
+        diesel::delete(schema::events::table.filter(schema::events::id.le(last - REPLAY)))
+            .execute(conn)?,
+    )
+}
+
 pub struct EventLogger {
     sender: mpsc::UnboundedSender<Msg>,
     watch: watch::Receiver<()>,
@@ -21,22 +84,54 @@ impl EventLogger {
     pub fn new() -> Self {
         let (sender, mut receiver) = mpsc::unbounded_channel();
         let (watch_send, watch) = watch::channel(());
-        RUNTIME.spawn(async move {
-            let mut senders: Vec<mpsc::UnboundedSender<Event>> = Vec::new();
-            while let Some(msg) = receiver.recv().await {
-                match msg {
-                    Msg::Emit(event) => {
-                        let mut new_senders: Vec<mpsc::UnboundedSender<Event>> = Vec::new();
-                        for sender in senders.drain(..) {
-                            match sender.send(event.clone()) {
-                                Ok(()) => new_senders.push(sender),
-                                Err(_) => (),
+        // events are stored from a dedicated thread, so that writing to the
+        // database does not block the runtime
+        std::thread::spawn(move || {
+            let mut senders: Vec<mpsc::UnboundedSender<LoggedEvent>> = Vec::new();
+            'outer: while let Some(msg) = receiver.blocking_recv() {
+                // the messages that came meanwhile share a connection
+                let mut msgs = vec![msg];
+                while let Ok(msg) = receiver.try_recv() {
+                    msgs.push(msg);
+                }
+                let mut conn = POOL.get().unwrap();
+                for msg in msgs {
+                    match msg {
+                        Msg::Emit(event) => {
+                            let id = match store(&mut conn, &event) {
+                                Ok(id) => id,
+                                Err(e) => {
+                                    tracing::error!("failed to store event {:?}: {}", event, e);
+                                    continue;
+                                }
+                            };
+                            let event = LoggedEvent { id, event };
+                            senders.retain(|sender| sender.send(event.clone()).is_ok());
+                        }
+                        Msg::Listen(since, sender) => {
+                            // events are replayed from the logger's thread, so
+                            // that none is missed or sent twice
+                            let events = match since {
+                                Some(since) => replay(&mut conn, since),
+                                None => last(&mut conn).map(|id| {
+                                    vec![LoggedEvent {
+                                        id,
+                                        event: Event::Ping,
+                                    }]
+                                }),
+                            };
+                            match events {
+                                Ok(events) => {
+                                    for event in events {
+                                        let _ = sender.send(event);
+                                    }
+                                    senders.push(sender);
+                                }
+                                Err(e) => tracing::error!("failed to replay events: {}", e),
                             }
                         }
-                        senders = new_senders;
+                        Msg::Shutdown => break 'outer,
                     }
-                    Msg::Listen(sender) => senders.push(sender),
-                    Msg::Shutdown => break,
                 }
             }
             let _watch_send = watch_send;
@@ -48,17 +143,24 @@ impl EventLogger {
         let _ = self.sender.send(Msg::Emit(event));
     }
 
-    pub fn listen(&self) -> Option<impl Stream<Item = Event>> {
-        let (sender, mut receiver) = mpsc::unbounded_channel();
-        let _ = self.sender.send(Msg::Listen(sender));
+    /// Listens to the events logged after event `since`, or to new events
+    /// after a `Ping` if `since` is `None`.
+    pub fn listen(&self, since: Option<i64>) -> Option<impl Stream<Item = LoggedEvent>> {
+        let mut receiver = self.receiver(since);
         Some(async_stream::stream! {
-            yield Event::Ping;
             while let Some(e) = receiver.recv().await {
                 yield e;
             }
         })
     }
 
+    /// Like `listen`, for listeners within Typhon
+    pub fn receiver(&self, since: Option<i64>) -> mpsc::UnboundedReceiver<LoggedEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let _ = self.sender.send(Msg::Listen(since, sender));
+        receiver
+    }
+
     pub async fn shutdown(&self) {
         let _ = self.sender.send(Msg::Shutdown);
         while self.watch.clone().changed().await.is_ok() {}
@@ -1,13 +1,22 @@
 use crate::nix;
 use crate::schema;
 use crate::Conn;
+use crate::POOL;
+use crate::RUNTIME;
+
+use typhon_types::data::TaskStatusKind;
 
 use diesel::prelude::*;
 
 use std::collections::HashSet;
-use std::fs::{remove_dir_all, rename, DirBuilder};
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, DirBuilder};
 use std::os::unix::fs::symlink;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+// The roots of an evaluation live in `evaluations/$uuid`, one symlink per store
+// path, named after the store path. The actions of a project are rooted by
+// `projects/$name`.
+const GCROOTS_DIR: &str = "/nix/var/nix/gcroots/typhon";
 
 #[allow(dead_code)] // FIXME: maybe use Display instead of Debug?
 #[derive(Debug)]
@@ -35,71 +44,159 @@ impl From<nix::Error> for Error {
     }
 }
 
-allow_columns_to_appear_in_same_group_by_clause!(
-    schema::jobs::out,
-    schema::jobs::drv,
-    schema::jobsets::name,
-);
+fn evaluations_dir() -> PathBuf {
+    Path::new(GCROOTS_DIR).join("evaluations")
+}
+
+fn projects_dir() -> PathBuf {
+    Path::new(GCROOTS_DIR).join("projects")
+}
+
+fn link(path: &str, dir: &Path) -> Result<(), Error> {
+    let name = Path::new(path).file_name().unwrap_or(path.as_ref());
+    let link = dir.join(name);
+    if link.symlink_metadata().is_err() {
+        symlink(path, link)?;
+    }
+    Ok(())
+}
+
+fn add_evaluation_aux(conn: &mut Conn, evaluation_id: i32, uuid: &str) -> Result<(), Error> {
+    let jobs = schema::jobs::table
+        .filter(schema::jobs::evaluation_id.eq(evaluation_id))
+        .filter(schema::jobs::error.is_null())
+        .select((schema::jobs::drv, schema::jobs::out))
+        .load::<(String, String)>(conn)?;
 
-fn update_aux(conn: &mut Conn) -> Result<(), Error> {
-    // collect all gcroots from the database
     let mut gcroots: HashSet<String> = HashSet::new();
-    let mut res_1 = schema::evaluations::table
-        .inner_join(schema::jobs::table)
-        .inner_join(
-            schema::jobsets::table.on(schema::evaluations::jobset_name.eq(schema::jobsets::name)),
-        )
-        .group_by((schema::jobs::out, schema::jobs::drv, schema::jobsets::name))
-        .select((
-            schema::jobs::out,
-            schema::jobs::drv,
-            schema::jobsets::name,
-            diesel::dsl::max(schema::evaluations::time_created),
-        ))
-        .load::<(String, String, String, Option<i64>)>(conn)?;
-    let mut res_2 = schema::projects::table
-        .select(schema::projects::actions_path)
-        .load::<Option<String>>(conn)?;
-
-    for (path, drv, _, _) in res_1.drain(..) {
-        if let Ok(deps) = nix::dependencies(&drv) {
-            for dep in deps {
-                gcroots.insert(dep);
-            }
-        } else {
-            tracing::warn!("gcroots: missing derivation {}", drv);
-        }
+    let drvs: Vec<String> = jobs.iter().map(|(drv, _)| drv.clone()).collect();
+    match nix::dependencies(&drvs) {
+        Ok(deps) => gcroots.extend(deps),
+        Err(e) => tracing::warn!(
+            "gcroots: missing dependencies of evaluation {}: {:?}",
+            uuid,
+            e
+        ),
+    }
+    for (drv, out) in jobs {
         gcroots.insert(drv);
-        gcroots.insert(path);
+        gcroots.insert(out);
     }
-    for actions in res_2.drain(..) {
-        if let Some(path) = actions {
-            gcroots.insert(path);
-        }
+
+    // the directory is only visible once complete
+    let dir = evaluations_dir().join(uuid);
+    let tmp = evaluations_dir().join(format!("{}.tmp", uuid));
+    if tmp.exists() {
+        remove_dir_all(&tmp)?;
     }
+    DirBuilder::new().recursive(true).create(&tmp)?;
+    for gcroot in gcroots.iter() {
+        link(gcroot, &tmp)?;
+    }
+    if dir.exists() {
+        remove_dir_all(&dir)?;
+    }
+    rename(&tmp, &dir)?;
 
-    let gcroots_dir = Path::new("/nix/var/nix/gcroots/typhon");
+    Ok(())
+}
 
-    // write new gcroots on disk
-    let new_path = gcroots_dir.join("new");
-    if new_path.exists() {
-        remove_dir_all(&new_path)?
+/// Roots the jobs of a finished evaluation, with their dependencies.
+pub fn add_evaluation(conn: &mut Conn, evaluation_id: i32, uuid: &str) {
+    add_evaluation_aux(conn, evaluation_id, uuid)
+        .unwrap_or_else(|e| tracing::error!("error when adding gcroots: {:?}", e));
+}
+
+/// Drops the roots of a deleted evaluation.
+pub fn remove_evaluation(uuid: &str) {
+    let dir = evaluations_dir().join(uuid);
+    if dir.exists() {
+        remove_dir_all(&dir)
+            .unwrap_or_else(|e| tracing::error!("error when removing gcroots: {:?}", e));
+    }
+}
+
+fn set_project_aux(name: &str, actions_path: Option<&str>) -> Result<(), Error> {
+    let dir = projects_dir();
+    create_dir_all(&dir)?;
+    let link = dir.join(name);
+    if link.symlink_metadata().is_ok() {
+        remove_file(&link)?;
+    }
+    if let Some(path) = actions_path {
+        symlink(path, link)?;
     }
-    DirBuilder::new().create(&new_path)?;
-    for (i, gcroot) in gcroots.iter().enumerate() {
-        symlink(Path::new(&gcroot), new_path.join(i.to_string()))?;
+    Ok(())
+}
+
+/// Roots the actions of a project, or drops the root when `actions_path` is
+/// `None`.
+pub fn set_project(name: &str, actions_path: Option<&str>) {
+    set_project_aux(name, actions_path)
+        .unwrap_or_else(|e| tracing::error!("error when updating gcroots: {:?}", e));
+}
+
+fn sync_aux(conn: &mut Conn) -> Result<(), Error> {
+    // roots from older versions
+    for legacy in ["cur", "new"] {
+        let path = Path::new(GCROOTS_DIR).join(legacy);
+        if path.exists() {
+            remove_dir_all(&path)?;
+        }
     }
 
-    // replace old gcroots
-    let cur_path = gcroots_dir.join("cur");
-    if cur_path.exists() {
-        remove_dir_all(&cur_path)?
+    let projects = schema::projects::table
+        .select((schema::projects::name, schema::projects::actions_path))
+        .load::<(String, Option<String>)>(conn)?;
+    create_dir_all(projects_dir())?;
+    let names: HashSet<&String> = projects.iter().map(|(name, _)| name).collect();
+    for entry in read_dir(projects_dir())? {
+        let entry = entry?;
+        if !names.contains(&entry.file_name().to_string_lossy().to_string()) {
+            remove_file(entry.path())?;
+        }
+    }
+    for (name, actions_path) in projects.iter() {
+        set_project_aux(name, actions_path.as_deref())?;
+    }
+
+    // running evaluations are rooted when they finish
+    let pending = i32::from(TaskStatusKind::Pending);
+    let evaluations = schema::evaluations::table
+        .inner_join(schema::tasks::table)
+        .select((
+            schema::evaluations::id,
+            schema::evaluations::uuid,
+            schema::tasks::status.eq(pending),
+        ))
+        .load::<(i32, String, bool)>(conn)?;
+    create_dir_all(evaluations_dir())?;
+    let mut rooted: HashSet<String> = HashSet::new();
+    let uuids: HashSet<&String> = evaluations.iter().map(|(_, uuid, _)| uuid).collect();
+    for entry in read_dir(evaluations_dir())? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if uuids.contains(&name) {
+            rooted.insert(name);
+        } else {
+            remove_dir_all(entry.path())?;
+        }
+    }
+    for (id, uuid, pending) in evaluations.iter() {
+        if !pending && !rooted.contains(uuid) {
+            add_evaluation_aux(conn, *id, uuid)?;
+        }
     }
-    rename(&new_path, &cur_path)?;
 
     Ok(())
 }
 
-pub fn update(conn: &mut Conn) -> () {
-    update_aux(conn).unwrap_or_else(|e| tracing::error!("error when updating gcroots: {:?}", e));
+/// Brings the roots on disk in line with the database, in the background. This
+/// is only slow the first time, when the roots of every evaluation are created.
+pub fn sync() {
+    RUNTIME.spawn_blocking(|| {
+        let mut conn = POOL.get().unwrap();
+        sync_aux(&mut conn)
+            .unwrap_or_else(|e| tracing::error!("error when syncing gcroots: {:?}", e));
+    });
 }
@@ -2,10 +2,13 @@ use crate::error::Error;
 use crate::handles;
 use crate::log_event;
 use crate::models;
+use crate::queue;
 use crate::responses;
 use crate::runs;
 use crate::schema;
 use crate::Conn;
+use crate::POOL;
+use crate::RUNTIME;
 
 use typhon_types::*;
 
@@ -54,8 +57,7 @@ impl Job {
         };
         crate::evaluations::Evaluation::jobs(
             &handles::project(self.project.name.clone()),
-            &handle.evaluation,
-            self.evaluation.id,
+            &self.evaluation,
             Some(handle.system.clone()),
             Some(handle.name.clone()),
             conn,
@@ -66,15 +68,20 @@ impl Job {
     }
 
     /** Create a new run in the database, without running it. */
-    pub fn new_run(&self, conn: &mut Conn) -> Result<runs::Run, Error> {
+    pub fn new_run(&self, conn: &mut Conn, retry_reason: Option<&str>) -> Result<runs::Run, Error> {
         let run = conn.transaction::<models::Run, Error, _>(|conn| {
             let num = self.job.tries + 1;
+            // a new run supersedes a pending retry
             diesel::update(&self.job)
-                .set(schema::jobs::tries.eq(num))
+                .set((
+                    schema::jobs::tries.eq(num),
+                    schema::jobs::retry_at.eq(None::<i64>),
+                ))
                 .execute(conn)?;
             let new_run = models::NewRun {
                 job_id: self.job.id,
                 num,
+                retry_reason,
                 time_created: OffsetDateTime::now_utc().unix_timestamp(),
             };
             Ok(diesel::insert_into(schema::runs::table)
@@ -90,7 +97,7 @@ impl Job {
             job: self.job.clone(),
             run,
         };
-        log_event(Event::RunNew(run.handle()));
+        log_event(Event::RunNew(run.handle(), run.jobset_handle()));
         Ok(run)
     }
 
@@ -99,7 +106,75 @@ impl Job {
         // We should only allow rerunning a job when no other run is pending for
         // that job. But we first need to rework runs, as it is currently hard
         // to know wether a run is finished or not.
-        self.new_run(conn)?;
+        if self.job.error.is_some() {
+            return Err(Error::JobEvaluationFailed(self.handle()));
+        }
+        // manual reruns go ahead of the runs of new evaluations
+        self.new_run(conn, None)?.run(conn, queue::Priority::High)?;
+        Ok(())
+    }
+
+    pub fn retry_policy(&self) -> Option<data::RetryPolicy> {
+        self.job
+            .retry
+            .as_ref()
+            .and_then(|retry| serde_json::from_str(retry).ok())
+    }
+
+    /** Schedule a retry of the job after the failure of its run `num`, if
+     * its retry policy allows it. The due time is stored on the job, so that
+     * the retry is scheduled again after a restart. */
+    pub fn schedule_retry(&self, conn: &mut Conn, num: i32, reason: &str) -> Result<(), Error> {
+        let Some(policy) = self.retry_policy() else {
+            return Ok(());
+        };
+        if num as u32 >= policy.attempts {
+            return Ok(());
+        }
+        let delay = policy.delay(num as u32).as_secs() as i64;
+        let due = OffsetDateTime::now_utc().unix_timestamp() + delay;
+        let updated = diesel::update(&self.job)
+            .filter(schema::jobs::tries.eq(num))
+            .set(schema::jobs::retry_at.eq(due))
+            .execute(conn)?;
+        if updated > 0 {
+            self.clone().spawn_retry(num, due, reason.to_string());
+        }
+        Ok(())
+    }
+
+    /** Retry the job once `due` is reached. */
+    pub fn spawn_retry(self, num: i32, due: i64, reason: String) {
+        RUNTIME.spawn(async move {
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let delay = std::time::Duration::from_secs((due - now).max(0) as u64);
+            tokio::time::sleep(delay).await;
+            let res = RUNTIME
+                .spawn_blocking(move || {
+                    let mut conn = POOL.get().unwrap();
+                    self.retry(&mut conn, num, &reason)
+                })
+                .await;
+            match res {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => tracing::error!("failed to retry job: {:?}", e),
+                Err(e) => tracing::error!("retry of job panicked: {:?}", e),
+            }
+        });
+    }
+
+    /** Run the job again after the failure of its run `num`, unless it was
+     * rerun in the meantime. */
+    pub fn retry(&self, conn: &mut Conn, num: i32, reason: &str) -> Result<(), Error> {
+        let job = Self {
+            job: schema::jobs::table.find(self.job.id).first(conn)?,
+            ..self.clone()
+        };
+        if job.job.tries != num {
+            return Ok(());
+        }
+        job.new_run(conn, Some(reason))?
+            .run(conn, queue::Priority::Normal)?;
         Ok(())
     }
 }
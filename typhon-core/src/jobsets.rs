@@ -1,16 +1,24 @@
 use crate::error::Error;
 use crate::evaluations;
-use crate::gcroots;
 use crate::models;
 use crate::nix;
 use crate::schema;
 use crate::Conn;
-use crate::{handles, responses};
+use crate::{data, handles, responses};
 use crate::{log_event, Event};
 
+use typhon_types::data::TaskStatusKind;
+
 use diesel::prelude::*;
 use serde::Deserialize;
 use time::OffsetDateTime;
+use uuid::Uuid;
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// The number of evaluations in the history of a jobset
+const HISTORY_LENGTH: i64 = 10;
 
 #[derive(Clone)]
 pub struct Jobset {
@@ -21,6 +29,10 @@ pub struct Jobset {
 #[derive(Deserialize, PartialEq)]
 pub struct JobsetDecl {
     pub flake: bool,
+    #[serde(default)]
+    pub retry: Option<data::RetryPolicy>,
+    #[serde(default)]
+    pub schedule: Option<data::Schedule>,
     pub url: String,
 }
 
@@ -37,6 +49,7 @@ impl Jobset {
 
         let preexisting = schema::evaluations::table
             .inner_join(schema::tasks::table)
+            .filter(schema::evaluations::project_id.eq(self.project.id))
             .filter(schema::evaluations::jobset_name.eq(&self.jobset.name))
             .filter(schema::evaluations::url.eq(&url))
             .first::<(models::Evaluation, models::Task)>(conn)
@@ -57,6 +70,12 @@ impl Jobset {
     pub fn decl(&self) -> JobsetDecl {
         JobsetDecl {
             flake: self.jobset.flake,
+            retry: self
+                .jobset
+                .retry
+                .as_ref()
+                .and_then(|retry| serde_json::from_str(retry).ok()),
+            schedule: self.schedule(),
             url: self.jobset.url.clone(),
         }
     }
@@ -81,15 +100,110 @@ impl Jobset {
         }
     }
 
-    pub fn info(&self) -> responses::JobsetInfo {
-        responses::JobsetInfo {
+    pub fn info(&self, conn: &mut Conn) -> Result<responses::JobsetInfo, Error> {
+        let project = handles::project(self.project.name.clone());
+        let evaluations = schema::evaluations::table
+            .filter(schema::evaluations::project_id.eq(self.project.id))
+            .filter(schema::evaluations::jobset_name.eq(&self.jobset.name))
+            .order(schema::evaluations::id.desc())
+            .limit(HISTORY_LENGTH)
+            .load::<models::Evaluation>(conn)?;
+        let last_success = schema::evaluations::table
+            .inner_join(schema::tasks::table)
+            .filter(schema::evaluations::project_id.eq(self.project.id))
+            .filter(schema::evaluations::jobset_name.eq(&self.jobset.name))
+            .filter(schema::tasks::status.eq(i32::from(TaskStatusKind::Success)))
+            .order(schema::evaluations::id.desc())
+            .select(schema::evaluations::uuid)
+            .first::<String>(conn)
+            .optional()?;
+
+        let mut history: BTreeMap<responses::JobSystemName, Vec<Option<responses::TaskStatus>>> =
+            BTreeMap::new();
+        for (i, evaluation) in evaluations.iter().enumerate() {
+            let jobs = evaluations::Evaluation::jobs(&project, evaluation, None, None, conn)?;
+            for (name, job) in jobs.iter() {
+                history
+                    .entry(name.clone())
+                    .or_insert_with(|| vec![None; evaluations.len()])[i] = Some(job.into());
+            }
+        }
+
+        let evaluations: Vec<handles::Evaluation> = evaluations
+            .iter()
+            .map(|evaluation| evaluation_handle(&evaluation.uuid))
+            .collect();
+        Ok(responses::JobsetInfo {
             handle: self.handle(),
+            evaluations: evaluations.clone(),
             flake: self.jobset.flake,
+            history: history
+                .into_iter()
+                .map(|(job, statuses)| responses::JobHistory { job, statuses })
+                .collect(),
+            last_evaluation: evaluations.first().cloned(),
+            last_success: last_success.map(|uuid| evaluation_handle(&uuid)),
+            schedule: self.schedule(),
             url: self.jobset.url.clone(),
+        })
+    }
+
+    pub fn job_history(
+        &self,
+        conn: &mut Conn,
+        system: &str,
+        name: &str,
+        limit: u8,
+        offset: u32,
+    ) -> Result<Vec<responses::JobHistoryEntry>, Error> {
+        let project = handles::project(self.project.name.clone());
+        let evaluations = schema::jobs::table
+            .inner_join(schema::evaluations::table)
+            .filter(schema::evaluations::project_id.eq(self.project.id))
+            .filter(schema::evaluations::jobset_name.eq(&self.jobset.name))
+            .filter(schema::jobs::system.eq(system))
+            .filter(schema::jobs::name.eq(name))
+            .order(schema::evaluations::id.desc())
+            .limit(limit.into())
+            .offset(offset.into())
+            .select(schema::evaluations::all_columns)
+            .load::<models::Evaluation>(conn)?;
+
+        let mut entries = Vec::new();
+        for evaluation in evaluations.iter() {
+            let jobs = evaluations::Evaluation::jobs(
+                &project,
+                evaluation,
+                Some(system.to_string()),
+                Some(name.to_string()),
+                conn,
+            )?;
+            for job in jobs.into_values() {
+                let status = responses::TaskStatus::from(&job);
+                let duration = match status.times() {
+                    (Some(start), Some(end)) => Some((end - start).whole_seconds()),
+                    _ => None,
+                };
+                entries.push(responses::JobHistoryEntry {
+                    handle: job.handle,
+                    drv: job.drv,
+                    duration,
+                    status,
+                    time_created: OffsetDateTime::from_unix_timestamp(evaluation.time_created)?,
+                });
+            }
         }
+        Ok(entries)
+    }
+
+    pub fn schedule(&self) -> Option<data::Schedule> {
+        self.jobset
+            .schedule
+            .as_ref()
+            .and_then(|schedule| serde_json::from_str(schedule).ok())
     }
 
-    fn new_evaluation(
+    pub fn new_evaluation(
         &self,
         conn: &mut Conn,
         url: &String,
@@ -110,6 +224,7 @@ impl Jobset {
                 flake: self.jobset.flake,
                 jobset_name: &self.jobset.name,
                 project_id: self.project.id,
+                retry: self.jobset.retry.as_deref(),
                 task_id: task.task.id,
                 time_created,
                 url: &url,
@@ -127,24 +242,30 @@ impl Jobset {
 
         let run = {
             let evaluation = evaluation.clone();
-            move |sender| evaluation.run(sender)
+            move |sender, _| evaluation.run(sender)
         };
 
         let finish = {
             let evaluation = evaluation.clone();
             move |r| {
                 let handle = evaluation.handle();
+                let jobset = evaluation.jobset_handle();
                 let status = evaluation.finish(r);
-                (status, Event::EvaluationFinished(handle))
+                (status, Event::EvaluationFinished(handle, jobset))
             }
         };
 
-        log_event(Event::EvaluationNew(evaluation.handle()));
+        log_event(Event::EvaluationNew(
+            evaluation.handle(),
+            evaluation.jobset_handle(),
+        ));
 
-        evaluation.task.run(conn, run, finish)?;
-
-        gcroots::update(conn);
+        evaluation.task.run(conn, None, run, finish)?;
 
         Ok(evaluation)
     }
 }
+
+fn evaluation_handle(uuid: &str) -> handles::Evaluation {
+    handles::evaluation(Uuid::from_str(uuid).unwrap())
+}
@@ -1,6 +1,7 @@
 #![feature(impl_trait_in_fn_trait_return)]
 
 mod actions;
+mod agents;
 mod builds;
 mod evaluations;
 mod events;
@@ -10,10 +11,19 @@ mod jobsets;
 mod models;
 mod nix;
 mod projects;
+mod queue;
+mod recovery;
+mod reporters;
+mod retention;
 mod runs;
+mod sandbox;
+mod scheduler;
 mod schema;
 mod search;
 mod tasks;
+mod timeouts;
+mod tokens;
+mod users;
 
 pub mod build_manager;
 pub mod error;
@@ -27,6 +37,11 @@ pub use typhon_types::{
 };
 
 pub use crate::actions::webhooks;
+pub use crate::nix::Evaluator;
+pub use crate::queue::Limits;
+pub use crate::retention::Retention;
+pub use crate::sandbox::{Backend as SandboxBackend, Policy as SandboxPolicy, Sandbox};
+pub use crate::timeouts::{Timeout, Timeouts};
 
 use actions::Action;
 use builds::Build;
@@ -37,6 +52,9 @@ use jobsets::Jobset;
 use projects::Project;
 use runs::Run;
 use task_manager::TaskManager;
+use tokens::Token;
+use typhon_types::data::TokenScope as Scope;
+use users::User as Account;
 
 use argon2::PasswordHash;
 use diesel::prelude::*;
@@ -52,6 +70,13 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug)]
 pub struct Settings {
     pub password: PasswordHash<'static>,
+    pub evaluator: Evaluator,
+    pub limits: Limits,
+    pub retention: Retention,
+    pub sandbox: Sandbox,
+    /// Where Typhon stores its files, such as logs
+    pub state_dir: std::path::PathBuf,
+    pub timeouts: Timeouts,
 }
 
 const _: () = {
@@ -74,18 +99,63 @@ fn verify_password(password: &[u8]) -> bool {
         .is_ok()
 }
 
-pub type DbPool = r2d2::Pool<r2d2::ConnectionManager<diesel::SqliteConnection>>;
-pub type Conn =
-    diesel::r2d2::PooledConnection<diesel::r2d2::ConnectionManager<diesel::SqliteConnection>>;
+/// A connection to either backend, chosen from the scheme of `DATABASE_URL`
+#[derive(diesel::MultiConnection)]
+pub enum DbConnection {
+    Sqlite(diesel::SqliteConnection),
+    Postgres(diesel::PgConnection),
+}
+
+pub type DbPool = r2d2::Pool<ConnectionManager>;
+pub type Conn = r2d2::PooledConnection<ConnectionManager>;
+
+/// Unlike diesel's manager, establishes connections to the backend given by
+/// the URL instead of trying every backend in turn.
+#[derive(Debug)]
+pub struct ConnectionManager {
+    database_url: String,
+}
+
+impl ConnectionManager {
+    fn is_postgres(&self) -> bool {
+        self.database_url.starts_with("postgres://")
+            || self.database_url.starts_with("postgresql://")
+    }
+}
+
+impl r2d2::ManageConnection for ConnectionManager {
+    type Connection = DbConnection;
+    type Error = r2d2::Error;
+
+    fn connect(&self) -> Result<DbConnection, r2d2::Error> {
+        if self.is_postgres() {
+            diesel::PgConnection::establish(&self.database_url).map(DbConnection::Postgres)
+        } else {
+            diesel::SqliteConnection::establish(&self.database_url).map(DbConnection::Sqlite)
+        }
+        .map_err(r2d2::Error::ConnectionError)
+    }
+
+    fn is_valid(&self, conn: &mut DbConnection) -> Result<(), r2d2::Error> {
+        use diesel::r2d2::R2D2Connection;
+        conn.ping().map_err(r2d2::Error::QueryError)
+    }
+
+    fn has_broken(&self, conn: &mut DbConnection) -> bool {
+        use diesel::r2d2::R2D2Connection;
+        std::thread::panicking() || conn.is_broken()
+    }
+}
 
 #[derive(Debug)]
 pub struct ConnectionCustomizer {}
 
-impl diesel::r2d2::CustomizeConnection<diesel::SqliteConnection, diesel::r2d2::Error>
-    for ConnectionCustomizer
-{
-    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+impl diesel::r2d2::CustomizeConnection<DbConnection, diesel::r2d2::Error> for ConnectionCustomizer {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
         use diesel::connection::SimpleConnection;
+        let DbConnection::Sqlite(conn) = conn else {
+            return Ok(());
+        };
         (|| {
             conn.batch_execute("PRAGMA foreign_keys = ON;")?;
             conn.batch_execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
@@ -105,20 +175,34 @@ pub static TASKS: Lazy<TaskManager<i32>> = Lazy::new(|| TaskManager::new());
 pub static LOGS: Lazy<logs::live::Cache<i32>> = Lazy::new(logs::live::Cache::new);
 pub static EVENT_LOGGER: Lazy<events::EventLogger> = Lazy::new(events::EventLogger::new);
 pub static CURRENT_SYSTEM: Lazy<String> = Lazy::new(nix::current_system);
+pub static SCHEDULER: Lazy<scheduler::Scheduler> = Lazy::new(scheduler::Scheduler::new);
+pub static QUEUE: Lazy<queue::Queue> = Lazy::new(queue::Queue::new);
+pub static REPORTERS: Lazy<reporters::Reporters> = Lazy::new(reporters::Reporters::new);
+pub static AGENTS: Lazy<agents::Agents> = Lazy::new(agents::Agents::new);
+pub static PRUNER: Lazy<retention::Pruner> = Lazy::new(retention::Pruner::new);
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum User {
     Admin,
     Anonymous,
+    Account(String),
+    /// The secret of an API token
+    Token(String),
 }
 
-impl User {
-    pub fn is_admin(&self) -> bool {
+// Secrets must not end up in the logs
+impl std::fmt::Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            User::Admin => true,
-            _ => false,
+            User::Admin => write!(f, "Admin"),
+            User::Anonymous => write!(f, "Anonymous"),
+            User::Account(name) => write!(f, "Account({:?})", name),
+            User::Token(_) => write!(f, "Token(..)"),
         }
     }
+}
+
+impl User {
     pub fn from_password(password: &[u8]) -> Self {
         if verify_password(password) {
             User::Admin
@@ -126,23 +210,112 @@ impl User {
             User::Anonymous
         }
     }
+
+    /// The account behind the user, along with the scope of the token if
+    /// the user is authenticated with one.
+    fn account(&self, conn: &mut Conn) -> Result<Option<(Account, Option<Scope>)>, Error> {
+        let get = |conn: &mut Conn, name: &String| match Account::get(conn, name) {
+            Ok(account) => Ok(Some(account)),
+            Err(Error::UserNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        };
+        Ok(match self {
+            User::Admin => get(conn, &"admin".to_string())?.map(|account| (account, None)),
+            User::Anonymous => None,
+            User::Account(name) => get(conn, name)?.map(|account| (account, None)),
+            User::Token(secret) => match Token::authenticate(conn, secret)? {
+                Some(token) => Some((token.user(conn)?, Some(token.scope()))),
+                None => None,
+            },
+        })
+    }
 }
 
-pub fn authorize_request(user: &User, req: &requests::Request) -> bool {
+pub fn authorize_request(
+    conn: &mut Conn,
+    user: &User,
+    req: &requests::Request,
+) -> Result<bool, Error> {
+    use data::Role;
     use requests::*;
-    match req {
+    let account = match user {
+        User::Admin => return Ok(true),
+        _ => user.account(conn)?,
+    };
+    let public = matches!(
+        req,
         Request::Search { .. }
-        | Request::Project(_, Project::Info)
-        | Request::Jobset(_, Jobset::Info)
-        | Request::Evaluation(_, Evaluation::Info)
-        | Request::Job(_, Job::Info)
-        | Request::Run(_, Run::Info)
-        | Request::Build(_, Build::Info)
-        | Request::Action(_, Action::Info)
-        | Request::Login { .. }
-        | Request::User => true,
-        _ => user.is_admin(),
+            | Request::Project(_, Project::Info)
+            | Request::Jobset(_, Jobset::Info)
+            | Request::Jobset(_, Jobset::JobHistory { .. })
+            | Request::Evaluation(_, Evaluation::Info)
+            | Request::Evaluation(_, Evaluation::Compare(_))
+            | Request::Job(_, Job::Info)
+            | Request::Run(_, Run::Info)
+            | Request::Build(_, Build::Info)
+            | Request::Action(_, Action::Info)
+            | Request::Queue
+            | Request::Login { .. }
+            | Request::User
+    );
+    let Some((account, scope)) = account else {
+        // an invalid or expired token is rejected rather than downgraded
+        return Ok(public && !matches!(user, User::Token(_)));
+    };
+    if public {
+        return Ok(true);
+    }
+    let (project, required) = match req {
+        Request::Project(
+            handle,
+            Project::Delete | Project::Refresh | Project::SetDecl(_) | Project::SetRole { .. },
+        ) => (
+            Some(projects::Project::get(conn, handle)?.project),
+            Role::Owner,
+        ),
+        Request::Project(handle, Project::UpdateJobsets) => (
+            Some(projects::Project::get(conn, handle)?.project),
+            Role::Maintainer,
+        ),
+        Request::Jobset(handle, Jobset::Evaluate(_)) => (
+            Some(projects::Project::get(conn, &handle.project)?.project),
+            Role::Maintainer,
+        ),
+        Request::Evaluation(handle, Evaluation::Cancel) => (
+            Some(evaluations::Evaluation::get(conn, handle)?.project),
+            Role::Maintainer,
+        ),
+        Request::Job(handle, Job::Rerun) => (
+            Some(evaluations::Evaluation::get(conn, &handle.evaluation)?.project),
+            Role::Maintainer,
+        ),
+        Request::Run(handle, Run::Cancel) => (
+            Some(evaluations::Evaluation::get(conn, &handle.job.evaluation)?.project),
+            Role::Maintainer,
+        ),
+        Request::SetPassword { name, .. } if *name == account.user.name => {
+            return Ok(scope.map_or(true, |scope| scope.allows(None, Role::Owner)))
+        }
+        Request::CreateToken { .. } | Request::RevokeToken { .. } | Request::Tokens => {
+            return Ok(scope.map_or(true, |scope| scope.allows(None, Role::Owner)))
+        }
+        _ => (None, Role::Owner),
+    };
+    if let Some(scope) = scope {
+        let handle = project
+            .as_ref()
+            .map(|project| handles::project(project.name.clone()));
+        if !scope.allows(handle.as_ref(), required) {
+            return Ok(false);
+        }
     }
+    if account.user.admin {
+        return Ok(true);
+    }
+    Ok(match project {
+        Some(project) => account.role(conn, project.id)? >= Some(required),
+        None => false,
+    })
 }
 
 pub fn handle_request_aux(
@@ -150,7 +323,7 @@ pub fn handle_request_aux(
     user: &User,
     req: &requests::Request,
 ) -> Result<Response, Error> {
-    if !authorize_request(user, req) {
+    if !authorize_request(conn, user, req)? {
         return Err(Error::AccessDenied);
     }
     Ok(match req {
@@ -166,9 +339,11 @@ pub fn handle_request_aux(
         requests::Request::Project(project_handle, req) => {
             let project = Project::get(conn, &project_handle)?;
             match req {
+                requests::Project::Delete => project.delete(conn)?,
                 requests::Project::Info => return Ok(Response::ProjectInfo(project.info(conn)?)),
                 requests::Project::Refresh => project.refresh(conn)?,
                 requests::Project::SetDecl(decl) => project.set_decl(conn, decl)?,
+                requests::Project::SetRole { user, role } => project.set_role(conn, user, role)?,
                 requests::Project::UpdateJobsets => project.update_jobsets(conn)?,
             };
             Response::Ok
@@ -180,7 +355,13 @@ pub fn handle_request_aux(
                     let evaluation_handle = jobset.evaluate(conn, *force)?;
                     Response::JobsetEvaluate(evaluation_handle)
                 }
-                requests::Jobset::Info => Response::JobsetInfo(jobset.info()),
+                requests::Jobset::Info => Response::JobsetInfo(jobset.info(conn)?),
+                requests::Jobset::JobHistory {
+                    system,
+                    name,
+                    limit,
+                    offset,
+                } => Response::JobHistory(jobset.job_history(conn, system, name, *limit, *offset)?),
             }
         }
         requests::Request::Evaluation(evaluation_handle, req) => {
@@ -190,6 +371,9 @@ pub fn handle_request_aux(
                     evaluation.cancel();
                     Response::Ok
                 }
+                requests::Evaluation::Compare(base) => {
+                    Response::EvaluationCompare(evaluation.compare(conn, base)?)
+                }
                 requests::Evaluation::Info => Response::EvaluationInfo(evaluation.info(conn)?),
             }
         }
@@ -218,23 +402,52 @@ pub fn handle_request_aux(
         requests::Request::Run(run_handle, req) => {
             let run = Run::get(conn, &run_handle)?;
             match req {
-                //requests::Run::Cancel => {
-                //    run.cancel();
-                //    Response::Ok
-                //}
+                requests::Run::Cancel => {
+                    run.cancel();
+                    Response::Ok
+                }
                 requests::Run::Info => Response::RunInfo(run.info()),
             }
         }
-        requests::Request::Login { password } => {
-            if verify_password(password.as_bytes()) {
-                Response::Ok
-            } else {
-                Err(Error::LoginError)?
-            }
+        requests::Request::CreateUser { name, decl } => {
+            Account::create(conn, name, decl)?;
+            Response::Ok
         }
-        requests::Request::User => Response::User(match user {
-            User::Admin => Some(data::User::Admin),
-            User::Anonymous => None,
+        requests::Request::DeleteUser { name } => {
+            Account::get(conn, name)?.delete(conn)?;
+            Response::Ok
+        }
+        requests::Request::SetPassword { name, password } => {
+            Account::get(conn, name)?.set_password(conn, password)?;
+            Response::Ok
+        }
+        requests::Request::CreateToken { name, decl } => {
+            let (account, _) = user.account(conn)?.ok_or(Error::AccessDenied)?;
+            Response::Token(Token::create(conn, &account, name, decl)?)
+        }
+        requests::Request::RevokeToken { name } => {
+            let (account, _) = user.account(conn)?.ok_or(Error::AccessDenied)?;
+            Token::get(conn, &account, name)?.revoke(conn)?;
+            Response::Ok
+        }
+        requests::Request::Tokens => {
+            let (account, _) = user.account(conn)?.ok_or(Error::AccessDenied)?;
+            Response::Tokens(Token::list(conn, &account)?)
+        }
+        requests::Request::Queue => Response::Queue(QUEUE.info()),
+        requests::Request::Prune { dry_run } => Response::Prune(retention::prune(
+            conn,
+            &Settings::get().retention,
+            *dry_run,
+        )?),
+        requests::Request::Login { name, password } => match Account::get(conn, name) {
+            Ok(account) if account.verify_password(password.as_bytes()) => Response::Ok,
+            Ok(_) | Err(Error::UserNotFound(_)) => Err(Error::LoginError)?,
+            Err(e) => Err(e)?,
+        },
+        requests::Request::User => Response::User(match user.account(conn)? {
+            Some((account, _)) => Some(account.info(conn)?),
+            None => None,
         }),
     })
 }
@@ -263,16 +476,37 @@ pub fn log_event(event: Event) {
     EVENT_LOGGER.log(event);
 }
 
-pub fn log(handle: handles::Log) -> Result<Option<impl Stream<Item = String>>, Error> {
+pub fn log(
+    handle: handles::Log,
+    range: data::LogRange,
+) -> Result<Option<impl Stream<Item = String>>, Error> {
     let mut conn = POOL.get().unwrap();
     match handle {
         handles::Log::Evaluation(handle) => evaluations::Evaluation::get(&mut conn, &handle)?
             .task
-            .log(&mut conn),
-        handles::Log::Build(handle) => builds::Build::get(&mut conn, &handle)?.task.log(&mut conn),
+            .log(&mut conn, range),
+        handles::Log::Build(handle) => builds::Build::get(&mut conn, &handle)?
+            .task
+            .log(&mut conn, range),
         handles::Log::Action(handle) => actions::Action::get(&mut conn, &handle)?
             .task
-            .log(&mut conn),
+            .log(&mut conn, range),
+    }
+}
+
+/// The number of lines of a log, to turn tails into line ranges
+pub fn log_lines(handle: handles::Log) -> Result<u64, Error> {
+    let mut conn = POOL.get().unwrap();
+    match handle {
+        handles::Log::Evaluation(handle) => evaluations::Evaluation::get(&mut conn, &handle)?
+            .task
+            .log_lines(&mut conn),
+        handles::Log::Build(handle) => builds::Build::get(&mut conn, &handle)?
+            .task
+            .log_lines(&mut conn),
+        handles::Log::Action(handle) => actions::Action::get(&mut conn, &handle)?
+            .task
+            .log_lines(&mut conn),
     }
 }
 
@@ -314,6 +548,9 @@ pub async fn shutdown() {
     // exists no other similar assumption at the moment, but I chose to shut
     // down everything in sequence anyway to try to avoid future problems.
     eprintln!("Typhon is shutting down...");
+    SCHEDULER.shutdown().await;
+    PRUNER.shutdown().await;
+    REPORTERS.shutdown().await;
     build_manager::BUILDS.shutdown().await;
     RUNS.shutdown().await;
     TASKS.shutdown().await;
@@ -323,28 +560,53 @@ pub async fn shutdown() {
 }
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
+pub const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations-postgres");
 
 fn pool() -> DbPool {
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let manager = diesel::r2d2::ConnectionManager::<SqliteConnection>::new(database_url);
+    let manager = ConnectionManager { database_url };
     let pool = diesel::r2d2::Pool::builder()
         .connection_customizer(Box::new(ConnectionCustomizer {}))
         .build(manager)
-        .expect("database URL should be valid path to SQLite DB file");
+        .expect("database URL should be a path to an SQLite DB file or a PostgreSQL URL");
 
     // Run migrations
     let mut conn = pool.get().unwrap();
-    let _ = conn
-        .run_pending_migrations(MIGRATIONS)
-        .expect("failed to run migrations");
+    let _ = match &mut *conn {
+        DbConnection::Sqlite(conn) => conn.run_pending_migrations(MIGRATIONS),
+        DbConnection::Postgres(conn) => conn.run_pending_migrations(POSTGRES_MIGRATIONS),
+    }
+    .expect("failed to run migrations");
 
     pool
 }
 
-pub fn init(password: &String) {
-    let password = Box::leak(Box::new(password.clone()));
-    let password = PasswordHash::new(password).expect("Unable to parse the password hash");
-    Settings::init(Settings { password });
+/// Parses the Argon2id hash of a password, for `Settings::password`.
+pub fn password_hash(hash: &str) -> PasswordHash<'static> {
+    let hash = Box::leak(Box::new(hash.to_string()));
+    PasswordHash::new(hash).expect("Unable to parse the password hash")
+}
+
+pub fn init(settings: Settings, resume: bool) {
+    Settings::init(settings);
     // Force database migrations
     let _ = once_cell::sync::Lazy::force(&POOL);
+    let mut conn = POOL.get().unwrap();
+    Account::init_admin(&mut conn, Settings::get().password.to_string().as_str())
+        .expect("failed to initialize the admin account");
+    logs::files::migrate(&mut conn).expect("failed to move logs to files");
+    recovery::reconcile(&mut conn, resume).expect("failed to recover interrupted tasks");
+    gcroots::sync();
+    // Start polling scheduled jobsets
+    let _ = once_cell::sync::Lazy::force(&SCHEDULER);
+    // Start reporting statuses
+    let _ = once_cell::sync::Lazy::force(&REPORTERS);
+    // Start pruning old evaluations
+    let _ = once_cell::sync::Lazy::force(&PRUNER);
+}
+
+/// Starts accepting build agents on the Unix socket at `path`. `password` is
+/// the Argon2id hash of the password agents must present.
+pub fn listen_agents(path: &str, password: &str) {
+    agents::listen(path.to_string(), password_hash(password));
 }
@@ -1,4 +1,7 @@
+/// Live logs of running tasks. Lines are appended to the spool of their task
+/// before being sent to listeners, which replay the spool when they join.
 pub mod live {
+    use super::files::Spool;
     use crate::RUNTIME;
 
     use tokio::sync::mpsc;
@@ -11,23 +14,35 @@ pub mod live {
     enum Msg<Id> {
         Remove {
             id: Id,
-            dump_sender: oneshot::Sender<Option<String>>,
+            spool_sender: oneshot::Sender<Option<Spool>>,
         },
         Init {
             id: Id,
+            spool: Option<Spool>,
         },
         Line {
             id: Id,
             line: String,
         },
+        Lines {
+            id: Id,
+            lines_sender: oneshot::Sender<Option<u64>>,
+        },
         Listen {
             id: Id,
+            tail: Option<u64>,
             lines_sender: mpsc::UnboundedSender<String>,
             not_found_sender: oneshot::Sender<bool>,
         },
         Shutdown,
     }
 
+    struct Log {
+        spool: Option<Spool>,
+        lines: u64,
+        listeners: Vec<mpsc::UnboundedSender<String>>,
+    }
+
     #[derive(Debug)]
     pub struct Cache<Id> {
         sender: mpsc::UnboundedSender<Msg<Id>>,
@@ -42,41 +57,68 @@ pub mod live {
             let (sender, mut receiver) = mpsc::unbounded_channel();
             let (watch_send, watch) = watch::channel(());
             RUNTIME.spawn(async move {
-                type Listeners = Vec<mpsc::UnboundedSender<String>>;
-                let mut state: HashMap<Id, (Vec<String>, Listeners)> = HashMap::new();
+                let mut state: HashMap<Id, Log> = HashMap::new();
                 while let Some(msg) = receiver.recv().await {
                     match msg {
-                        Msg::Remove { id, dump_sender } => {
-                            dump_sender
-                                .send(state.remove(&id).map(|(lines, _)| lines.join("\n")))
+                        Msg::Remove { id, spool_sender } => {
+                            spool_sender
+                                .send(state.remove(&id).and_then(|log| log.spool))
                                 .unwrap();
                         }
-                        Msg::Init { id } => {
-                            state.insert(id.clone(), (Vec::new(), Vec::new()));
+                        Msg::Init { id, spool } => {
+                            state.insert(
+                                id,
+                                Log {
+                                    spool,
+                                    lines: 0,
+                                    listeners: Vec::new(),
+                                },
+                            );
                         }
                         Msg::Line { id, line } => {
-                            let (lines, ref mut listeners) = state
+                            let log = state
                                 .get_mut(&id)
                                 .expect("log channels need to be initialized before sending lines");
-                            lines.push(line.clone());
+                            if let Some(spool) = log.spool.as_mut() {
+                                spool.write_line(line.clone());
+                                log.lines += 1;
+                            }
 
-                            *listeners = listeners
-                                .iter()
-                                .filter(|listener| listener.send(line.clone()).is_ok())
-                                .cloned()
-                                .collect();
+                            log.listeners
+                                .retain(|listener| listener.send(line.clone()).is_ok());
+                        }
+                        Msg::Lines { id, lines_sender } => {
+                            let _ = lines_sender.send(state.get(&id).map(|log| log.lines));
                         }
                         Msg::Listen {
                             id,
+                            tail,
                             lines_sender,
                             not_found_sender,
                         } => {
-                            if let Some((lines, listeners)) = state.get_mut(&id) {
+                            if let Some(log) = state.get_mut(&id) {
                                 not_found_sender.send(false).unwrap();
-                                for line in lines {
-                                    lines_sender.send(line.clone()).unwrap();
-                                }
-                                listeners.push(lines_sender);
+                                // the spool is opened now, so that it can be
+                                // read even if the task finishes meanwhile,
+                                // once the lines written so far reach it
+                                let file = log.spool.as_ref().and_then(|spool| {
+                                    let file = spool
+                                        .open()
+                                        .map_err(|e| tracing::error!("failed to open spool: {}", e))
+                                        .ok()?;
+                                    Some((file, spool.sync()))
+                                });
+                                let count = log.lines;
+                                let skip = tail.map_or(0, |n| count.saturating_sub(n));
+                                let (follow_sender, follow_receiver) = mpsc::unbounded_channel();
+                                log.listeners.push(follow_sender);
+                                RUNTIME.spawn(replay(
+                                    file,
+                                    skip,
+                                    count,
+                                    follow_receiver,
+                                    lines_sender,
+                                ));
                             } else {
                                 not_found_sender.send(true).unwrap();
                             }
@@ -89,27 +131,52 @@ pub mod live {
             Self { sender, watch }
         }
 
-        pub fn remove(&self, id: &Id) -> Option<String> {
-            let (dump_sender, remove_receiver) = oneshot::channel();
+        /// Stops the live log, returns its spool
+        pub fn remove(&self, id: &Id) -> Option<Spool> {
+            let (spool_sender, remove_receiver) = oneshot::channel();
             self.sender
                 .send(Msg::Remove {
                     id: id.clone(),
-                    dump_sender,
+                    spool_sender,
                 })
                 .unwrap();
             remove_receiver.blocking_recv().unwrap()
         }
 
-        pub fn init(&self, id: &Id) -> () {
-            self.sender.send(Msg::Init { id: id.clone() }).unwrap();
+        pub fn init(&self, id: &Id, spool: Option<Spool>) {
+            self.sender
+                .send(Msg::Init {
+                    id: id.clone(),
+                    spool,
+                })
+                .unwrap();
         }
 
-        pub fn listen(&self, id: &Id) -> Option<impl futures_core::stream::Stream<Item = String>> {
+        /// The number of lines of a live log
+        pub fn lines(&self, id: &Id) -> Option<u64> {
+            let (lines_sender, lines_receiver) = oneshot::channel();
+            self.sender
+                .send(Msg::Lines {
+                    id: id.clone(),
+                    lines_sender,
+                })
+                .unwrap();
+            lines_receiver.blocking_recv().unwrap()
+        }
+
+        /// Listens to a live log, from its first line or from its last
+        /// `tail` lines.
+        pub fn listen(
+            &self,
+            id: &Id,
+            tail: Option<u64>,
+        ) -> Option<impl futures_core::stream::Stream<Item = String>> {
             let (lines_sender, mut lines_receiver) = mpsc::unbounded_channel();
             let (not_found_sender, not_found_receiver) = oneshot::channel();
             self.sender
                 .send(Msg::Listen {
                     id: id.clone(),
+                    tail,
                     lines_sender,
                     not_found_sender,
                 })
@@ -140,4 +207,752 @@ pub mod live {
             while self.watch.clone().changed().await.is_ok() {}
         }
     }
+
+    /// Sends the `count` first lines of the spool to a new listener, skipping
+    /// `skip` of them, then the lines that came meanwhile
+    async fn replay(
+        file: Option<(std::fs::File, oneshot::Receiver<()>)>,
+        skip: u64,
+        count: u64,
+        mut follow_receiver: mpsc::UnboundedReceiver<String>,
+        lines_sender: mpsc::UnboundedSender<String>,
+    ) {
+        if let Some((file, sync)) = file {
+            let _ = sync.await;
+            let lines_sender = lines_sender.clone();
+            let _ = RUNTIME
+                .spawn_blocking(move || {
+                    let lines = super::files::read_spool(file)
+                        .take(count as usize)
+                        .skip(skip as usize);
+                    for line in lines {
+                        if lines_sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .await;
+        }
+        while let Some(line) = follow_receiver.recv().await {
+            if lines_sender.send(line).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Selection of the lines in a `LogRange`
+pub mod range {
+    use typhon_types::data::LogRange;
+
+    use futures_core::stream::Stream;
+
+    use std::collections::VecDeque;
+
+    pub fn select<'a>(
+        lines: impl Iterator<Item = String> + 'a,
+        range: LogRange,
+    ) -> Box<dyn Iterator<Item = String> + 'a> {
+        match range {
+            LogRange::All => Box::new(lines),
+            LogRange::Lines { start, end } => Box::new(
+                lines
+                    .skip(start as usize)
+                    .take(end.map_or(usize::MAX, |end| end.saturating_sub(start) as usize)),
+            ),
+            LogRange::Bytes { start, end } => {
+                let mut offset = 0;
+                Box::new(
+                    lines
+                        .map(move |line| {
+                            let line_offset = offset;
+                            offset += line.len() as u64 + 1;
+                            (line_offset, line)
+                        })
+                        .skip_while(move |(offset, _)| *offset < start)
+                        .take_while(move |(offset, _)| end.map_or(true, |end| *offset < end))
+                        .map(|(_, line)| line),
+                )
+            }
+            LogRange::Tail(n) => {
+                let mut tail = VecDeque::new();
+                for line in lines {
+                    if tail.len() as u64 >= n {
+                        tail.pop_front();
+                    }
+                    if n > 0 {
+                        tail.push_back(line);
+                    }
+                }
+                Box::new(tail.into_iter())
+            }
+        }
+    }
+
+    /// Like `select`, for live logs. Tails are handled by the cache.
+    pub fn select_stream(
+        lines: impl Stream<Item = String>,
+        range: LogRange,
+    ) -> impl Stream<Item = String> {
+        async_stream::stream! {
+            let (mut index, mut offset) = (0, 0);
+            for await line in lines {
+                let (line_index, line_offset) = (index, offset);
+                index += 1;
+                offset += line.len() as u64 + 1;
+                let (position, start, end) = match range {
+                    LogRange::Lines { start, end } => (line_index, start, end),
+                    LogRange::Bytes { start, end } => (line_offset, start, end),
+                    LogRange::All | LogRange::Tail(_) => (0, 0, None),
+                };
+                if end.is_some_and(|end| position >= end) {
+                    break;
+                }
+                if position >= start {
+                    yield line;
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn lines() -> impl Iterator<Item = String> {
+            ["a", "bb", "ccc", "dddd"].into_iter().map(String::from)
+        }
+
+        fn selected(range: LogRange) -> Vec<String> {
+            select(lines(), range).collect()
+        }
+
+        #[test]
+        fn all() {
+            assert_eq!(selected(LogRange::All), ["a", "bb", "ccc", "dddd"]);
+        }
+
+        #[test]
+        fn lines_range() {
+            let range = |start, end| LogRange::Lines { start, end };
+            assert_eq!(selected(range(1, Some(3))), ["bb", "ccc"]);
+            assert_eq!(selected(range(2, None)), ["ccc", "dddd"]);
+            assert_eq!(selected(range(3, Some(1))), Vec::<String>::new());
+            assert_eq!(selected(range(10, None)), Vec::<String>::new());
+        }
+
+        #[test]
+        fn bytes_range() {
+            // the lines start at bytes 0, 2, 5 and 9
+            let range = |start, end| LogRange::Bytes { start, end };
+            assert_eq!(selected(range(0, Some(2))), ["a"]);
+            assert_eq!(selected(range(1, Some(6))), ["bb", "ccc"]);
+            assert_eq!(selected(range(5, None)), ["ccc", "dddd"]);
+            assert_eq!(selected(range(10, None)), Vec::<String>::new());
+        }
+
+        #[test]
+        fn tail() {
+            assert_eq!(selected(LogRange::Tail(2)), ["ccc", "dddd"]);
+            assert_eq!(selected(LogRange::Tail(10)), ["a", "bb", "ccc", "dddd"]);
+            assert_eq!(selected(LogRange::Tail(0)), Vec::<String>::new());
+        }
+    }
+}
+
+/// Logs are stored as zstd-compressed files in the `logs` directory of the
+/// state directory, named after the id of their row in the `logs` table.
+///
+/// Every `FRAME_LINES` lines, a log starts a new zstd frame. An index next to
+/// the file records where frames start, so that a range of a log can be read
+/// without decompressing what comes before it.
+pub mod files {
+    use crate::error::Error;
+    use crate::schema;
+    use crate::Conn;
+    use crate::Settings;
+
+    use typhon_types::data::LogRange;
+
+    use diesel::prelude::*;
+    use futures_core::stream::Stream;
+    use once_cell::sync::Lazy;
+    use tokio::sync::{mpsc, oneshot};
+
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+
+    const LEVEL: i32 = 3;
+
+    const FRAME_LINES: u64 = 1024;
+
+    /// The path of a log, relative to the state directory
+    fn path(id: i32) -> String {
+        format!("logs/{}.zst", id)
+    }
+
+    fn spool_path(id: i32) -> String {
+        format!("logs/{}.live", id)
+    }
+
+    fn index_path(path: &Path) -> PathBuf {
+        path.with_extension("idx")
+    }
+
+    fn full_path(path: &str) -> PathBuf {
+        Settings::get().state_dir.join(path)
+    }
+
+    /// Where a frame starts, in the file and in the log
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct Frame {
+        offset: u64,
+        line: u64,
+        byte: u64,
+    }
+
+    /// The index of a log is its number of lines, followed by its frames, as
+    /// little-endian integers
+    #[derive(Debug, PartialEq, Eq)]
+    struct Index {
+        lines: u64,
+        frames: Vec<Frame>,
+    }
+
+    impl Index {
+        fn write(&self, path: &Path) -> std::io::Result<()> {
+            let mut bytes = Vec::with_capacity(8 + 24 * self.frames.len());
+            bytes.extend(self.lines.to_le_bytes());
+            for frame in self.frames.iter() {
+                bytes.extend(frame.offset.to_le_bytes());
+                bytes.extend(frame.line.to_le_bytes());
+                bytes.extend(frame.byte.to_le_bytes());
+            }
+            std::fs::write(path, bytes)
+        }
+
+        /// Reads the index of a log, if it has one. Logs written by previous
+        /// versions of Typhon do not.
+        fn read(path: &Path) -> std::io::Result<Option<Self>> {
+            let bytes = match std::fs::read(path) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let mut integers = bytes
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+            let lines = match integers.next() {
+                Some(lines) if bytes.len() % 24 == 8 => lines,
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "malformed log index",
+                    ))
+                }
+            };
+            let mut frames = Vec::new();
+            while let (Some(offset), Some(line), Some(byte)) =
+                (integers.next(), integers.next(), integers.next())
+            {
+                frames.push(Frame { offset, line, byte });
+            }
+            Ok(Some(Self { lines, frames }))
+        }
+
+        /// The last frame starting at or before the first line of `range`,
+        /// and the range relative to that frame
+        fn seek(&self, range: LogRange) -> Option<(Frame, LogRange)> {
+            let before = |key: fn(&Frame) -> u64, position: u64| {
+                let i = self.frames.partition_point(|frame| key(frame) <= position);
+                self.frames.get(i.checked_sub(1)?).copied()
+            };
+            match range {
+                LogRange::All => None,
+                LogRange::Lines { start, end } => {
+                    let frame = before(|frame| frame.line, start)?;
+                    let range = LogRange::Lines {
+                        start: start - frame.line,
+                        end: end.map(|end| end.saturating_sub(frame.line)),
+                    };
+                    Some((frame, range))
+                }
+                LogRange::Bytes { start, end } => {
+                    let frame = before(|frame| frame.byte, start)?;
+                    let range = LogRange::Bytes {
+                        start: start - frame.byte,
+                        end: end.map(|end| end.saturating_sub(frame.byte)),
+                    };
+                    Some((frame, range))
+                }
+                LogRange::Tail(n) => {
+                    let frame = before(|frame| frame.line, self.lines.saturating_sub(n))?;
+                    Some((frame, range))
+                }
+            }
+        }
+    }
+
+    /// Writes a log as lines come
+    pub struct Writer {
+        file: BufWriter<File>,
+        /// The uncompressed lines of the current frame
+        frame: Vec<u8>,
+        frames: Vec<Frame>,
+        full_path: PathBuf,
+        lines: u64,
+        offset: u64,
+        path: String,
+        size: u64,
+    }
+
+    impl Writer {
+        pub fn create(id: i32) -> std::io::Result<Self> {
+            let path = path(id);
+            Self::create_at(full_path(&path), path)
+        }
+
+        fn create_at(full_path: PathBuf, path: String) -> std::io::Result<Self> {
+            if let Some(parent) = full_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            Ok(Self {
+                file: BufWriter::new(File::create(&full_path)?),
+                frame: Vec::new(),
+                frames: Vec::new(),
+                full_path,
+                lines: 0,
+                offset: 0,
+                path,
+                size: 0,
+            })
+        }
+
+        fn write_frame(&mut self) -> std::io::Result<()> {
+            let compressed = zstd::encode_all(&self.frame[..], LEVEL)?;
+            self.file.write_all(&compressed)?;
+            self.offset += compressed.len() as u64;
+            self.frame.clear();
+            Ok(())
+        }
+
+        pub fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+            // lines are separated, not terminated, by newlines, but frames
+            // end with one so that they can be decoded on their own
+            if self.lines > 0 {
+                self.frame.push(b'\n');
+                self.size += 1;
+                if self.lines % FRAME_LINES == 0 {
+                    self.write_frame()?;
+                }
+            }
+            if self.lines % FRAME_LINES == 0 {
+                self.frames.push(Frame {
+                    offset: self.offset,
+                    line: self.lines,
+                    byte: self.size,
+                });
+            }
+            self.frame.extend(line.as_bytes());
+            self.lines += 1;
+            self.size += line.len() as u64;
+            Ok(())
+        }
+
+        /// Completes the file and its index, returns the path and the size of
+        /// the log
+        pub fn finish(mut self) -> std::io::Result<(String, u64)> {
+            self.write_frame()?;
+            self.file.flush()?;
+            let index = Index {
+                lines: self.lines,
+                frames: self.frames,
+            };
+            index.write(&index_path(&self.full_path))?;
+            Ok((self.path, self.size))
+        }
+    }
+
+    enum SpoolMsg {
+        Open {
+            id: i32,
+            file: File,
+        },
+        Line {
+            id: i32,
+            line: String,
+        },
+        Sync {
+            id: i32,
+            done: oneshot::Sender<()>,
+        },
+        Close {
+            id: i32,
+            done: Option<oneshot::Sender<std::io::Result<()>>>,
+        },
+    }
+
+    fn handle(files: &mut HashMap<i32, BufWriter<File>>, msg: SpoolMsg) {
+        match msg {
+            SpoolMsg::Open { id, file } => {
+                files.insert(id, BufWriter::new(file));
+            }
+            SpoolMsg::Line { id, line } => {
+                if let Some(file) = files.get_mut(&id) {
+                    if let Err(e) = writeln!(file, "{}", line) {
+                        tracing::error!("failed to spool log {}: {}", id, e);
+                    }
+                }
+            }
+            SpoolMsg::Sync { id, done } => {
+                if let Some(file) = files.get_mut(&id) {
+                    if let Err(e) = file.flush() {
+                        tracing::error!("failed to spool log {}: {}", id, e);
+                    }
+                }
+                let _ = done.send(());
+            }
+            SpoolMsg::Close { id, done } => {
+                let res = files.remove(&id).map_or(Ok(()), |mut file| file.flush());
+                match (done, res) {
+                    (Some(done), res) => {
+                        let _ = done.send(res);
+                    }
+                    (None, Err(e)) => tracing::error!("failed to spool log {}: {}", id, e),
+                    (None, Ok(())) => (),
+                }
+            }
+        }
+    }
+
+    /// Writes the spools of all running tasks from a dedicated thread, so that
+    /// writing lines does not block the runtime. Lines are flushed once no
+    /// more are waiting.
+    static SPOOLS: Lazy<std::sync::mpsc::Sender<SpoolMsg>> = Lazy::new(|| {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut files = HashMap::new();
+            while let Ok(msg) = receiver.recv() {
+                handle(&mut files, msg);
+                while let Ok(msg) = receiver.try_recv() {
+                    handle(&mut files, msg);
+                }
+                for (id, file) in files.iter_mut() {
+                    if let Err(e) = file.flush() {
+                        tracing::error!("failed to spool log {}: {}", id, e);
+                    }
+                }
+            }
+        });
+        sender
+    });
+
+    fn send(msg: SpoolMsg) {
+        let _ = SPOOLS.send(msg);
+    }
+
+    /// The uncompressed log of a running task. Lines are written to the file
+    /// as they come, so that the log survives a crash of the server.
+    #[derive(Debug)]
+    pub struct Spool {
+        id: i32,
+        closed: bool,
+    }
+
+    impl Spool {
+        pub fn create(id: i32) -> std::io::Result<Self> {
+            let full_path = full_path(&spool_path(id));
+            if let Some(parent) = full_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let file = File::create(full_path)?;
+            send(SpoolMsg::Open { id, file });
+            Ok(Self { id, closed: false })
+        }
+
+        /// Queues a line, it is written in the background
+        pub fn write_line(&mut self, line: String) {
+            send(SpoolMsg::Line { id: self.id, line });
+        }
+
+        /// Completes once the lines queued so far are in the file
+        pub fn sync(&self) -> oneshot::Receiver<()> {
+            let (done, receiver) = oneshot::channel();
+            send(SpoolMsg::Sync { id: self.id, done });
+            receiver
+        }
+
+        /// Opens the spool for reading, see `read_spool`
+        pub fn open(&self) -> std::io::Result<File> {
+            File::open(full_path(&spool_path(self.id)))
+        }
+
+        /// Compresses the spool into the file of the log, returns its path
+        /// and the size of the log. Blocks until the queued lines are written.
+        pub fn finish(mut self) -> std::io::Result<(String, u64)> {
+            let (done, receiver) = oneshot::channel();
+            send(SpoolMsg::Close {
+                id: self.id,
+                done: Some(done),
+            });
+            self.closed = true;
+            receiver.blocking_recv().unwrap_or(Ok(()))?;
+            finish_spool(self.id, None)
+        }
+    }
+
+    impl Drop for Spool {
+        fn drop(&mut self) {
+            if !self.closed {
+                send(SpoolMsg::Close {
+                    id: self.id,
+                    done: None,
+                });
+            }
+        }
+    }
+
+    pub fn read_spool(file: File) -> impl Iterator<Item = String> {
+        BufReader::new(file)
+            .split(b'\n')
+            .map_while(Result::ok)
+            .map(|line| String::from_utf8_lossy(&line).into_owned())
+    }
+
+    fn finish_spool(id: i32, last_line: Option<&str>) -> std::io::Result<(String, u64)> {
+        let spool = full_path(&spool_path(id));
+        let mut writer = Writer::create(id)?;
+        for line in read_spool(File::open(&spool)?) {
+            writer.write_line(&line)?;
+        }
+        if let Some(line) = last_line {
+            writer.write_line(line)?;
+        }
+        let res = writer.finish()?;
+        std::fs::remove_file(spool)?;
+        Ok(res)
+    }
+
+    /// Completes the log of a task that was interrupted, from its spool, and
+    /// appends `line`. Returns the path and the size of the log.
+    pub fn recover(id: i32, line: &str) -> std::io::Result<(String, u64)> {
+        if full_path(&spool_path(id)).exists() {
+            return finish_spool(id, Some(line));
+        }
+        // tasks interrupted before an upgrade wrote compressed files directly
+        let lines: Vec<String> = match read_lines(&full_path(&path(id))) {
+            Ok(lines) => lines.collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut writer = Writer::create(id)?;
+        for line in lines.iter().map(String::as_str).chain([line]) {
+            writer.write_line(line)?;
+        }
+        writer.finish()
+    }
+
+    pub fn remove(path: &str) {
+        let full_path = full_path(path);
+        if let Err(e) = std::fs::remove_file(&full_path) {
+            tracing::warn!("failed to remove log {}: {}", path, e);
+        }
+        match std::fs::remove_file(index_path(&full_path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!("failed to remove the index of log {}: {}", path, e)
+            }
+            _ => (),
+        }
+    }
+
+    fn decode(reader: impl Read) -> std::io::Result<impl Iterator<Item = String>> {
+        let decoder = zstd::stream::read::Decoder::new(reader)?;
+        Ok(BufReader::new(decoder)
+            .split(b'\n')
+            .map_while(Result::ok)
+            .map(|line| String::from_utf8_lossy(&line).into_owned()))
+    }
+
+    fn read_lines(path: &Path) -> std::io::Result<impl Iterator<Item = String>> {
+        decode(File::open(path)?)
+    }
+
+    /// The lines of a log in `range`, decoded from the frame containing the
+    /// first of them
+    fn read_range(
+        path: &Path,
+        range: LogRange,
+    ) -> std::io::Result<Box<dyn Iterator<Item = String>>> {
+        let mut file = File::open(path)?;
+        let seek = Index::read(&index_path(path))?.and_then(|index| index.seek(range));
+        let range = match seek {
+            Some((frame, range)) => {
+                file.seek(SeekFrom::Start(frame.offset))?;
+                range
+            }
+            None => range,
+        };
+        Ok(super::range::select(decode(file)?, range))
+    }
+
+    /// The number of lines of a stored log
+    pub fn lines(path: &str) -> std::io::Result<u64> {
+        let path = full_path(path);
+        match Index::read(&index_path(&path))? {
+            Some(index) => Ok(index.lines),
+            None => Ok(read_lines(&path)?.count() as u64),
+        }
+    }
+
+    /// Reads a stored log in a blocking thread
+    pub fn read(path: &str, range: LogRange) -> impl Stream<Item = String> {
+        let path = full_path(path);
+        let (sender, mut receiver) = mpsc::channel(1024);
+        crate::RUNTIME.spawn_blocking(move || {
+            let lines = match read_range(&path, range) {
+                Ok(lines) => lines,
+                Err(e) => {
+                    tracing::error!("failed to read log {}: {}", path.display(), e);
+                    return;
+                }
+            };
+            for line in lines {
+                if sender.blocking_send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        async_stream::stream! {
+            while let Some(line) = receiver.recv().await {
+                yield line;
+            }
+        }
+    }
+
+    /// Moves the logs stored in the database by previous versions of Typhon
+    /// to files
+    pub fn migrate(conn: &mut Conn) -> Result<(), Error> {
+        // logs that fail to move are left in the database
+        let mut last = 0;
+        loop {
+            let logs = schema::logs::table
+                .filter(schema::logs::stderr.is_not_null())
+                .filter(schema::logs::id.gt(last))
+                .order(schema::logs::id)
+                .select((schema::logs::id, schema::logs::stderr))
+                .limit(100)
+                .load::<(i32, Option<String>)>(conn)?;
+            if logs.is_empty() {
+                return Ok(());
+            }
+            for (id, stderr) in logs {
+                last = id;
+                let res = Writer::create(id).and_then(|mut writer| {
+                    for line in stderr.unwrap_or_default().split('\n') {
+                        writer.write_line(line)?;
+                    }
+                    writer.finish()
+                });
+                let (path, size) = match res {
+                    Ok(res) => res,
+                    Err(e) => {
+                        tracing::error!("failed to move log {} to a file: {}", id, e);
+                        continue;
+                    }
+                };
+                diesel::update(schema::logs::table.find(id))
+                    .set((
+                        schema::logs::path.eq(path),
+                        schema::logs::size.eq(size as i64),
+                        schema::logs::stderr.eq(None::<String>),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn write(name: &str, lines: u64) -> PathBuf {
+            let full_path = std::env::temp_dir()
+                .join(format!("typhon-logs-{}", std::process::id()))
+                .join(name);
+            let mut writer = Writer::create_at(full_path.clone(), name.to_string()).unwrap();
+            for i in 0..lines {
+                writer.write_line(&format!("line {}", i)).unwrap();
+            }
+            writer.finish().unwrap();
+            full_path
+        }
+
+        fn lines(range: std::ops::Range<u64>) -> Vec<String> {
+            range.map(|i| format!("line {}", i)).collect()
+        }
+
+        fn read(path: &Path, range: LogRange) -> Vec<String> {
+            read_range(path, range).unwrap().collect()
+        }
+
+        #[test]
+        fn frames() {
+            let path = write("frames.zst", 3000);
+            let index = Index::read(&index_path(&path)).unwrap().unwrap();
+            assert_eq!(index.lines, 3000);
+            let starts: Vec<u64> = index.frames.iter().map(|frame| frame.line).collect();
+            assert_eq!(starts, vec![0, 1024, 2048]);
+            // "line 0" to "line 9" take 7 bytes with their newline
+            assert_eq!(index.frames[1].byte, 7 * 10 + 8 * 90 + 9 * 900 + 10 * 24);
+            assert_eq!(
+                read_lines(&path).unwrap().collect::<Vec<_>>(),
+                lines(0..3000)
+            );
+        }
+
+        #[test]
+        fn seek() {
+            let path = write("seek.zst", 3000);
+            let range = LogRange::Lines {
+                start: 2040,
+                end: Some(2050),
+            };
+            assert_eq!(read(&path, range), lines(2040..2050));
+            let range = LogRange::Lines {
+                start: 2990,
+                end: None,
+            };
+            assert_eq!(read(&path, range), lines(2990..3000));
+            assert_eq!(read(&path, LogRange::Tail(5)), lines(2995..3000));
+            assert_eq!(read(&path, LogRange::Tail(5000)), lines(0..3000));
+            let index = Index::read(&index_path(&path)).unwrap().unwrap();
+            let start = index.frames[2].byte;
+            let range = LogRange::Bytes {
+                start,
+                end: Some(start + 20),
+            };
+            assert_eq!(read(&path, range), lines(2048..2050));
+        }
+
+        #[test]
+        fn without_index() {
+            let path = write("without_index.zst", 2000);
+            std::fs::remove_file(index_path(&path)).unwrap();
+            let range = LogRange::Lines {
+                start: 1500,
+                end: Some(1502),
+            };
+            assert_eq!(read(&path, range), lines(1500..1502));
+            assert_eq!(read(&path, LogRange::Tail(1)), lines(1999..2000));
+        }
+
+        #[test]
+        fn empty() {
+            let path = write("empty.zst", 0);
+            assert_eq!(read(&path, LogRange::All), Vec::<String>::new());
+            assert_eq!(read(&path, LogRange::Tail(10)), Vec::<String>::new());
+        }
+    }
 }
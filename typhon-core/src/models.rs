@@ -1,12 +1,16 @@
 use crate::schema::actions;
 use crate::schema::builds;
 use crate::schema::evaluations;
+use crate::schema::events;
 use crate::schema::jobs;
 use crate::schema::jobsets;
 use crate::schema::logs;
 use crate::schema::projects;
+use crate::schema::roles;
 use crate::schema::runs;
 use crate::schema::tasks;
+use crate::schema::tokens;
+use crate::schema::users;
 
 use diesel::prelude::*;
 
@@ -19,8 +23,11 @@ pub struct Project {
     pub homepage: String,
     pub id: i32,
     pub key: String,
+    pub last_refresh_build_id: Option<i32>,
     pub last_refresh_task_id: Option<i32>,
     pub name: String,
+    pub reporters: String,
+    pub sandbox: Option<String>,
     pub title: String,
     pub url: String,
     pub url_locked: String,
@@ -43,6 +50,9 @@ pub struct Jobset {
     pub id: i32,
     pub name: String,
     pub project_id: i32,
+    /// The retry policy of the jobs, as JSON
+    pub retry: Option<String>,
+    pub schedule: Option<String>,
     pub url: String,
 }
 
@@ -52,6 +62,8 @@ pub struct NewJobset<'a> {
     pub flake: bool,
     pub name: &'a str,
     pub project_id: i32,
+    pub retry: Option<&'a str>,
+    pub schedule: Option<&'a str>,
     pub url: &'a str,
 }
 
@@ -65,6 +77,8 @@ pub struct Evaluation {
     pub id: i32,
     pub jobset_name: String,
     pub project_id: i32,
+    /// The retry policy of the jobset when the evaluation was created
+    pub retry: Option<String>,
     pub task_id: i32,
     pub time_created: i64,
     pub url: String,
@@ -78,23 +92,48 @@ pub struct NewEvaluation<'a> {
     pub flake: bool,
     pub jobset_name: &'a str,
     pub project_id: i32,
+    pub retry: Option<&'a str>,
     pub task_id: i32,
     pub time_created: i64,
     pub url: &'a str,
     pub uuid: &'a str,
 }
 
+#[derive(Debug, Queryable, Clone, Identifiable, Selectable)]
+#[diesel(table_name = events)]
+pub struct Event {
+    pub data: String,
+    pub id: i64,
+    pub time_created: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = events)]
+pub struct NewEvent<'a> {
+    pub data: &'a str,
+    pub time_created: i64,
+}
+
 #[derive(Debug, Queryable, Clone, Identifiable, Selectable)]
 #[diesel(table_name = jobs)]
 #[diesel(belongs_to(Evaluation))]
 pub struct Job {
     pub dist: bool,
     pub drv: String,
+    /// The evaluation error of the job, in which case `drv` and `out` are
+    /// empty and the job has no runs
+    pub error: Option<String>,
     pub evaluation_id: i32,
     pub id: i32,
     pub name: String,
     pub out: String,
+    /// The retry policy of the job, as JSON
+    pub retry: Option<String>,
+    /// When the pending retry of the job is due, as a Unix timestamp
+    pub retry_at: Option<i64>,
     pub system: String,
+    /// The limits of the build of the job, as JSON
+    pub timeout: Option<String>,
     pub tries: i32,
 }
 
@@ -103,10 +142,13 @@ pub struct Job {
 pub struct NewJob<'a> {
     pub dist: bool,
     pub drv: &'a str,
+    pub error: Option<&'a str>,
     pub evaluation_id: i32,
     pub name: &'a str,
     pub out: &'a str,
+    pub retry: Option<&'a str>,
     pub system: &'a str,
+    pub timeout: Option<&'a str>,
     pub tries: i32,
 }
 
@@ -114,6 +156,11 @@ pub struct NewJob<'a> {
 #[diesel(table_name = logs)]
 pub struct Log {
     pub id: i32,
+    /// The compressed log, relative to the state directory
+    pub path: Option<String>,
+    /// The size of the uncompressed log in bytes
+    pub size: Option<i64>,
+    /// Logs from before they were stored in files
     pub stderr: Option<String>,
 }
 
@@ -166,9 +213,12 @@ pub struct NewBuild<'a> {
 #[diesel(belongs_to(Project))]
 #[diesel(belongs_to(Task))]
 pub struct Action {
+    pub exit_code: Option<i32>,
     pub id: i32,
     pub input: String,
     pub name: String,
+    pub output: Option<String>,
+    pub output_error: Option<String>,
     pub path: String,
     pub project_id: i32,
     pub task_id: i32,
@@ -199,13 +249,77 @@ pub struct Run {
     pub id: i32,
     pub job_id: i32,
     pub num: i32,
+    /// Why the run was created automatically after a failure
+    pub retry_reason: Option<String>,
     pub time_created: i64,
 }
 
 #[derive(Debug, Insertable)]
 #[diesel(table_name = runs)]
-pub struct NewRun {
+pub struct NewRun<'a> {
     pub job_id: i32,
     pub num: i32,
+    pub retry_reason: Option<&'a str>,
     pub time_created: i64,
 }
+
+#[derive(Debug, Queryable, Clone, Identifiable, Selectable)]
+#[diesel(table_name = users)]
+pub struct User {
+    pub admin: bool,
+    pub id: i32,
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = users)]
+pub struct NewUser<'a> {
+    pub admin: bool,
+    pub name: &'a str,
+    pub password: &'a str,
+}
+
+#[derive(Debug, Queryable, Clone, Identifiable, Selectable)]
+#[diesel(table_name = roles)]
+#[diesel(belongs_to(Project))]
+#[diesel(belongs_to(User))]
+pub struct Role {
+    pub id: i32,
+    pub project_id: i32,
+    pub role: i32,
+    pub user_id: i32,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = roles)]
+pub struct NewRole {
+    pub project_id: i32,
+    pub role: i32,
+    pub user_id: i32,
+}
+
+#[derive(Debug, Queryable, Clone, Identifiable, Selectable)]
+#[diesel(table_name = tokens)]
+#[diesel(belongs_to(Project))]
+#[diesel(belongs_to(User))]
+pub struct Token {
+    pub expiration: Option<i64>,
+    pub hash: String,
+    pub id: i32,
+    pub name: String,
+    pub project_id: Option<i32>,
+    pub role: Option<i32>,
+    pub user_id: i32,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = tokens)]
+pub struct NewToken<'a> {
+    pub expiration: Option<i64>,
+    pub hash: &'a str,
+    pub name: &'a str,
+    pub project_id: Option<i32>,
+    pub role: Option<i32>,
+    pub user_id: i32,
+}
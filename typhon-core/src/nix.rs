@@ -8,6 +8,10 @@ use tokio::sync::mpsc;
 
 use std::{collections::HashMap, ffi::OsStr, process::Stdio};
 
+use crate::timeouts::{ProcessGroup, Timeout};
+
+use typhon_types::data;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     Flake {
@@ -100,38 +104,51 @@ impl CommandExtTrait for Command {
     }
 }
 
+/// Keeps the lines of Nix's internal JSON log that concern the build of a
+/// given derivation, and formats them for Typhon's logs.
+pub struct LogFilter {
+    path: DrvPath,
+    drv_id: Option<messages::Id>,
+}
+
+impl LogFilter {
+    pub fn new(path: &DrvPath) -> Self {
+        Self {
+            path: path.clone(),
+            drv_id: None,
+        }
+    }
+
+    pub fn filter(&mut self, line: String) -> Option<String> {
+        use messages::*;
+        let Message { id, body } = parse(line)?;
+        match body {
+            MessageBody::Start { drv } => {
+                if self.path == DrvPath::new(&drv) {
+                    self.drv_id = Some(id);
+                }
+                None
+            }
+            MessageBody::Phase { phase } if self.drv_id == Some(id) => Some(format!(
+                "@nix {} \"action\": \"setPhase\", \"phase\": \"{}\" {}",
+                "{", phase, "}"
+            )),
+            MessageBody::BuildLogLine { line } if self.drv_id == Some(id) => Some(line),
+            _ => None,
+        }
+    }
+}
+
 async fn handle_logs(
     path: &DrvPath,
     buffer: BufReader<tokio::process::ChildStderr>,
     sender: mpsc::UnboundedSender<String>,
 ) {
     let mut lines = buffer.lines();
-    use messages::*;
-    let mut drv_id: Option<Id> = None;
+    let mut filter = LogFilter::new(path);
     while let Some(line) = lines.next_line().await.unwrap() {
-        if let Some(Message { id, body }) = parse(line) {
-            match body {
-                MessageBody::Start { drv } => {
-                    if *path == DrvPath::new(&drv) {
-                        drv_id = Some(id);
-                    }
-                }
-                MessageBody::Phase { phase } => {
-                    if drv_id == Some(id) {
-                        let line = format!(
-                            "@nix {} \"action\": \"setPhase\", \"phase\": \"{}\" {}",
-                            "{", phase, "}"
-                        );
-                        let _ = sender.send(line);
-                    }
-                }
-                MessageBody::BuildLogLine { line } => {
-                    if drv_id == Some(id) {
-                        let _ = sender.send(line);
-                    }
-                }
-                _ => (),
-            }
+        if let Some(line) = filter.filter(line) {
+            let _ = sender.send(line);
         }
     }
 }
@@ -141,18 +158,19 @@ pub async fn build(
     path: &DrvPath,
     sender: mpsc::UnboundedSender<String>,
 ) -> Result<DrvOutputs, Error> {
-    let mut child = Command::nix([
-        "build",
-        "--log-format",
-        "internal-json",
-        "--json",
-        "--no-link",
-    ])
-    .arg(format!("{}^*", path))
-    .stdin(Stdio::inherit())
-    .stdout(Stdio::piped())
-    .stderr(Stdio::piped())
-    .spawn()
+    let (mut child, group) = ProcessGroup::spawn(
+        Command::nix([
+            "build",
+            "--log-format",
+            "internal-json",
+            "--json",
+            "--no-link",
+        ])
+        .arg(format!("{}^*", path))
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped()),
+    )
     .expect(RUNNING_NIX_FAILED);
     handle_logs(path, BufReader::new(child.stderr.take().unwrap()), sender).await;
     let mut stdout = String::new();
@@ -168,6 +186,7 @@ pub async fn build(
         .await
         .map(|status| status.success())
         .unwrap_or(false);
+    group.release();
     if success {
         if let [obj] = serde_json::from_str::<Value>(stdout.as_str())
             .unwrap()
@@ -335,35 +354,191 @@ pub async fn eval(url: &str, path: &str, flake: bool) -> Result<serde_json::Valu
     )?)
 }
 
-pub type NewJobs = HashMap<(String, String), (Derivation, bool)>;
-
-pub async fn eval_jobs(url: &str, flake: bool) -> Result<NewJobs, Error> {
-    let json = eval(url, "typhonJobs", flake).await?;
-    let mut jobs: HashMap<(String, String), (Derivation, bool)> = HashMap::new();
-    for system in json.as_object().unwrap().keys() {
-        for name in json[system].as_object().unwrap().keys() {
-            jobs.insert(
-                (system.clone(), name.clone()),
-                (
-                    derivation(Expr::Flake {
-                        flake,
-                        url: url.to_string(),
-                        path: format!("typhonJobs.{system}.{name}"),
-                    })
-                    .await?,
-                    eval(
-                        url,
-                        &format!("typhonJobs.{system}.{name}.passthru.typhonDist"),
-                        flake,
-                    )
-                    .await
-                    .map(|json| json.as_bool().unwrap_or(false))
-                    .unwrap_or(false),
-                ),
-            );
+/// Returns the revision a locked flake URL points to, if any.
+pub async fn flake_rev(url: &str) -> Result<Option<String>, Error> {
+    // a JSON string is a valid Nix string once interpolations are escaped
+    let url = serde_json::to_string(url)?.replace("${", "\\${");
+    let json: Value = serde_json::from_str(
+        &Command::nix([
+            "eval",
+            "--json",
+            "--expr",
+            &format!("builtins.parseFlakeRef {}", url),
+        ])
+        .sync_stdout()
+        .await?,
+    )?;
+    Ok(json["rev"].as_str().map(String::from))
+}
+
+/// Settings of `nix-eval-jobs`, which evaluates the jobs of a jobset
+#[derive(Clone, Debug)]
+pub struct Evaluator {
+    /// Number of parallel evaluation workers
+    pub workers: usize,
+    /// Memory limit of a worker in MiB, after which it is restarted
+    pub max_memory_size: Option<usize>,
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            max_memory_size: None,
         }
     }
-    Ok(jobs)
+}
+
+/// A job as it comes out of `nix-eval-jobs`, either a derivation and whether
+/// it is a distribution or the evaluation error
+#[derive(Clone, Debug)]
+pub struct NewJob {
+    pub system: String,
+    pub name: String,
+    pub result: Result<(Derivation, bool), String>,
+    /// The retry policy set by `passthru.typhonRetry`
+    pub retry: Option<data::RetryPolicy>,
+    /// The limits of the build set by `passthru.typhonTimeout`
+    pub timeout: Option<Timeout>,
+}
+
+impl NewJob {
+    fn parse(line: &str) -> Result<Self, Error> {
+        let unexpected = || Error::UnexpectedOutput {
+            context: format!("While parsing the output of nix-eval-jobs: {}", line),
+        };
+        let json: Value = serde_json::from_str(line)?;
+        let (system, name) = match json["attrPath"].as_array().map(Vec::as_slice) {
+            Some([system, name]) => (system.as_str(), name.as_str()),
+            _ => json["attr"]
+                .as_str()
+                .and_then(|attr| attr.split_once('.'))
+                .map_or((None, None), |(system, name)| (Some(system), Some(name))),
+        };
+        let (system, name) = (
+            system.ok_or_else(unexpected)?.to_string(),
+            name.ok_or_else(unexpected)?.to_string(),
+        );
+        if let Some(error) = json["error"].as_str() {
+            return Ok(Self {
+                system,
+                name,
+                result: Err(error.to_string()),
+                retry: None,
+                timeout: None,
+            });
+        }
+        let derivation = Derivation {
+            path: DrvPath::new(json["drvPath"].as_str().ok_or_else(unexpected)?),
+            outputs: json["outputs"]
+                .as_object()
+                .ok_or_else(unexpected)?
+                .iter()
+                .filter_map(|(name, path)| Some((name.clone(), path.as_str()?.to_string())))
+                .collect(),
+        };
+        let dist = json["meta"]["typhonDist"].as_bool().unwrap_or(false);
+        let retry = serde_json::from_value(json["meta"]["typhonRetry"].clone()).ok();
+        let timeout = serde_json::from_value(json["meta"]["typhonTimeout"].clone()).ok();
+        Ok(Self {
+            system,
+            name,
+            result: Ok((derivation, dist)),
+            retry,
+            timeout,
+        })
+    }
+}
+
+/// The Nix expression of the jobs of a locked URL. `passthru.typhonDist`,
+/// `passthru.typhonRetry` and `passthru.typhonTimeout` are copied into `meta`,
+/// which `nix-eval-jobs` outputs.
+fn jobs_expr(url: &str, flake: bool) -> Result<String, Error> {
+    // a JSON string is a valid Nix string once interpolations are escaped
+    let url = serde_json::to_string(url)?.replace("${", "\\${");
+    let jobs = if flake {
+        format!("(builtins.getFlake {url}).typhonJobs")
+    } else {
+        format!(
+            "let src = builtins.fetchTree {url}; in \
+             if builtins.pathExists \"${{src}}/nix/jobs.nix\" \
+             then import \"${{src}}/nix/jobs.nix\" {{}} \
+             else {{}}"
+        )
+    };
+    Ok(format!(
+        "let \
+           withMeta = job: job // {{ \
+             meta = (job.meta or {{}}) // {{ \
+               typhonDist = job.passthru.typhonDist or false; \
+               typhonRetry = job.passthru.typhonRetry or null; \
+               typhonTimeout = job.passthru.typhonTimeout or null; \
+             }}; \
+           }}; \
+         in builtins.mapAttrs \
+           (system: jobs: builtins.mapAttrs (name: withMeta) jobs // {{ recurseForDerivations = true; }}) \
+           ({jobs})"
+    ))
+}
+
+/// Evaluates the jobs of a locked URL with `nix-eval-jobs`, sending each job
+/// to `jobs` as soon as it is evaluated. The standard error goes to `sender`.
+pub async fn eval_jobs(
+    url: &str,
+    flake: bool,
+    evaluator: &Evaluator,
+    jobs: mpsc::UnboundedSender<NewJob>,
+    sender: mpsc::UnboundedSender<String>,
+) -> Result<(), Error> {
+    let mut cmd = Command::new("nix-eval-jobs");
+    cmd.kill_on_drop(true)
+        .args(["--meta", "--expr", &jobs_expr(url, flake)?])
+        .args(["--workers", &evaluator.workers.to_string()]);
+    if let Some(max_memory_size) = evaluator.max_memory_size {
+        cmd.args(["--max-memory-size", &max_memory_size.to_string()]);
+    }
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("command nix-eval-jobs failed to run");
+
+    let (stdout, stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+    let stderr = async {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let _ = sender.send(line);
+        }
+    };
+    let stdout = async {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match NewJob::parse(&line) {
+                Ok(job) => {
+                    let _ = jobs.send(job);
+                }
+                Err(e) => {
+                    let _ = sender.send(e.to_string());
+                }
+            }
+        }
+    };
+    tokio::join!(stderr, stdout);
+
+    let status = child
+        .wait()
+        .await
+        .expect("command nix-eval-jobs failed to run");
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::NixCommand {
+            cmd: format!("{:?}", cmd),
+            stdout: String::new(),
+            stderr: format!("nix-eval-jobs exited with {}", status),
+        })
+    }
 }
 
 pub fn current_system() -> String {
@@ -434,13 +609,11 @@ pub fn lock(url: &String) -> Result<String, Error> {
     Ok(stdout)
 }
 
-pub fn dependencies(drv: &String) -> Result<Vec<String>, Error> {
+fn derivation_show(drvs: &[&str]) -> Result<serde_json::Value, Error> {
     use std::process::Command;
 
-    let mut dependencies: Vec<String> = Vec::new();
-
     let mut cmd = Command::new("nix");
-    cmd.args(["derivation", "show", &drv]);
+    cmd.args(["derivation", "show"]).args(drvs);
     let output = cmd.output().unwrap();
     let stdout = String::from_utf8(output.stdout)?;
     let stderr = String::from_utf8(output.stderr)?;
@@ -452,37 +625,50 @@ pub fn dependencies(drv: &String) -> Result<Vec<String>, Error> {
         });
     }
 
-    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    Ok(serde_json::from_str(&stdout).unwrap())
+}
 
-    let input_drvs = json[&drv]["inputDrvs"].as_object().unwrap();
-    for (drv, json) in input_drvs {
-        let outputs = json["outputs"].as_array().unwrap();
-        let mut cmd = Command::new("nix");
-        cmd.args(["derivation", "show", &drv]);
-        let output = cmd.output().unwrap();
-        let stdout = String::from_utf8(output.stdout)?;
-        let stderr = String::from_utf8(output.stderr)?;
-        if !output.status.success() {
-            return Err(Error::NixCommand {
-                cmd: format!("{:?}", cmd),
-                stdout,
-                stderr,
-            });
-        }
-        let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
-        for output in outputs {
-            dependencies.push(
-                json[&drv]["outputs"][output.as_str().unwrap()]["path"]
-                    .as_str()
-                    .unwrap()
-                    .to_string(),
-            )
+/// The outputs of the input derivations and the input sources of `drvs`. Each
+/// batch of derivations is shown with a single call to `nix`.
+pub fn dependencies(drvs: &[String]) -> Result<Vec<String>, Error> {
+    // keep the command line short enough
+    const BATCH: usize = 500;
+
+    let mut dependencies: Vec<String> = Vec::new();
+    let mut input_drvs: HashMap<String, Vec<String>> = HashMap::new();
+
+    for drvs in drvs.chunks(BATCH) {
+        let drvs: Vec<&str> = drvs.iter().map(String::as_str).collect();
+        let json = derivation_show(&drvs)?;
+        for drv in drvs {
+            for (input, json) in json[drv]["inputDrvs"].as_object().unwrap() {
+                let outputs = json["outputs"].as_array().unwrap();
+                input_drvs.entry(input.clone()).or_default().extend(
+                    outputs
+                        .iter()
+                        .map(|output| output.as_str().unwrap().to_string()),
+                );
+            }
+            for src in json[drv]["inputSrcs"].as_array().unwrap() {
+                dependencies.push(src.as_str().unwrap().to_string());
+            }
         }
     }
 
-    let input_srcs = json[drv]["inputSrcs"].as_array().unwrap();
-    for src in input_srcs {
-        dependencies.push(src.as_str().unwrap().to_string());
+    let inputs: Vec<&String> = input_drvs.keys().collect();
+    for inputs in inputs.chunks(BATCH) {
+        let inputs: Vec<&str> = inputs.iter().map(|input| input.as_str()).collect();
+        let json = derivation_show(&inputs)?;
+        for input in inputs {
+            for output in &input_drvs[input] {
+                dependencies.push(
+                    json[input]["outputs"][output]["path"]
+                        .as_str()
+                        .unwrap()
+                        .to_string(),
+                );
+            }
+        }
     }
 
     Ok(dependencies)
@@ -1,11 +1,19 @@
 use crate::actions;
+use crate::build_manager::BUILDS;
 use crate::error::Error;
 use crate::gcroots;
 use crate::jobsets;
+use crate::logs;
 use crate::models;
 use crate::nix;
+use crate::queue;
+use crate::reporters;
+use crate::sandbox;
+use crate::scheduler;
 use crate::schema;
 use crate::tasks;
+use crate::timeouts::Timeout;
+use crate::users;
 use crate::Conn;
 use crate::CURRENT_SYSTEM;
 use crate::POOL;
@@ -20,11 +28,21 @@ use age::secrecy::ExposeSecret;
 use diesel::prelude::*;
 use serde::Deserialize;
 use time::OffsetDateTime;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 
 use std::collections::HashMap;
 use std::str::FromStr;
 
+/// What a refresh reads from the project declaration: the locked URL, the
+/// metadata, the path of the actions, the reporters and the sandbox
+type Refreshed = (
+    String,
+    ProjectMetadata,
+    Option<String>,
+    String,
+    Option<String>,
+);
+
 #[derive(Clone)]
 pub struct Project {
     pub refresh_task: Option<tasks::Task>,
@@ -63,9 +81,162 @@ impl Project {
         }
     }
 
-    //pub fn delete(&self, _conn: &mut Conn) -> Result<(), Error> {
-    //    todo!()
-    //}
+    pub fn delete(&self, conn: &mut Conn) -> Result<(), Error> {
+        use crate::{RUNS, RUNTIME, TASKS};
+
+        let pending = i32::from(TaskStatusKind::Pending);
+
+        // cancel everything still running for this project, the database is
+        // only touched once the finishers are done
+        let mut task_ids: Vec<i32> = schema::evaluations::table
+            .inner_join(schema::tasks::table)
+            .filter(schema::evaluations::project_id.eq(self.project.id))
+            .filter(schema::tasks::status.eq(pending))
+            .select(schema::tasks::id)
+            .load(conn)?;
+        task_ids.extend(
+            schema::actions::table
+                .inner_join(schema::tasks::table)
+                .filter(schema::actions::project_id.eq(self.project.id))
+                .filter(schema::tasks::status.eq(pending))
+                .select(schema::tasks::id)
+                .load::<i32>(conn)?,
+        );
+        if let Some(task) = &self.refresh_task {
+            if task.status_kind() == TaskStatusKind::Pending {
+                task_ids.push(task.task.id);
+            }
+        }
+        let run_ids: Vec<i32> = schema::runs::table
+            .inner_join(schema::jobs::table.inner_join(schema::evaluations::table))
+            .filter(schema::evaluations::project_id.eq(self.project.id))
+            .filter(schema::runs::end_id.is_null())
+            .select(schema::runs::id)
+            .load(conn)?;
+        for id in run_ids.iter() {
+            RUNS.cancel(*id);
+        }
+        for id in task_ids.iter() {
+            TASKS.cancel(*id);
+        }
+        // the finishers need connections of their own, so the project is
+        // removed once they are done, without holding the request's connection
+        let self_ = self.clone();
+        RUNTIME.spawn(async move {
+            for id in run_ids.iter() {
+                RUNS.wait(id).await;
+            }
+            for id in task_ids.iter() {
+                TASKS.wait(id).await;
+            }
+            let handle = self_.handle();
+            let res = RUNTIME
+                .spawn_blocking(move || self_.remove(&mut POOL.get().unwrap()))
+                .await;
+            match res {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => tracing::error!("failed to delete project {}: {:?}", handle, e),
+                Err(e) => tracing::error!("deletion of project {} panicked: {:?}", handle, e),
+            }
+        });
+
+        Ok(())
+    }
+
+    fn remove(&self, conn: &mut Conn) -> Result<(), Error> {
+        let uuids: Vec<String> = schema::evaluations::table
+            .filter(schema::evaluations::project_id.eq(self.project.id))
+            .select(schema::evaluations::uuid)
+            .load(conn)?;
+        let paths = conn.transaction::<Vec<String>, Error, _>(|conn| {
+            let evaluations = || {
+                schema::evaluations::table
+                    .filter(schema::evaluations::project_id.eq(self.project.id))
+                    .select(schema::evaluations::id)
+            };
+            let jobs = || {
+                schema::jobs::table
+                    .filter(schema::jobs::evaluation_id.eq_any(evaluations()))
+                    .select(schema::jobs::id)
+            };
+
+            // builds are shared between projects, so they are kept
+            let mut task_ids: Vec<i32> = schema::evaluations::table
+                .filter(schema::evaluations::project_id.eq(self.project.id))
+                .select(schema::evaluations::task_id)
+                .load(conn)?;
+            task_ids.extend(
+                schema::actions::table
+                    .filter(schema::actions::project_id.eq(self.project.id))
+                    .select(schema::actions::task_id)
+                    .load::<i32>(conn)?,
+            );
+            task_ids.extend(self.project.last_refresh_task_id);
+
+            diesel::delete(schema::runs::table.filter(schema::runs::job_id.eq_any(jobs())))
+                .execute(conn)?;
+            diesel::delete(
+                schema::jobs::table.filter(schema::jobs::evaluation_id.eq_any(evaluations())),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                schema::evaluations::table
+                    .filter(schema::evaluations::project_id.eq(self.project.id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                schema::actions::table.filter(schema::actions::project_id.eq(self.project.id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                schema::jobsets::table.filter(schema::jobsets::project_id.eq(self.project.id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                schema::roles::table.filter(schema::roles::project_id.eq(self.project.id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                schema::tokens::table.filter(schema::tokens::project_id.eq(self.project.id)),
+            )
+            .execute(conn)?;
+            diesel::delete(&self.project).execute(conn)?;
+
+            // SQLite limits the number of bound parameters per statement
+            let mut paths = Vec::new();
+            for ids in task_ids.chunks(500) {
+                let log_ids: Vec<i32> = schema::tasks::table
+                    .filter(schema::tasks::id.eq_any(ids))
+                    .select(schema::tasks::log_id)
+                    .load(conn)?;
+                paths.extend(
+                    schema::logs::table
+                        .filter(schema::logs::id.eq_any(&log_ids))
+                        .select(schema::logs::path)
+                        .load::<Option<String>>(conn)?
+                        .into_iter()
+                        .flatten(),
+                );
+                diesel::delete(schema::tasks::table.filter(schema::tasks::id.eq_any(ids)))
+                    .execute(conn)?;
+                diesel::delete(schema::logs::table.filter(schema::logs::id.eq_any(log_ids)))
+                    .execute(conn)?;
+            }
+
+            Ok(paths)
+        })?;
+        for path in paths {
+            logs::files::remove(&path);
+        }
+        for uuid in uuids {
+            gcroots::remove_evaluation(&uuid);
+        }
+        gcroots::set_project(&self.project.name, None);
+
+        log_event(Event::ProjectDeleted(self.handle()));
+
+        Ok(())
+    }
 
     pub fn get(conn: &mut Conn, handle: &handles::Project) -> Result<Self, Error> {
         let (project, task): (models::Project, Option<models::Task>) = schema::projects::table
@@ -95,12 +266,23 @@ impl Project {
             .map_err(|_| Error::Todo)?
             .to_public()
             .to_string();
+        let last_refresh_build = match self.project.last_refresh_build_id {
+            Some(id) => Some(
+                schema::builds::table
+                    .find(id)
+                    .select(schema::builds::uuid)
+                    .first::<String>(conn)?,
+            ),
+            None => None,
+        };
         Ok(responses::ProjectInfo {
             handle: self.handle(),
             actions_path: self.project.actions_path.clone(),
             flake: self.project.flake,
             jobsets: jobsets_names,
             last_refresh: self.refresh_task.clone().map(|task| task.status()),
+            last_refresh_build: last_refresh_build
+                .map(|uuid| handles::build(uuid::Uuid::from_str(&uuid).unwrap())),
             metadata: ProjectMetadata {
                 title: self.project.title.clone(),
                 description: self.project.description.clone(),
@@ -155,37 +337,70 @@ impl Project {
             actions: Option<HashMap<String, String>>,
             #[serde(default)]
             meta: ProjectMetadata,
+            #[serde(default)]
+            reporters: Vec<reporters::ReporterDecl>,
+            #[serde(default)]
+            sandbox: Option<sandbox::Narrowing>,
         }
 
         let run = {
+            let handle = self.handle();
+            let id = self.project.id;
             let url = self.project.url.clone();
             let flake = self.project.flake;
-            move |sender| async move {
+            move |sender: mpsc::UnboundedSender<String>, _| async move {
                 let url_locked = nix::lock(&url)?;
 
-                let TyphonProject { actions, meta } =
-                    serde_json::from_value(nix::eval(&url_locked, &"typhonProject", flake).await?)
-                        .map_err(|_| Error::BadProjectDecl)?;
+                let TyphonProject {
+                    actions,
+                    meta,
+                    reporters,
+                    sandbox,
+                } = serde_json::from_value(nix::eval(&url_locked, "typhonProject", flake).await?)
+                    .map_err(|_| Error::BadProjectDecl)?;
 
                 let actions: Option<&String> =
                     actions.as_ref().map(|m| m.get(&*CURRENT_SYSTEM)).flatten();
 
                 let actions_path = if let Some(x) = actions {
                     let drv = nix::derivation(nix::Expr::Path(x.clone())).await?;
-                    // FIXME: this should spawn a build
-                    Some(nix::build(&drv.path, sender).await?["out"].clone())
+                    let build_handle = BUILDS
+                        .run_async(drv.path.clone(), queue::Priority::High, Timeout::default())
+                        .await;
+                    // the build is linked while it runs, so that its log can be
+                    // followed from the project
+                    let uuid = {
+                        let mut conn = POOL.get().unwrap();
+                        diesel::update(schema::projects::table.find(id))
+                            .set(schema::projects::last_refresh_build_id.eq(build_handle.id))
+                            .execute(&mut conn)?;
+                        schema::builds::table
+                            .find(build_handle.id)
+                            .select(schema::builds::uuid)
+                            .first::<String>(&mut conn)?
+                    };
+                    log_event(Event::ProjectUpdated(handle));
+                    let _ = sender.send(format!("building {} in build {}", drv.path, uuid));
+                    match build_handle.wait().await {
+                        TaskStatusKind::Success => (),
+                        status => return Err(Error::ActionsBuildFailed(status)),
+                    }
+                    Some(drv.outputs["out"].clone())
                     // TODO: check public key used to encrypt secrets
                 } else {
                     None
                 };
 
-                Ok((url_locked, meta, actions_path))
+                let reporters = serde_json::to_string(&reporters).unwrap();
+                let sandbox = sandbox.map(|sandbox| serde_json::to_string(&sandbox).unwrap());
+
+                Ok((url_locked, meta, actions_path, reporters, sandbox))
             }
         };
 
         let finish = {
             let self_ = self.clone();
-            move |res: Option<Result<(String, ProjectMetadata, Option<String>), Error>>| {
+            move |res: Option<Result<Refreshed, Error>>| {
                 let status = match res {
                     Some(Ok(x)) => self_.finish_refresh(x),
                     Some(Err(e)) => {
@@ -208,7 +423,7 @@ impl Project {
 
         log_event(Event::ProjectUpdated(self.handle()));
 
-        task.run(conn, run, finish)?;
+        task.run(conn, None, run, finish)?;
 
         Ok(())
     }
@@ -228,6 +443,47 @@ impl Project {
         Ok(())
     }
 
+    pub fn set_role(
+        &self,
+        conn: &mut Conn,
+        user: &String,
+        role: &Option<data::Role>,
+    ) -> Result<(), Error> {
+        let user = users::User::get(conn, user)?;
+        match role {
+            Some(role) => {
+                let new_role = models::NewRole {
+                    project_id: self.project.id,
+                    role: i32::from(*role),
+                    user_id: user.user.id,
+                };
+                conn.transaction::<(), Error, _>(|conn| {
+                    let updated = diesel::update(schema::roles::table)
+                        .filter(schema::roles::project_id.eq(new_role.project_id))
+                        .filter(schema::roles::user_id.eq(new_role.user_id))
+                        .set(schema::roles::role.eq(new_role.role))
+                        .execute(conn)?;
+                    if updated == 0 {
+                        diesel::insert_into(schema::roles::table)
+                            .values(&new_role)
+                            .execute(conn)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            None => {
+                diesel::delete(
+                    schema::roles::table
+                        .filter(schema::roles::project_id.eq(self.project.id))
+                        .filter(schema::roles::user_id.eq(user.user.id)),
+                )
+                .execute(conn)?;
+            }
+        }
+        log_event(Event::UserUpdated(user.user.name.clone()));
+        Ok(())
+    }
+
     pub fn update_jobsets(&self, conn: &mut Conn) -> Result<(), Error> {
         // run action `jobsets`
         let action = self.new_action(
@@ -266,7 +522,7 @@ impl Project {
             }
         };
 
-        action.spawn(conn, finish)?;
+        action.spawn(conn, queue::Priority::High, finish)?;
 
         Ok(())
     }
@@ -315,26 +571,28 @@ impl Project {
             }
         };
 
-        action.spawn(conn, finish)?;
+        action.spawn(conn, queue::Priority::High, finish)?;
 
         Ok(receiver.blocking_recv().map_err(|_| Error::Todo)?)
     }
 
     fn finish_refresh(
         &self,
-        (url_locked, meta, actions_path): (String, ProjectMetadata, Option<String>),
+        (url_locked, meta, actions_path, reporters, sandbox): Refreshed,
     ) -> Result<TaskStatusKind, Error> {
         let mut conn = POOL.get().unwrap();
         diesel::update(&self.project)
             .set((
-                schema::projects::actions_path.eq(actions_path),
+                schema::projects::actions_path.eq(&actions_path),
                 schema::projects::description.eq(meta.description),
                 schema::projects::homepage.eq(meta.homepage),
+                schema::projects::reporters.eq(reporters),
+                schema::projects::sandbox.eq(sandbox),
                 schema::projects::title.eq(meta.title),
                 schema::projects::url_locked.eq(url_locked),
             ))
             .execute(&mut conn)?;
-        gcroots::update(&mut conn);
+        gcroots::set_project(&self.project.name, actions_path.as_deref());
         Ok(TaskStatusKind::Success)
     }
 
@@ -342,6 +600,14 @@ impl Project {
         &self,
         decls: HashMap<String, jobsets::JobsetDecl>,
     ) -> Result<TaskStatusKind, Error> {
+        for decl in decls.values() {
+            if let Some(data::Schedule::Cron(expr)) = &decl.schedule {
+                scheduler::Cron::parse(expr).map_err(|e| {
+                    Error::BadJobsetDecl(format!("invalid cron expression {}: {}", expr, e))
+                })?;
+            }
+        }
+
         let mut conn = POOL.get().unwrap();
         let mut current_jobsets: Vec<jobsets::Jobset> = schema::jobsets::table
             .filter(schema::jobsets::project_id.eq(&self.project.id))
@@ -369,10 +635,20 @@ impl Project {
         // create new jobsets
         for (name, decl) in decls.iter() {
             if !set.contains(name) {
+                let schedule = decl
+                    .schedule
+                    .as_ref()
+                    .map(|schedule| serde_json::to_string(schedule).unwrap());
+                let retry = decl
+                    .retry
+                    .as_ref()
+                    .map(|retry| serde_json::to_string(retry).unwrap());
                 let new_jobset = models::NewJobset {
                     flake: decl.flake,
                     name,
                     project_id: self.project.id,
+                    retry: retry.as_deref(),
+                    schedule: schedule.as_deref(),
                     url: &decl.url,
                 };
                 diesel::insert_into(schema::jobsets::table)
@@ -381,8 +657,6 @@ impl Project {
             }
         }
 
-        gcroots::update(&mut conn);
-
         Ok(TaskStatusKind::Success)
     }
 }
@@ -0,0 +1,187 @@
+use crate::Settings;
+use crate::QUEUE;
+
+use typhon_types::data;
+
+use tokio::sync::oneshot;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The kind of a queued task, each kind has its own limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Action,
+    Build,
+}
+
+/// Tasks with a higher priority are given slots first. Among tasks of the same
+/// priority, the oldest goes first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Normal,
+    High,
+}
+
+/// Limits on the number of concurrent tasks, `None` means unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    pub actions: Option<usize>,
+    pub builds: Option<usize>,
+    /// Per-system limits on builds, on top of the global one
+    pub systems: HashMap<String, usize>,
+}
+
+struct Waiter {
+    kind: Kind,
+    priority: Priority,
+    system: Option<String>,
+    sender: oneshot::Sender<Slot>,
+}
+
+#[derive(Default)]
+struct State {
+    actions: usize,
+    builds: usize,
+    systems: HashMap<String, usize>,
+    waiters: Vec<Waiter>,
+}
+
+/// A running slot in the queue, released when dropped.
+pub struct Slot {
+    kind: Kind,
+    system: Option<String>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        QUEUE.release(self.kind, &self.system);
+    }
+}
+
+impl State {
+    fn take(&mut self, kind: Kind, system: &Option<String>) {
+        match kind {
+            Kind::Action => self.actions += 1,
+            Kind::Build => self.builds += 1,
+        }
+        if let Some(system) = system {
+            *self.systems.entry(system.clone()).or_default() += 1;
+        }
+    }
+
+    fn free(&mut self, kind: Kind, system: &Option<String>) {
+        match kind {
+            Kind::Action => self.actions -= 1,
+            Kind::Build => self.builds -= 1,
+        }
+        if let Some(system) = system {
+            if let Some(n) = self.systems.get_mut(system) {
+                *n -= 1;
+            }
+        }
+    }
+
+    fn available(&self, limits: &Limits, kind: Kind, system: &Option<String>) -> bool {
+        match kind {
+            Kind::Action => limits.actions.map_or(true, |max| self.actions < max),
+            Kind::Build => {
+                limits.builds.map_or(true, |max| self.builds < max)
+                    && system
+                        .as_ref()
+                        .and_then(|system| Some((limits.systems.get(system)?, system)))
+                        .map_or(true, |(max, system)| {
+                            self.systems.get(system).copied().unwrap_or(0) < *max
+                        })
+            }
+        }
+    }
+
+    /// Gives slots to the waiters that can run, by order of priority.
+    fn dispatch(&mut self, limits: &Limits) {
+        // the waiters are kept sorted by decreasing priority, and by arrival
+        // order within a priority
+        let mut waiters = Vec::new();
+        for waiter in std::mem::take(&mut self.waiters) {
+            if waiter.sender.is_closed() {
+                continue;
+            }
+            if !self.available(limits, waiter.kind, &waiter.system) {
+                waiters.push(waiter);
+                continue;
+            }
+            self.take(waiter.kind, &waiter.system);
+            let slot = Slot {
+                kind: waiter.kind,
+                system: waiter.system,
+            };
+            if let Err(slot) = waiter.sender.send(slot) {
+                // the waiter is gone, release the slot without re-entering
+                // the queue's lock
+                self.free(slot.kind, &slot.system);
+                std::mem::forget(slot);
+            }
+        }
+        self.waiters = waiters;
+    }
+}
+
+pub struct Queue {
+    state: Mutex<State>,
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Waits for a slot to run a task of kind `kind`. Builds should give
+    /// their system so that per-system limits apply.
+    pub async fn acquire(&self, kind: Kind, system: Option<String>, priority: Priority) -> Slot {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            let index = state
+                .waiters
+                .iter()
+                .position(|waiter| waiter.priority < priority)
+                .unwrap_or(state.waiters.len());
+            state.waiters.insert(
+                index,
+                Waiter {
+                    kind,
+                    priority,
+                    system,
+                    sender,
+                },
+            );
+            state.dispatch(&Settings::get().limits);
+        }
+        receiver.await.expect("the queue dropped a waiter")
+    }
+
+    pub fn info(&self) -> data::QueueInfo {
+        let state = self.state.lock().unwrap();
+        let waiting = |kind| {
+            state
+                .waiters
+                .iter()
+                .filter(|waiter| waiter.kind == kind && !waiter.sender.is_closed())
+                .count()
+        };
+        data::QueueInfo {
+            running_actions: state.actions,
+            running_builds: state.builds,
+            waiting_actions: waiting(Kind::Action),
+            waiting_builds: waiting(Kind::Build),
+        }
+    }
+
+    fn release(&self, kind: Kind, system: &Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.free(kind, system);
+        state.dispatch(&Settings::get().limits);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::error::Error;
+use crate::handles;
+use crate::jobs;
+use crate::jobsets;
+use crate::logs;
+use crate::models;
+use crate::runs;
+use crate::schema;
+use crate::Conn;
+use crate::POOL;
+use crate::RUNTIME;
+
+use typhon_types::data::TaskStatusKind;
+
+use diesel::prelude::*;
+use time::OffsetDateTime;
+
+use std::collections::HashSet;
+
+const INTERRUPTED: &str = "typhon: interrupted by a restart";
+
+/// What was running when the previous process stopped
+#[derive(Default)]
+struct Interrupted {
+    evaluations: Vec<models::Evaluation>,
+    runs: Vec<(
+        models::Run,
+        models::Job,
+        models::Evaluation,
+        models::Project,
+    )>,
+}
+
+/// Cancels a task left pending, and completes its log file
+fn cancel(conn: &mut Conn, task: &models::Task, now: i64) -> Result<(), Error> {
+    match logs::files::recover(task.log_id, INTERRUPTED) {
+        Ok((path, size)) => {
+            diesel::update(schema::logs::table.find(task.log_id))
+                .set((
+                    schema::logs::path.eq(path),
+                    schema::logs::size.eq(size as i64),
+                ))
+                .execute(conn)?;
+        }
+        Err(e) => tracing::error!("failed to recover log of task {}: {}", task.id, e),
+    }
+    // a task that was started gets an end time, so that it keeps its duration
+    diesel::update(task)
+        .set((
+            schema::tasks::status.eq(i32::from(TaskStatusKind::Canceled)),
+            schema::tasks::time_finished.eq(task.time_started.map(|_| now)),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn cancel_all(conn: &mut Conn) -> Result<Interrupted, Error> {
+    let tasks = schema::tasks::table
+        .filter(schema::tasks::status.eq(i32::from(TaskStatusKind::Pending)))
+        .load::<models::Task>(conn)?;
+    if tasks.is_empty() {
+        return Ok(Interrupted::default());
+    }
+    tracing::warn!("canceling {} tasks interrupted by a restart", tasks.len());
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    for task in tasks.iter() {
+        cancel(conn, task, now)?;
+    }
+
+    // a run is done once its 'end' action is, runs that have not spawned it
+    // yet were waiting on their 'begin' action or on their build
+    let ids: Vec<i32> = tasks.iter().map(|task| task.id).collect();
+    let mut interrupted = Interrupted::default();
+    let runs = || {
+        schema::runs::table.inner_join(
+            schema::jobs::table
+                .inner_join(schema::evaluations::table.inner_join(schema::projects::table)),
+        )
+    };
+    let select = (
+        schema::runs::all_columns,
+        schema::jobs::all_columns,
+        schema::evaluations::all_columns,
+        schema::projects::all_columns,
+    );
+    // the SQLite backend limits the number of bound parameters
+    for chunk in ids.chunks(500) {
+        let actions = schema::actions::table
+            .filter(schema::actions::task_id.eq_any(chunk))
+            .select(schema::actions::id.nullable());
+        let builds = schema::builds::table
+            .filter(schema::builds::task_id.eq_any(chunk))
+            .select(schema::builds::id.nullable());
+        interrupted.runs.extend(
+            runs()
+                .filter(schema::runs::end_id.is_null())
+                .filter(
+                    schema::runs::begin_id
+                        .eq_any(actions)
+                        .or(schema::runs::build_id.eq_any(builds)),
+                )
+                .select(select)
+                .load::<(
+                    models::Run,
+                    models::Job,
+                    models::Evaluation,
+                    models::Project,
+                )>(conn)?,
+        );
+        interrupted.runs.extend(
+            runs()
+                .inner_join(
+                    schema::actions::table
+                        .on(schema::actions::id.nullable().eq(schema::runs::end_id)),
+                )
+                .filter(schema::actions::task_id.eq_any(chunk))
+                .select(select)
+                .load::<(
+                    models::Run,
+                    models::Job,
+                    models::Evaluation,
+                    models::Project,
+                )>(conn)?,
+        );
+        interrupted.evaluations.extend(
+            schema::evaluations::table
+                .filter(schema::evaluations::task_id.eq_any(chunk))
+                .load::<models::Evaluation>(conn)?,
+        );
+    }
+    Ok(interrupted)
+}
+
+/// Starts the interrupted runs and evaluations again
+fn resume(conn: &mut Conn, interrupted: Interrupted) {
+    // the jobs of an interrupted evaluation are run by the new evaluation
+    let evaluations: HashSet<i32> = interrupted
+        .evaluations
+        .iter()
+        .map(|evaluation| evaluation.id)
+        .collect();
+    for (run, job, evaluation, project) in interrupted.runs {
+        if evaluations.contains(&evaluation.id) {
+            continue;
+        }
+        let job = jobs::Job {
+            job,
+            evaluation,
+            project,
+        };
+        let reason = format!("run {} was interrupted by a restart", run.num);
+        // the job is left alone if it was rerun since
+        if let Err(e) = job.retry(conn, run.num, &reason) {
+            tracing::error!("failed to resume run {}: {:?}", run.id, e);
+        }
+    }
+    for evaluation in interrupted.evaluations {
+        let project = schema::projects::table
+            .find(evaluation.project_id)
+            .select(schema::projects::name)
+            .first::<String>(conn);
+        let res = project.map_err(Error::from).and_then(|project| {
+            let handle = handles::jobset((project, evaluation.jobset_name.clone()));
+            let jobset = jobsets::Jobset::get(conn, &handle)?;
+            jobset.new_evaluation(conn, &evaluation.url)
+        });
+        if let Err(e) = res {
+            tracing::error!("failed to resume evaluation {}: {:?}", evaluation.uuid, e);
+        }
+    }
+}
+
+/// Schedules the retries that were pending when the previous process stopped
+fn reschedule(conn: &mut Conn) -> Result<(), Error> {
+    let pending = schema::jobs::table
+        .inner_join(schema::evaluations::table.inner_join(schema::projects::table))
+        .filter(schema::jobs::retry_at.is_not_null())
+        .load::<(models::Job, (models::Evaluation, models::Project))>(conn)?;
+    for (job, (evaluation, project)) in pending {
+        let Some(due) = job.retry_at else {
+            continue;
+        };
+        let num = job.tries;
+        // the reason is only recorded on the new run, it is found again from
+        // the status of the build of the failed run
+        let status = schema::runs::table
+            .inner_join(
+                schema::builds::table.on(schema::runs::build_id.eq(schema::builds::id.nullable())),
+            )
+            .inner_join(schema::tasks::table.on(schema::builds::task_id.eq(schema::tasks::id)))
+            .filter(schema::runs::job_id.eq(job.id))
+            .filter(schema::runs::num.eq(num))
+            .select(schema::tasks::status)
+            .first::<i32>(conn)
+            .optional()?
+            .and_then(|status| TaskStatusKind::try_from(status).ok())
+            .unwrap_or(TaskStatusKind::Failure);
+        let reason = runs::retry_reason(num, status);
+        let job = jobs::Job {
+            job,
+            evaluation,
+            project,
+        };
+        job.spawn_retry(num, due, reason);
+    }
+    Ok(())
+}
+
+/// Cancels the tasks left pending by a previous process, whose runs and
+/// evaluations are started again when `resume` is set, and schedules the
+/// pending retries again. It must be called before anything is run.
+pub fn reconcile(conn: &mut Conn, resume: bool) -> Result<(), Error> {
+    let interrupted = cancel_all(conn)?;
+    reschedule(conn)?;
+    if resume && !(interrupted.runs.is_empty() && interrupted.evaluations.is_empty()) {
+        // starting a build blocks on the build manager
+        RUNTIME.spawn_blocking(move || {
+            let mut conn = POOL.get().unwrap();
+            self::resume(&mut conn, interrupted);
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,631 @@
+use crate::actions;
+use crate::error;
+use crate::evaluations::Evaluation;
+use crate::models;
+use crate::nix;
+use crate::runs::Run;
+use crate::Conn;
+use crate::EVENT_LOGGER;
+use crate::POOL;
+use crate::RUNTIME;
+
+use typhon_types::data::TaskStatusKind;
+use typhon_types::Event;
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+/// Number of statuses posted concurrently
+const WORKERS: usize = 16;
+
+/// Number of revisions and secrets kept in the cache
+const CACHE_SIZE: usize = 256;
+
+#[derive(Debug)]
+pub enum Error {
+    MissingSecret(String),
+    RequestFailed(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use Error::*;
+        match self {
+            MissingSecret(name) => write!(f, "Secret {} not found", name),
+            RequestFailed(e) => write!(f, "Request failed: {}", e),
+        }
+    }
+}
+
+/// The state of a commit status, as understood by forges
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Pending,
+    Success,
+    Failure,
+    Error,
+}
+
+impl From<TaskStatusKind> for State {
+    fn from(status: TaskStatusKind) -> Self {
+        match status {
+            TaskStatusKind::Pending => State::Pending,
+            TaskStatusKind::Success => State::Success,
+            TaskStatusKind::Failure => State::Failure,
+            TaskStatusKind::Canceled => State::Error,
+            TaskStatusKind::TimedOut => State::Failure,
+        }
+    }
+}
+
+/// A status to report on a commit
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub context: String,
+    pub description: String,
+    pub event: Event,
+    /// The path of the corresponding page of the webapp
+    pub path: String,
+    pub project: String,
+    pub rev: String,
+    pub state: State,
+}
+
+/// The declaration of a reporter, in the `reporters` attribute of a project.
+/// Secrets are given by their name in the project's secrets.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ReporterDecl {
+    Github {
+        #[serde(default = "github_api")]
+        api: String,
+        owner: String,
+        repo: String,
+        #[serde(default = "github_token")]
+        token: String,
+        #[serde(rename = "typhonUrl")]
+        typhon_url: String,
+    },
+    Gitea {
+        api: String,
+        owner: String,
+        repo: String,
+        #[serde(default = "gitea_token")]
+        token: String,
+        #[serde(rename = "typhonUrl")]
+        typhon_url: String,
+    },
+    Webhook {
+        url: String,
+        secret: Option<String>,
+    },
+}
+
+fn github_api() -> String {
+    "api.github.com".to_string()
+}
+
+fn github_token() -> String {
+    "github_token".to_string()
+}
+
+fn gitea_token() -> String {
+    "gitea_token".to_string()
+}
+
+#[async_trait]
+pub trait Reporter {
+    async fn report(&self, status: &Status, secrets: &Value) -> Result<(), Error>;
+}
+
+/// A forge with a GitHub-like commit status API
+struct Forge {
+    api: String,
+    keyword: &'static str,
+    owner: String,
+    repo: String,
+    token: String,
+    typhon_url: String,
+}
+
+#[async_trait]
+impl Reporter for Forge {
+    async fn report(&self, status: &Status, secrets: &Value) -> Result<(), Error> {
+        let token = secret(secrets, &self.token)?;
+        let url = format!(
+            "{}/repos/{}/{}/statuses/{}",
+            base_url(&self.api),
+            self.owner,
+            self.repo,
+            status.rev,
+        );
+        let body = json!({
+            "context": status.context,
+            "description": status.description,
+            "state": status.state,
+            "target_url": format!("{}{}", self.typhon_url, status.path),
+        });
+        post(&url, Some(format!("{} {}", self.keyword, token)), &body).await
+    }
+}
+
+/// Posts the statuses as JSON to an arbitrary URL
+struct Webhook {
+    url: String,
+    secret: Option<String>,
+}
+
+#[async_trait]
+impl Reporter for Webhook {
+    async fn report(&self, status: &Status, secrets: &Value) -> Result<(), Error> {
+        let authorization = match &self.secret {
+            Some(name) => Some(format!("Bearer {}", secret(secrets, name)?)),
+            None => None,
+        };
+        post(&self.url, authorization, &json!(status)).await
+    }
+}
+
+impl ReporterDecl {
+    fn reporter(self) -> Box<dyn Reporter + Send + Sync> {
+        match self {
+            ReporterDecl::Github {
+                api,
+                owner,
+                repo,
+                token,
+                typhon_url,
+            } => Box::new(Forge {
+                api,
+                keyword: "Bearer",
+                owner,
+                repo,
+                token,
+                typhon_url,
+            }),
+            ReporterDecl::Gitea {
+                api,
+                owner,
+                repo,
+                token,
+                typhon_url,
+            } => Box::new(Forge {
+                api,
+                keyword: "token",
+                owner,
+                repo,
+                token,
+                typhon_url,
+            }),
+            ReporterDecl::Webhook { url, secret } => Box::new(Webhook { url, secret }),
+        }
+    }
+}
+
+fn secret<'a>(secrets: &'a Value, name: &str) -> Result<&'a str, Error> {
+    secrets[name]
+        .as_str()
+        .ok_or_else(|| Error::MissingSecret(name.to_string()))
+}
+
+/// APIs are given without a scheme, unless they are not served over HTTPS
+fn base_url(api: &str) -> String {
+    if api.contains("://") {
+        api.to_string()
+    } else {
+        format!("https://{}", api)
+    }
+}
+
+fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        // GitHub rejects requests without a user agent
+        .user_agent("typhon")
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap()
+});
+
+async fn post(url: &str, authorization: Option<String>, body: &Value) -> Result<(), Error> {
+    let mut request = CLIENT
+        .post(url)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .json(body);
+    if let Some(authorization) = authorization {
+        request = request.header(reqwest::header::AUTHORIZATION, authorization);
+    }
+    request
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| Error::RequestFailed(e.to_string()))?;
+    Ok(())
+}
+
+/// The status corresponding to an event, without its revision, along with the
+/// project and the flake URL of the evaluation.
+fn status(
+    conn: &mut Conn,
+    event: &Event,
+) -> Result<Option<(Status, models::Project, String)>, error::Error> {
+    let (project, evaluation, context, path, state, description) = match event {
+        Event::RunNew(handle, _) | Event::RunUpdated(handle, _) => {
+            let run = Run::get(conn, handle)?;
+            let state = run
+                .build
+                .as_ref()
+                .map(|build| build.task.status_kind())
+                .unwrap_or(TaskStatusKind::Pending);
+            let description = match state {
+                TaskStatusKind::Pending => "Building",
+                TaskStatusKind::Success => "Build succeeded",
+                TaskStatusKind::Failure => "Build failed",
+                TaskStatusKind::Canceled => "Build canceled",
+                TaskStatusKind::TimedOut => "Build timed out",
+            };
+            (
+                run.project,
+                run.evaluation.clone(),
+                format!("Typhon: {} / {}", run.job.system, run.job.name),
+                format!(
+                    "/evaluation/{}/{}/{}",
+                    run.evaluation.uuid,
+                    encode(&run.job.system),
+                    encode(&run.job.name),
+                ),
+                state,
+                description,
+            )
+        }
+        Event::EvaluationFinished(handle, _) => {
+            let evaluation = Evaluation::get(conn, handle)?;
+            let state = evaluation.task.status_kind();
+            let description = match state {
+                TaskStatusKind::Pending => "Evaluating",
+                TaskStatusKind::Success => "Evaluation succeeded",
+                TaskStatusKind::Failure => "Evaluation failed",
+                TaskStatusKind::Canceled => "Evaluation canceled",
+                TaskStatusKind::TimedOut => "Evaluation timed out",
+            };
+            (
+                evaluation.project,
+                evaluation.evaluation.clone(),
+                format!(
+                    "Typhon: evaluation of {}",
+                    evaluation.evaluation.jobset_name
+                ),
+                format!("/evaluation/{}", evaluation.evaluation.uuid),
+                state,
+                description,
+            )
+        }
+        _ => return Ok(None),
+    };
+    let status = Status {
+        context,
+        description: description.to_string(),
+        event: event.clone(),
+        path,
+        project: project.name.clone(),
+        rev: String::new(),
+        state: state.into(),
+    };
+    Ok(Some((status, project, evaluation.url)))
+}
+
+/// The revisions of flakes and the secrets of projects, needed for every run
+/// of an evaluation. Both are keyed by immutable values: locked flake URLs and
+/// store paths of actions.
+#[derive(Default)]
+struct Cache {
+    revs: HashMap<String, Option<String>>,
+    secrets: HashMap<String, Value>,
+}
+
+type SharedCache = Arc<Mutex<Cache>>;
+
+fn cached<T: Clone>(map: &mut HashMap<String, T>, key: &str, value: T) -> T {
+    if map.len() >= CACHE_SIZE {
+        map.clear();
+    }
+    map.insert(key.to_string(), value.clone());
+    value
+}
+
+fn secrets(cache: &SharedCache, project: &models::Project) -> Result<Value, actions::Error> {
+    let Some(path) = &project.actions_path else {
+        return Ok(json!({}));
+    };
+    if let Some(secrets) = cache.lock().unwrap().secrets.get(path) {
+        return Ok(secrets.clone());
+    }
+    let secrets = actions::secrets(&project.key, path)?;
+    Ok(cached(&mut cache.lock().unwrap().secrets, path, secrets))
+}
+
+async fn rev(cache: &SharedCache, url: &str) -> Result<Option<String>, nix::Error> {
+    if let Some(rev) = cache.lock().unwrap().revs.get(url) {
+        return Ok(rev.clone());
+    }
+    let rev = nix::flake_rev(url).await?;
+    Ok(cached(&mut cache.lock().unwrap().revs, url, rev))
+}
+
+async fn report(cache: &SharedCache, event: Event) {
+    let res = RUNTIME
+        .spawn_blocking({
+            let cache = cache.clone();
+            let event = event.clone();
+            move || {
+                let Some((status, project, url)) = status(&mut POOL.get().unwrap(), &event)? else {
+                    return Ok(None);
+                };
+                let decls: Vec<ReporterDecl> =
+                    serde_json::from_str(&project.reporters).unwrap_or_default();
+                if decls.is_empty() {
+                    return Ok(None);
+                }
+                // the secrets are decrypted here, as it blocks
+                let secrets = secrets(&cache, &project)?;
+                Ok::<_, error::Error>(Some((status, project, url, decls, secrets)))
+            }
+        })
+        .await;
+    let (mut status, project, url, decls, secrets) = match res {
+        Ok(Ok(Some(x))) => x,
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => {
+            tracing::warn!("failed to report {:?}: {}", event, e);
+            return;
+        }
+        Err(e) => {
+            tracing::error!("reporter panicked: {:?}", e);
+            return;
+        }
+    };
+
+    status.rev = match rev(cache, &url).await {
+        Ok(Some(rev)) => rev,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::warn!("failed to get the revision of {}: {}", url, e);
+            return;
+        }
+    };
+    for decl in decls {
+        if let Err(e) = decl.reporter().report(&status, &secrets).await {
+            tracing::warn!("failed to report status of {}: {}", project.name, e);
+        }
+    }
+}
+
+/// The worker reporting an event. The statuses of a job, or of an evaluation,
+/// always go to the same worker, so that they are posted in order.
+fn worker(event: &Event) -> usize {
+    let mut hasher = DefaultHasher::new();
+    match event {
+        Event::RunNew(handle, _) | Event::RunUpdated(handle, _) => handle.job.hash(&mut hasher),
+        Event::EvaluationFinished(handle, _) => handle.hash(&mut hasher),
+        _ => (),
+    }
+    hasher.finish() as usize % WORKERS
+}
+
+/// Reports the statuses of runs and evaluations to the reporters declared by
+/// projects.
+pub struct Reporters {
+    shutdown: watch::Sender<bool>,
+    watch: watch::Receiver<()>,
+}
+
+impl Reporters {
+    pub fn new() -> Self {
+        let (shutdown, mut shutdown_recv) = watch::channel(false);
+        let (watch_send, watch) = watch::channel(());
+        RUNTIME.spawn(async move {
+            let cache = SharedCache::default();
+            let mut workers = JoinSet::new();
+            let senders: Vec<mpsc::UnboundedSender<Event>> = (0..WORKERS)
+                .map(|_| {
+                    let (sender, mut receiver) = mpsc::unbounded_channel();
+                    let cache = cache.clone();
+                    workers.spawn(async move {
+                        while let Some(event) = receiver.recv().await {
+                            report(&cache, event).await;
+                        }
+                    });
+                    sender
+                })
+                .collect();
+            let mut events = EVENT_LOGGER.receiver(None);
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = shutdown_recv.changed() => break,
+                };
+                match event.map(|event| event.event) {
+                    Some(
+                        event @ (Event::RunNew(..)
+                        | Event::RunUpdated(..)
+                        | Event::EvaluationFinished(..)),
+                    ) => {
+                        let _ = senders[worker(&event)].send(event);
+                    }
+                    Some(_) => (),
+                    None => break,
+                }
+            }
+            workers.shutdown().await;
+            let _watch_send = watch_send;
+        });
+        Self { shutdown, watch }
+    }
+
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        while self.watch.clone().changed().await.is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handles;
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    struct Request {
+        line: String,
+        headers: HashMap<String, String>,
+        body: Value,
+    }
+
+    /// A forge answering `201 Created` to the first request it receives
+    async fn mock() -> (String, tokio::task::JoinHandle<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let mut headers = HashMap::new();
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                let Some((name, value)) = header.trim_end().split_once(": ") else {
+                    break;
+                };
+                headers.insert(name.to_lowercase(), value.to_string());
+            }
+            let mut body = vec![0; headers["content-length"].parse().unwrap()];
+            stream.read_exact(&mut body).await.unwrap();
+            stream
+                .get_mut()
+                .write_all(
+                    b"HTTP/1.1 201 Created\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            Request {
+                line: line.trim_end().to_string(),
+                headers,
+                body: serde_json::from_slice(&body).unwrap(),
+            }
+        });
+        (url, handle)
+    }
+
+    fn status() -> Status {
+        Status {
+            context: "Typhon: x86_64-linux / hello".to_string(),
+            description: "Build succeeded".to_string(),
+            event: Event::EvaluationFinished(
+                handles::evaluation(uuid::Uuid::nil()),
+                handles::jobset(("test".to_string(), "main".to_string())),
+            ),
+            path: "/evaluation/00000000-0000-0000-0000-000000000000".to_string(),
+            project: "test".to_string(),
+            rev: "0123abcd".to_string(),
+            state: State::Success,
+        }
+    }
+
+    #[tokio::test]
+    async fn github() {
+        let (url, request) = mock().await;
+        let decl = ReporterDecl::Github {
+            api: url,
+            owner: "owner".to_string(),
+            repo: "repo".to_string(),
+            token: "github_token".to_string(),
+            typhon_url: "https://typhon.example".to_string(),
+        };
+        let secrets = json!({ "github_token": "secret" });
+        decl.reporter().report(&status(), &secrets).await.unwrap();
+        let request = request.await.unwrap();
+        assert_eq!(
+            request.line,
+            "POST /repos/owner/repo/statuses/0123abcd HTTP/1.1"
+        );
+        assert_eq!(request.headers["authorization"], "Bearer secret");
+        assert_eq!(request.headers["user-agent"], "typhon");
+        assert_eq!(
+            request.body,
+            json!({
+                "context": "Typhon: x86_64-linux / hello",
+                "description": "Build succeeded",
+                "state": "success",
+                "target_url": "https://typhon.example/evaluation/00000000-0000-0000-0000-000000000000",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn gitea() {
+        let (url, request) = mock().await;
+        let decl = ReporterDecl::Gitea {
+            api: format!("{}/api/v1", url),
+            owner: "owner".to_string(),
+            repo: "repo".to_string(),
+            token: "gitea_token".to_string(),
+            typhon_url: "https://typhon.example".to_string(),
+        };
+        let secrets = json!({ "gitea_token": "secret" });
+        decl.reporter().report(&status(), &secrets).await.unwrap();
+        let request = request.await.unwrap();
+        assert_eq!(
+            request.line,
+            "POST /api/v1/repos/owner/repo/statuses/0123abcd HTTP/1.1"
+        );
+        assert_eq!(request.headers["authorization"], "token secret");
+        assert_eq!(request.body["state"], "success");
+    }
+
+    #[tokio::test]
+    async fn webhook() {
+        let (url, request) = mock().await;
+        let decl = ReporterDecl::Webhook {
+            url: format!("{}/hook", url),
+            secret: Some("hook_secret".to_string()),
+        };
+        let secrets = json!({ "hook_secret": "secret" });
+        decl.reporter().report(&status(), &secrets).await.unwrap();
+        let request = request.await.unwrap();
+        assert_eq!(request.line, "POST /hook HTTP/1.1");
+        assert_eq!(request.headers["authorization"], "Bearer secret");
+        assert_eq!(request.body, json!(status()));
+    }
+
+    #[tokio::test]
+    async fn missing_secret() {
+        let decl = ReporterDecl::Webhook {
+            url: "http://127.0.0.1:1".to_string(),
+            secret: Some("hook_secret".to_string()),
+        };
+        let res = decl.reporter().report(&status(), &json!({})).await;
+        assert!(matches!(res, Err(Error::MissingSecret(_))));
+    }
+}
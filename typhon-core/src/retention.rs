@@ -0,0 +1,379 @@
+use crate::error::Error;
+use crate::events;
+use crate::gcroots;
+use crate::handles;
+use crate::logs;
+use crate::schema;
+use crate::Conn;
+use crate::Settings;
+use crate::POOL;
+use crate::RUNTIME;
+
+use typhon_types::data::{PruneInfo, TaskStatusKind};
+
+use diesel::prelude::*;
+use time::OffsetDateTime;
+use tokio::sync::watch;
+
+use std::collections::{HashMap, HashSet};
+
+const INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// How long evaluations are kept. An evaluation is pruned when it is neither
+/// among the last `evaluations` of its jobset nor younger than `days`. Nothing
+/// is pruned when both are `None`.
+#[derive(Clone, Debug, Default)]
+pub struct Retention {
+    pub evaluations: Option<u32>,
+    pub days: Option<u32>,
+}
+
+impl Retention {
+    fn keeps(&self, rank: u32, time_created: i64, now: i64) -> bool {
+        match (self.evaluations, self.days) {
+            (None, None) => true,
+            (evaluations, days) => {
+                evaluations.is_some_and(|n| rank < n)
+                    || days.is_some_and(|d| now - time_created < d as i64 * 24 * 60 * 60)
+            }
+        }
+    }
+}
+
+/// The rows to delete
+#[derive(Default)]
+struct Plan {
+    actions: Vec<i32>,
+    builds: Vec<i32>,
+    evaluations: Vec<(i32, String)>,
+    jobs: Vec<i32>,
+    runs: Vec<i32>,
+    tasks: Vec<i32>,
+}
+
+/// The evaluation, id, build, 'begin' action and 'end' action of a run
+type RunIds = (i32, i32, Option<i32>, Option<i32>, Option<i32>);
+
+fn plan(conn: &mut Conn, retention: &Retention) -> Result<Plan, Error> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let pending = i32::from(TaskStatusKind::Pending);
+    let pending_tasks: HashSet<i32> = schema::tasks::table
+        .filter(schema::tasks::status.eq(pending))
+        .select(schema::tasks::id)
+        .load::<i32>(conn)?
+        .into_iter()
+        .collect();
+
+    // evaluations, from the most recent to the oldest for each jobset
+    let evaluations = schema::evaluations::table
+        .order((
+            schema::evaluations::project_id.asc(),
+            schema::evaluations::jobset_name.asc(),
+            schema::evaluations::time_created.desc(),
+        ))
+        .select((
+            schema::evaluations::id,
+            schema::evaluations::project_id,
+            schema::evaluations::jobset_name,
+            schema::evaluations::task_id,
+            schema::evaluations::time_created,
+            schema::evaluations::uuid,
+        ))
+        .load::<(i32, i32, String, i32, i64, String)>(conn)?;
+    let mut ranks: HashMap<(i32, String), u32> = HashMap::new();
+    let mut candidates: HashMap<i32, (String, i32)> = HashMap::new();
+    for (id, project_id, jobset_name, task_id, time_created, uuid) in evaluations {
+        let rank = ranks.entry((project_id, jobset_name)).or_insert(0);
+        if !retention.keeps(*rank, time_created, now) && !pending_tasks.contains(&task_id) {
+            candidates.insert(id, (uuid, task_id));
+        }
+        *rank += 1;
+    }
+
+    // the runs of the candidates, evaluations with unfinished runs are kept
+    let mut runs: Vec<RunIds> = Vec::new();
+    let ids: Vec<i32> = candidates.keys().copied().collect();
+    // SQLite limits the number of bound parameters per statement
+    for ids in ids.chunks(500) {
+        runs.extend(
+            schema::runs::table
+                .inner_join(schema::jobs::table)
+                .filter(schema::jobs::evaluation_id.eq_any(ids))
+                .select((
+                    schema::jobs::evaluation_id,
+                    schema::runs::id,
+                    schema::runs::build_id,
+                    schema::runs::begin_id,
+                    schema::runs::end_id,
+                ))
+                .load::<(i32, i32, Option<i32>, Option<i32>, Option<i32>)>(conn)?,
+        );
+    }
+    let build_ids: Vec<i32> = runs.iter().filter_map(|run| run.2).collect();
+    let action_ids: Vec<i32> = runs
+        .iter()
+        .flat_map(|run| [run.3, run.4])
+        .flatten()
+        .collect();
+    let mut build_tasks: HashMap<i32, i32> = HashMap::new();
+    for ids in build_ids.chunks(500) {
+        build_tasks.extend(
+            schema::builds::table
+                .filter(schema::builds::id.eq_any(ids))
+                .select((schema::builds::id, schema::builds::task_id))
+                .load::<(i32, i32)>(conn)?,
+        );
+    }
+    let mut action_tasks: HashMap<i32, i32> = HashMap::new();
+    for ids in action_ids.chunks(500) {
+        action_tasks.extend(
+            schema::actions::table
+                .filter(schema::actions::id.eq_any(ids))
+                .select((schema::actions::id, schema::actions::task_id))
+                .load::<(i32, i32)>(conn)?,
+        );
+    }
+    let is_pending = |tasks: &HashMap<i32, i32>, id: Option<i32>| {
+        id.and_then(|id| tasks.get(&id))
+            .is_some_and(|task_id| pending_tasks.contains(task_id))
+    };
+    for (evaluation_id, _, build_id, begin_id, end_id) in runs.iter() {
+        if is_pending(&build_tasks, *build_id)
+            || is_pending(&action_tasks, *begin_id)
+            || is_pending(&action_tasks, *end_id)
+        {
+            candidates.remove(evaluation_id);
+        }
+    }
+    runs.retain(|run| candidates.contains_key(&run.0));
+
+    let mut plan = Plan::default();
+    let mut builds: HashSet<i32> = HashSet::new();
+    for (_, run_id, build_id, begin_id, end_id) in runs {
+        plan.runs.push(run_id);
+        builds.extend(build_id);
+        plan.actions.extend(begin_id);
+        plan.actions.extend(end_id);
+    }
+    let ids: Vec<i32> = candidates.keys().copied().collect();
+    for ids in ids.chunks(500) {
+        plan.jobs.extend(
+            schema::jobs::table
+                .filter(schema::jobs::evaluation_id.eq_any(ids))
+                .select(schema::jobs::id)
+                .load::<i32>(conn)?,
+        );
+    }
+
+    // builds are shared between runs, they are kept as long as a run uses them
+    let pruned_runs: HashSet<i32> = plan.runs.iter().copied().collect();
+    let ids: Vec<i32> = builds.iter().copied().collect();
+    for ids in ids.chunks(500) {
+        let users = schema::runs::table
+            .filter(schema::runs::build_id.eq_any(ids))
+            .select((schema::runs::id, schema::runs::build_id))
+            .load::<(i32, Option<i32>)>(conn)?;
+        for (run_id, build_id) in users {
+            if !pruned_runs.contains(&run_id) {
+                builds.remove(&build_id.unwrap());
+            }
+        }
+    }
+    // as well as the builds of the actions of a project
+    let refresh_builds = schema::projects::table
+        .select(schema::projects::last_refresh_build_id)
+        .load::<Option<i32>>(conn)?;
+    for build_id in refresh_builds.into_iter().flatten() {
+        builds.remove(&build_id);
+    }
+    plan.builds = builds.into_iter().collect();
+
+    for (id, (uuid, task_id)) in candidates {
+        plan.evaluations.push((id, uuid));
+        plan.tasks.push(task_id);
+    }
+    plan.tasks
+        .extend(plan.builds.iter().filter_map(|id| build_tasks.get(id)));
+    plan.tasks
+        .extend(plan.actions.iter().filter_map(|id| action_tasks.get(id)));
+
+    Ok(plan)
+}
+
+/// Deletes the rows of the plan, returns the paths of the logs to remove
+fn execute(conn: &mut Conn, plan: &mut Plan) -> Result<Vec<String>, Error> {
+    let evaluation_ids: Vec<i32> = plan.evaluations.iter().map(|(id, _)| *id).collect();
+    for ids in plan.runs.chunks(500) {
+        diesel::delete(schema::runs::table.filter(schema::runs::id.eq_any(ids))).execute(conn)?;
+    }
+    // builds used by a run created since the plan are kept, with their task
+    let mut kept: Vec<i32> = Vec::new();
+    for ids in plan.builds.chunks(500) {
+        kept.extend(
+            schema::runs::table
+                .filter(schema::runs::build_id.eq_any(ids))
+                .select(schema::runs::build_id)
+                .load::<Option<i32>>(conn)?
+                .into_iter()
+                .flatten(),
+        );
+    }
+    if !kept.is_empty() {
+        let mut kept_tasks: HashSet<i32> = HashSet::new();
+        for ids in kept.chunks(500) {
+            kept_tasks.extend(
+                schema::builds::table
+                    .filter(schema::builds::id.eq_any(ids))
+                    .select(schema::builds::task_id)
+                    .load::<i32>(conn)?,
+            );
+        }
+        let kept: HashSet<i32> = kept.into_iter().collect();
+        plan.builds.retain(|id| !kept.contains(id));
+        plan.tasks.retain(|id| !kept_tasks.contains(id));
+    }
+    for ids in plan.jobs.chunks(500) {
+        diesel::delete(schema::jobs::table.filter(schema::jobs::id.eq_any(ids))).execute(conn)?;
+    }
+    for ids in evaluation_ids.chunks(500) {
+        diesel::delete(schema::evaluations::table.filter(schema::evaluations::id.eq_any(ids)))
+            .execute(conn)?;
+    }
+    for ids in plan.builds.chunks(500) {
+        diesel::delete(schema::builds::table.filter(schema::builds::id.eq_any(ids)))
+            .execute(conn)?;
+    }
+    for ids in plan.actions.chunks(500) {
+        diesel::delete(schema::actions::table.filter(schema::actions::id.eq_any(ids)))
+            .execute(conn)?;
+    }
+    let mut paths = Vec::new();
+    for ids in plan.tasks.chunks(500) {
+        let log_ids: Vec<i32> = schema::tasks::table
+            .filter(schema::tasks::id.eq_any(ids))
+            .select(schema::tasks::log_id)
+            .load(conn)?;
+        paths.extend(
+            schema::logs::table
+                .filter(schema::logs::id.eq_any(&log_ids))
+                .select(schema::logs::path)
+                .load::<Option<String>>(conn)?
+                .into_iter()
+                .flatten(),
+        );
+        diesel::delete(schema::tasks::table.filter(schema::tasks::id.eq_any(ids))).execute(conn)?;
+        diesel::delete(schema::logs::table.filter(schema::logs::id.eq_any(log_ids)))
+            .execute(conn)?;
+    }
+    Ok(paths)
+}
+
+/// Gives the space freed by pruning back to the file system. Vacuuming
+/// rewrites the whole database, so it is done in a background thread.
+fn vacuum() {
+    use diesel::connection::SimpleConnection;
+
+    RUNTIME.spawn_blocking(|| {
+        if let Err(e) = POOL.get().unwrap().batch_execute("VACUUM;") {
+            tracing::warn!("failed to vacuum the database: {}", e);
+        }
+    });
+}
+
+/// Deletes the evaluations that are out of the retention policy, along with
+/// their jobs, runs, builds, actions and logs. In a dry run, only reports what
+/// would be deleted.
+pub fn prune(conn: &mut Conn, retention: &Retention, dry_run: bool) -> Result<PruneInfo, Error> {
+    // the plan is made in the transaction deleting its rows, so that they
+    // cannot be used meanwhile
+    let (plan, paths) = conn.transaction::<_, Error, _>(|conn| {
+        let mut plan = plan(conn, retention)?;
+        let paths = if dry_run {
+            Vec::new()
+        } else {
+            execute(conn, &mut plan)?
+        };
+        Ok((plan, paths))
+    })?;
+    // files are only removed once the rows are gone
+    for path in paths {
+        logs::files::remove(&path);
+    }
+    if !dry_run {
+        for (_, uuid) in plan.evaluations.iter() {
+            gcroots::remove_evaluation(uuid);
+        }
+    }
+    let info = PruneInfo {
+        actions: plan.actions.len(),
+        builds: plan.builds.len(),
+        dry_run,
+        evaluations: plan
+            .evaluations
+            .iter()
+            .filter_map(|(_, uuid)| Some(handles::evaluation(uuid.parse().ok()?)))
+            .collect(),
+        jobs: plan.jobs.len(),
+        logs: plan.tasks.len(),
+        runs: plan.runs.len(),
+    };
+    if !dry_run && !plan.evaluations.is_empty() {
+        vacuum();
+    }
+    Ok(info)
+}
+
+/// Prunes the database every day, following the retention policy given on
+/// the command line. Events too old to be replayed are pruned too.
+pub struct Pruner {
+    shutdown: watch::Sender<bool>,
+    watch: watch::Receiver<()>,
+}
+
+impl Pruner {
+    pub fn new() -> Self {
+        let (shutdown, mut shutdown_recv) = watch::channel(false);
+        let (watch_send, watch) = watch::channel(());
+        RUNTIME.spawn(async move {
+            let mut interval = tokio::time::interval(INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => (),
+                    _ = shutdown_recv.changed() => break,
+                }
+                let retention = &Settings::get().retention;
+                let res = RUNTIME
+                    .spawn_blocking(move || {
+                        let mut conn = POOL.get().unwrap();
+                        match events::prune(&mut conn) {
+                            Ok(n) => tracing::info!("pruned {} events", n),
+                            Err(e) => tracing::error!("failed to prune events: {}", e),
+                        }
+                        if retention.evaluations.is_none() && retention.days.is_none() {
+                            return Ok(None);
+                        }
+                        prune(&mut conn, retention, false).map(Some)
+                    })
+                    .await;
+                match res {
+                    Ok(Ok(None)) => (),
+                    Ok(Ok(Some(info))) => tracing::info!(
+                        "pruned {} evaluations and {} logs",
+                        info.evaluations.len(),
+                        info.logs
+                    ),
+                    Ok(Err(e)) => tracing::error!("pruner raised error: {:?}", e),
+                    Err(e) => tracing::error!("pruner panicked: {:?}", e),
+                }
+            }
+            let _watch_send = watch_send;
+        });
+        Self { shutdown, watch }
+    }
+
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        while self.watch.clone().changed().await.is_ok() {}
+    }
+}
@@ -2,8 +2,10 @@ use crate::actions;
 use crate::builds;
 use crate::error::Error;
 use crate::handles;
+use crate::jobs;
 use crate::log_event;
 use crate::models;
+use crate::queue;
 use crate::responses;
 use crate::schema;
 use crate::tasks;
@@ -19,6 +21,14 @@ use uuid::Uuid;
 
 use std::str::FromStr;
 
+/// Why a job is retried after the build of its run `num` ended with `status`
+pub fn retry_reason(num: i32, status: TaskStatusKind) -> String {
+    match status {
+        TaskStatusKind::TimedOut => format!("the build of run {} timed out", num),
+        _ => format!("the build of run {} failed", num),
+    }
+}
+
 #[derive(Clone)]
 pub struct Run {
     pub begin: Option<actions::Action>,
@@ -31,9 +41,14 @@ pub struct Run {
 }
 
 impl Run {
-    //pub fn cancel(&self) {
-    //    RUNS.cancel(self.run.id);
-    //}
+    pub fn cancel(&self) {
+        // Dropping the run's waiter releases its `BuildHandle`: the build
+        // manager only cancels the build if no other run is waiting on it.
+        RUNS.cancel(self.run.id);
+        if let Some(begin) = &self.begin {
+            begin.task.cancel();
+        }
+    }
 
     pub fn get(conn: &mut Conn, handle: &handles::Run) -> Result<Self, Error> {
         let (begin_action, end_action, begin_task, build_task, end_task) = diesel::alias!(
@@ -143,6 +158,13 @@ impl Run {
         ))
     }
 
+    pub fn jobset_handle(&self) -> handles::Jobset {
+        handles::jobset((
+            self.project.name.clone(),
+            self.evaluation.jobset_name.clone(),
+        ))
+    }
+
     pub fn info(&self) -> responses::RunInfo {
         use crate::evaluations::ExtraRunInfo;
         let Run {
@@ -162,17 +184,23 @@ impl Run {
         )
     }
 
-    pub fn run(&self, conn: &mut Conn) -> Result<(), Error> {
+    pub fn run(&self, conn: &mut Conn, priority: queue::Priority) -> Result<(), Error> {
         use crate::build_manager::BUILDS;
         use crate::nix;
         use crate::TASKS;
 
         // run the build
         let drv = nix::DrvPath::new(&self.job.drv);
-        let build_handle = BUILDS.run(drv);
+        let timeout = self
+            .job
+            .timeout
+            .as_ref()
+            .and_then(|timeout| serde_json::from_str(timeout).ok())
+            .unwrap_or_default();
+        let build_handle = BUILDS.run(drv, priority, timeout);
 
         // run the 'begin' action
-        let action_begin = self.spawn_action(conn, "begin", TaskStatusKind::Pending)?;
+        let action_begin = self.spawn_action(conn, "begin", TaskStatusKind::Pending, priority)?;
 
         diesel::update(&self.run)
             .set((
@@ -180,30 +208,27 @@ impl Run {
                 schema::runs::build_id.eq(build_handle.id),
             ))
             .execute(conn)?;
-        log_event(Event::RunUpdated(self.handle()));
+        log_event(Event::RunUpdated(self.handle(), self.jobset_handle()));
 
         // a waiter task
         let run_run = async move {
             TASKS.wait(&action_begin.task.task.id).await;
-            let res = build_handle.wait().await;
-            match res {
-                Some(Some(())) => TaskStatusKind::Success,
-                Some(None) => TaskStatusKind::Failure,
-                None => TaskStatusKind::Canceled,
-            }
+            build_handle.wait().await
         };
 
         // run the 'end' action
         let finish_run = {
             let self_ = self.clone();
-            let finish_err = move |status| {
-                if let Some(status) = status {
-                    let mut conn = POOL.get().unwrap();
-                    let action_end = self_.spawn_action(&mut conn, "end", status)?;
-                    diesel::update(&self_.run)
-                        .set((schema::runs::end_id.eq(action_end.action.id),))
-                        .execute(&mut conn)?;
-                    log_event(Event::RunUpdated(self_.handle()));
+            let finish_err = move |status: Option<TaskStatusKind>| {
+                let status = status.unwrap_or(TaskStatusKind::Canceled);
+                let mut conn = POOL.get().unwrap();
+                let action_end = self_.spawn_action(&mut conn, "end", status, priority)?;
+                diesel::update(&self_.run)
+                    .set((schema::runs::end_id.eq(action_end.action.id),))
+                    .execute(&mut conn)?;
+                log_event(Event::RunUpdated(self_.handle(), self_.jobset_handle()));
+                if let TaskStatusKind::Failure | TaskStatusKind::TimedOut = status {
+                    self_.retry(&mut conn, &retry_reason(self_.run.num, status))?;
                 }
                 Ok::<_, Error>(())
             };
@@ -218,6 +243,17 @@ impl Run {
         Ok(())
     }
 
+    /// Runs the job again after a failure, once the backoff delay is over, if
+    /// its retry policy allows it. The reason is recorded on the new run.
+    fn retry(&self, conn: &mut Conn, reason: &str) -> Result<(), Error> {
+        let job = jobs::Job {
+            job: self.job.clone(),
+            evaluation: self.evaluation.clone(),
+            project: self.project.clone(),
+        };
+        job.schedule_retry(conn, self.run.num, reason)
+    }
+
     fn mk_input(&self, status: TaskStatusKind) -> Result<serde_json::Value, Error> {
         Ok(serde_json::json!({
             "drv": self.job.drv,
@@ -238,6 +274,7 @@ impl Run {
         conn: &mut Conn,
         name: &str,
         status: TaskStatusKind,
+        priority: queue::Priority,
     ) -> Result<actions::Action, Error> {
         use crate::projects;
 
@@ -264,7 +301,7 @@ impl Run {
             None => TaskStatusKind::Failure,
         };
 
-        action.spawn(conn, finish)?;
+        action.spawn(conn, priority, finish)?;
 
         Ok(action)
     }
@@ -0,0 +1,260 @@
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+/// How action scripts are isolated from the system
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// A bubblewrap container, which only sees the Nix store
+    #[default]
+    Bwrap,
+    /// A transient systemd scope, which only enforces resource limits
+    SystemdRun,
+    /// No isolation at all, for development
+    None,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bwrap" => Ok(Backend::Bwrap),
+            "systemd-run" => Ok(Backend::SystemdRun),
+            "none" => Ok(Backend::None),
+            _ => Err(format!(
+                "expected `bwrap`, `systemd-run` or `none`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// What action scripts are allowed to do. Network, binds, tmpfs and uid only
+/// apply to the bwrap backend.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub network: bool,
+    /// Paths bound read-only, on top of the Nix store
+    pub binds: Vec<String>,
+    /// The size of the tmpfs mounted on `/tmp`, in bytes
+    pub tmpfs_size: Option<u64>,
+    /// The uid and gid of the script inside of the sandbox
+    pub uid: Option<u32>,
+    /// The memory limit of the cgroup of the script, in bytes
+    pub memory_max: Option<u64>,
+    /// The CPU time the script can use, in percent of one CPU
+    pub cpu_quota: Option<u32>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            network: true,
+            binds: Vec::new(),
+            tmpfs_size: None,
+            uid: None,
+            memory_max: None,
+            cpu_quota: None,
+        }
+    }
+}
+
+/// Restrictions of a project on the sandbox of its actions, declared with
+/// `typhonProject.sandbox`. They can only narrow the policy of the instance.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Narrowing {
+    #[serde(default)]
+    pub network: Option<bool>,
+    /// Only keep these binds of the instance
+    #[serde(default)]
+    pub binds: Option<Vec<String>>,
+    #[serde(default)]
+    pub tmpfs_size: Option<u64>,
+    #[serde(default)]
+    pub memory_max: Option<u64>,
+    #[serde(default)]
+    pub cpu_quota: Option<u32>,
+}
+
+fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Policy {
+    pub fn narrow(&self, narrowing: &Narrowing) -> Self {
+        Self {
+            network: self.network && narrowing.network.unwrap_or(true),
+            binds: match &narrowing.binds {
+                Some(binds) => self
+                    .binds
+                    .iter()
+                    .filter(|bind| binds.contains(bind))
+                    .cloned()
+                    .collect(),
+                None => self.binds.clone(),
+            },
+            tmpfs_size: min(self.tmpfs_size, narrowing.tmpfs_size),
+            uid: self.uid,
+            memory_max: min(self.memory_max, narrowing.memory_max),
+            cpu_quota: min(self.cpu_quota, narrowing.cpu_quota),
+        }
+    }
+
+    fn has_limits(&self) -> bool {
+        self.memory_max.is_some() || self.cpu_quota.is_some()
+    }
+}
+
+/// The sandbox of the instance, set with `init`
+#[derive(Clone, Debug, Default)]
+pub struct Sandbox {
+    pub backend: Backend,
+    pub policy: Policy,
+}
+
+/// A transient scope enforcing the resource limits of `policy`
+fn systemd_run(policy: &Policy) -> Command {
+    let mut command = Command::new("systemd-run");
+    command.args(["--scope", "--quiet", "--collect"]);
+    if let Some(bytes) = policy.memory_max {
+        command.args(["-p", &format!("MemoryMax={}", bytes)]);
+    }
+    if let Some(percent) = policy.cpu_quota {
+        command.args(["-p", &format!("CPUQuota={}%", percent)]);
+    }
+    command.arg("--");
+    command
+}
+
+fn bwrap(policy: &Policy) -> Command {
+    // bubblewrap cannot set cgroup limits, a scope does it
+    let mut command = if policy.has_limits() {
+        let mut command = systemd_run(policy);
+        command.arg("bwrap");
+        command
+    } else {
+        Command::new("bwrap")
+    };
+    command
+        .args(["--proc", "/proc"])
+        .args(["--dev", "/dev"])
+        .args(["--ro-bind", "/nix/store", "/nix/store"])
+        .args(["--ro-bind", "/nix/var/nix", "/nix/var/nix"]);
+    if policy.network {
+        command.args(["--ro-bind", "/etc/resolv.conf", "/etc/resolv.conf"]);
+    } else {
+        command.arg("--unshare-net");
+    }
+    for bind in policy.binds.iter() {
+        command.args(["--ro-bind", bind, bind]);
+    }
+    if let Some(bytes) = policy.tmpfs_size {
+        command.args(["--size", &bytes.to_string()]);
+    }
+    command.args(["--tmpfs", "/tmp"]);
+    if let Some(uid) = policy.uid {
+        command
+            .arg("--unshare-user")
+            .args(["--uid", &uid.to_string()])
+            .args(["--gid", &uid.to_string()]);
+    }
+    command
+        .arg("--clearenv")
+        .arg("--die-with-parent")
+        .arg("--unshare-pid");
+    command
+}
+
+impl Sandbox {
+    /// The command running `script` in the sandbox, with the policy of the
+    /// instance narrowed by the project
+    pub fn command(&self, narrowing: &Narrowing, script: &str) -> Command {
+        let policy = self.policy.narrow(narrowing);
+        let mut command = match self.backend {
+            Backend::Bwrap => {
+                let mut command = bwrap(&policy);
+                command.arg(script);
+                command
+            }
+            Backend::SystemdRun => {
+                let mut command = systemd_run(&policy);
+                command.args(["env", "-i", script]);
+                command
+            }
+            Backend::None => {
+                let mut command = Command::new(script);
+                command.env_clear();
+                command
+            }
+        };
+        command.kill_on_drop(true);
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy {
+            network: true,
+            binds: vec!["/etc/ssl".into(), "/var/lib/secrets".into()],
+            tmpfs_size: Some(1 << 20),
+            uid: Some(1000),
+            memory_max: None,
+            cpu_quota: Some(100),
+        }
+    }
+
+    #[test]
+    fn unchanged() {
+        let narrowed = policy().narrow(&Narrowing::default());
+        assert!(narrowed.network);
+        assert_eq!(narrowed.binds, policy().binds);
+        assert_eq!(narrowed.tmpfs_size, Some(1 << 20));
+        assert_eq!(narrowed.uid, Some(1000));
+        assert_eq!(narrowed.memory_max, None);
+        assert_eq!(narrowed.cpu_quota, Some(100));
+    }
+
+    #[test]
+    fn narrowed() {
+        let narrowing = Narrowing {
+            network: Some(false),
+            binds: Some(vec!["/etc/ssl".into(), "/home".into()]),
+            tmpfs_size: Some(1 << 10),
+            memory_max: Some(1 << 30),
+            cpu_quota: Some(50),
+        };
+        let narrowed = policy().narrow(&narrowing);
+        assert!(!narrowed.network);
+        assert_eq!(narrowed.binds, vec!["/etc/ssl".to_string()]);
+        assert_eq!(narrowed.tmpfs_size, Some(1 << 10));
+        assert_eq!(narrowed.memory_max, Some(1 << 30));
+        assert_eq!(narrowed.cpu_quota, Some(50));
+    }
+
+    #[test]
+    fn not_widened() {
+        let narrowing = Narrowing {
+            network: Some(true),
+            binds: None,
+            tmpfs_size: Some(1 << 30),
+            memory_max: None,
+            cpu_quota: Some(400),
+        };
+        let policy = Policy {
+            network: false,
+            ..policy()
+        };
+        let narrowed = policy.narrow(&narrowing);
+        assert!(!narrowed.network);
+        assert_eq!(narrowed.binds, policy.binds);
+        assert_eq!(narrowed.tmpfs_size, Some(1 << 20));
+        assert_eq!(narrowed.cpu_quota, Some(100));
+    }
+}
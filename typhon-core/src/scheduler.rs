@@ -0,0 +1,279 @@
+use crate::error::Error;
+use crate::jobsets;
+use crate::models;
+use crate::schema;
+use crate::Conn;
+use crate::POOL;
+use crate::RUNTIME;
+
+use typhon_types::data::Schedule;
+
+use diesel::prelude::*;
+use time::OffsetDateTime;
+use tokio::sync::watch;
+
+use std::collections::HashMap;
+
+/// A cron expression with five fields: minute, hour, day of month, month and
+/// day of week. Each field is a comma-separated list of `*`, `n` or `n-m`,
+/// optionally followed by a step `/k`.
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // when both days and weekdays are restricted, either of them matches
+    any_day: bool,
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                Some(
+                    step.parse::<u64>()
+                        .ok()
+                        .filter(|step| *step > 0)
+                        .ok_or(format!("bad step {}", step))?,
+                ),
+            ),
+            None => (part, None),
+        };
+        let parse = |n: &str| {
+            n.parse::<u64>()
+                .ok()
+                .filter(|n| min <= *n && *n <= max)
+                .ok_or(format!("{} is not between {} and {}", n, min, max))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None if step.is_some() => (parse(range)?, max),
+            None => (parse(range)?, parse(range)?),
+        };
+        if start > end {
+            return Err(format!("empty range {}", range));
+        }
+        for n in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Ok(mask)
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("expected 5 fields, got {}", fields.len()));
+        };
+        let mut weekdays_mask = parse_field(weekdays, 0, 7)?;
+        // both 0 and 7 are Sunday
+        if weekdays_mask & (1 << 7) != 0 {
+            weekdays_mask |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekdays_mask,
+            any_day: days != "*" && weekdays != "*",
+        })
+    }
+
+    pub fn matches(&self, time: OffsetDateTime) -> bool {
+        let bit = |mask: u64, n: u8| mask & (1 << n) != 0;
+        let day = bit(self.days, time.day());
+        let weekday = bit(self.weekdays, time.weekday().number_days_from_sunday());
+        bit(self.minutes, time.minute())
+            && bit(self.hours, time.hour())
+            && bit(self.months, time.month() as u8)
+            && if self.any_day {
+                day || weekday
+            } else {
+                day && weekday
+            }
+    }
+}
+
+/// The time of the latest evaluation of a jobset, so that schedules survive
+/// restarts.
+fn last_evaluation(
+    conn: &mut Conn,
+    jobset: &models::Jobset,
+) -> Result<Option<OffsetDateTime>, Error> {
+    let time_created: Option<i64> = schema::evaluations::table
+        .filter(schema::evaluations::project_id.eq(jobset.project_id))
+        .filter(schema::evaluations::jobset_name.eq(&jobset.name))
+        .select(diesel::dsl::max(schema::evaluations::time_created))
+        .first(conn)?;
+    Ok(time_created
+        .map(OffsetDateTime::from_unix_timestamp)
+        .transpose()?)
+}
+
+/// Evaluates the jobsets that are due, and returns the new times of the last
+/// evaluation of each scheduled jobset.
+fn tick(
+    conn: &mut Conn,
+    last: HashMap<i32, OffsetDateTime>,
+    now: OffsetDateTime,
+) -> Result<HashMap<i32, OffsetDateTime>, Error> {
+    let scheduled: Vec<(models::Jobset, models::Project)> = schema::jobsets::table
+        .inner_join(schema::projects::table)
+        .filter(schema::jobsets::schedule.is_not_null())
+        .load(conn)?;
+    let mut next = HashMap::new();
+    for (jobset, project) in scheduled {
+        let jobset = jobsets::Jobset { jobset, project };
+        let previous = match last.get(&jobset.jobset.id) {
+            Some(previous) => Some(*previous),
+            None => last_evaluation(conn, &jobset.jobset)?,
+        };
+        let due = match jobset.schedule() {
+            Some(Schedule::Interval(secs)) => previous.map_or(true, |previous| {
+                (now - previous).whole_seconds() >= secs as i64
+            }),
+            Some(Schedule::Cron(expr)) => {
+                Cron::parse(&expr).is_ok_and(|cron| cron.matches(now))
+                    && previous.map_or(true, |previous| (now - previous).whole_seconds() >= 60)
+            }
+            None => false,
+        };
+        if !due {
+            if let Some(previous) = previous {
+                next.insert(jobset.jobset.id, previous);
+            }
+            continue;
+        }
+        next.insert(jobset.jobset.id, now);
+        // the evaluation is only created if the locked URL changed
+        if let Err(e) = jobset.evaluate(conn, false) {
+            tracing::warn!(
+                "scheduled evaluation of jobset {} failed: {}",
+                jobset.handle(),
+                e
+            );
+        }
+    }
+    Ok(next)
+}
+
+/// Periodically evaluates the jobsets that have a schedule.
+pub struct Scheduler {
+    shutdown: watch::Sender<bool>,
+    watch: watch::Receiver<()>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        let (shutdown, mut shutdown_recv) = watch::channel(false);
+        let (watch_send, watch) = watch::channel(());
+        RUNTIME.spawn(async move {
+            // ticks are shorter than a minute so that cron schedules never
+            // skip a minute
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(20));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut last = HashMap::new();
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => (),
+                    _ = shutdown_recv.changed() => break,
+                }
+                let now = OffsetDateTime::now_utc().replace_second(0).unwrap();
+                let previous = last.clone();
+                let res = RUNTIME
+                    .spawn_blocking(move || tick(&mut POOL.get().unwrap(), previous, now))
+                    .await;
+                match res {
+                    Ok(Ok(next)) => last = next,
+                    Ok(Err(e)) => tracing::error!("scheduler raised error: {:?}", e),
+                    Err(e) => tracing::error!("scheduler panicked: {:?}", e),
+                }
+            }
+            let _watch_send = watch_send;
+        });
+        Self { shutdown, watch }
+    }
+
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        while self.watch.clone().changed().await.is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(timestamp: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    // Monday 2024-03-04 12:30 UTC
+    const MONDAY: i64 = 1709555400;
+    // Sunday 2024-03-10 00:00 UTC
+    const SUNDAY: i64 = 1710028800;
+    // Friday 2024-03-15 09:00 UTC
+    const FRIDAY: i64 = 1710493200;
+
+    #[test]
+    fn fields() {
+        assert_eq!(parse_field("*", 0, 3), Ok(0b1111));
+        assert_eq!(parse_field("2", 0, 59), Ok(1 << 2));
+        assert_eq!(parse_field("1-3", 0, 59), Ok(0b1110));
+        assert_eq!(parse_field("1,5", 0, 59), Ok(0b100010));
+        assert_eq!(parse_field("*/2", 0, 5), Ok(0b010101));
+        assert_eq!(parse_field("1/2", 0, 5), Ok(0b101010));
+        assert_eq!(parse_field("0-4/3", 0, 59), Ok(0b1001));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_field("60", 0, 59).is_err());
+        assert!(parse_field("0", 1, 31).is_err());
+        assert!(parse_field("3-1", 0, 59).is_err());
+        assert!(parse_field("*/0", 0, 59).is_err());
+        assert!(parse_field("a", 0, 59).is_err());
+        assert!(parse_field("", 0, 59).is_err());
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("* * * * * *").is_err());
+    }
+
+    #[test]
+    fn matches() {
+        let cron = Cron::parse("30 12 * * *").unwrap();
+        assert!(cron.matches(time(MONDAY)));
+        assert!(!cron.matches(time(MONDAY + 60)));
+        let cron = Cron::parse("*/15 * * 3 1-5").unwrap();
+        assert!(cron.matches(time(MONDAY)));
+        assert!(cron.matches(time(FRIDAY)));
+        assert!(!cron.matches(time(SUNDAY)));
+        assert!(!cron.matches(time(MONDAY + 60)));
+    }
+
+    #[test]
+    fn sunday() {
+        let cron = Cron::parse("0 0 * * 7").unwrap();
+        assert!(cron.matches(time(SUNDAY)));
+        let cron = Cron::parse("0 0 * * 0").unwrap();
+        assert!(cron.matches(time(SUNDAY)));
+    }
+
+    #[test]
+    fn days_or_weekdays() {
+        // the 15th or any Sunday
+        let cron = Cron::parse("0 * 15 * 0").unwrap();
+        assert!(cron.matches(time(SUNDAY)));
+        assert!(cron.matches(time(FRIDAY)));
+        assert!(!cron.matches(time(MONDAY - 30 * 60)));
+        // only the 15th
+        let cron = Cron::parse("0 * 15 * *").unwrap();
+        assert!(!cron.matches(time(SUNDAY)));
+        assert!(cron.matches(time(FRIDAY)));
+    }
+}
@@ -2,9 +2,12 @@
 
 diesel::table! {
     actions (id) {
+        exit_code -> Nullable<Integer>,
         id -> Integer,
         input -> Text,
         name -> Text,
+        output -> Nullable<Text>,
+        output_error -> Nullable<Text>,
         path -> Text,
         project_id -> Integer,
         task_id -> Integer,
@@ -30,6 +33,7 @@ diesel::table! {
         id -> Integer,
         jobset_name -> Text,
         project_id -> Integer,
+        retry -> Nullable<Text>,
         task_id -> Integer,
         time_created -> BigInt,
         url -> Text,
@@ -37,15 +41,27 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    events (id) {
+        data -> Text,
+        id -> BigInt,
+        time_created -> BigInt,
+    }
+}
+
 diesel::table! {
     jobs (id) {
         dist -> Bool,
         drv -> Text,
+        error -> Nullable<Text>,
         evaluation_id -> Integer,
         id -> Integer,
         name -> Text,
         out -> Text,
+        retry -> Nullable<Text>,
+        retry_at -> Nullable<BigInt>,
         system -> Text,
+        timeout -> Nullable<Text>,
         tries -> Integer,
     }
 }
@@ -56,6 +72,8 @@ diesel::table! {
         id -> Integer,
         name -> Text,
         project_id -> Integer,
+        retry -> Nullable<Text>,
+        schedule -> Nullable<Text>,
         url -> Text,
     }
 }
@@ -63,6 +81,8 @@ diesel::table! {
 diesel::table! {
     logs (id) {
         id -> Integer,
+        path -> Nullable<Text>,
+        size -> Nullable<BigInt>,
         stderr -> Nullable<Text>,
     }
 }
@@ -75,14 +95,26 @@ diesel::table! {
         homepage -> Text,
         id -> Integer,
         key -> Text,
+        last_refresh_build_id -> Nullable<Integer>,
         last_refresh_task_id -> Nullable<Integer>,
         name -> Text,
+        reporters -> Text,
+        sandbox -> Nullable<Text>,
         title -> Text,
         url -> Text,
         url_locked -> Text,
     }
 }
 
+diesel::table! {
+    roles (id) {
+        id -> Integer,
+        project_id -> Integer,
+        role -> Integer,
+        user_id -> Integer,
+    }
+}
+
 diesel::table! {
     runs (id) {
         begin_id -> Nullable<Integer>,
@@ -91,6 +123,7 @@ diesel::table! {
         id -> Integer,
         job_id -> Integer,
         num -> Integer,
+        retry_reason -> Nullable<Text>,
         time_created -> BigInt,
     }
 }
@@ -105,6 +138,27 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    tokens (id) {
+        expiration -> Nullable<BigInt>,
+        hash -> Text,
+        id -> Integer,
+        name -> Text,
+        project_id -> Nullable<Integer>,
+        role -> Nullable<Integer>,
+        user_id -> Integer,
+    }
+}
+
+diesel::table! {
+    users (id) {
+        admin -> Bool,
+        id -> Integer,
+        name -> Text,
+        password -> Text,
+    }
+}
+
 diesel::joinable!(actions -> projects (project_id));
 diesel::joinable!(actions -> tasks (task_id));
 diesel::joinable!(builds -> tasks (task_id));
@@ -112,19 +166,28 @@ diesel::joinable!(evaluations -> projects (project_id));
 diesel::joinable!(evaluations -> tasks (task_id));
 diesel::joinable!(jobs -> evaluations (evaluation_id));
 diesel::joinable!(jobsets -> projects (project_id));
+diesel::joinable!(projects -> builds (last_refresh_build_id));
 diesel::joinable!(projects -> tasks (last_refresh_task_id));
+diesel::joinable!(roles -> projects (project_id));
+diesel::joinable!(roles -> users (user_id));
 diesel::joinable!(runs -> builds (build_id));
 diesel::joinable!(runs -> jobs (job_id));
 diesel::joinable!(tasks -> logs (log_id));
+diesel::joinable!(tokens -> projects (project_id));
+diesel::joinable!(tokens -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     actions,
     builds,
     evaluations,
+    events,
     jobs,
     jobsets,
     logs,
     projects,
+    roles,
     runs,
     tasks,
+    tokens,
+    users,
 );
@@ -1,18 +1,21 @@
 use crate::error::Error;
 use crate::log_event;
+use crate::logs;
 use crate::models;
+use crate::queue;
 use crate::schema;
 use crate::Conn;
 use crate::POOL;
-use crate::{LOGS, TASKS};
+use crate::{LOGS, QUEUE, RUNTIME, TASKS};
 
-use typhon_types::data::TaskStatusKind;
+use typhon_types::data::{LogRange, TaskStatusKind};
 use typhon_types::responses::TaskStatus;
 use typhon_types::Event;
 
 use diesel::prelude::*;
 use futures_core::stream::Stream;
 use std::future::Future;
+use std::sync::{Arc, Mutex};
 use time::OffsetDateTime;
 use tokio::sync::mpsc;
 
@@ -21,6 +24,37 @@ pub struct Task {
     pub task: models::Task,
 }
 
+/// Marks the start of a task. Queued tasks stay pending without a start time
+/// until they enter the gate, other tasks are started right away.
+pub struct Gate {
+    id: i32,
+    start: Arc<Mutex<Option<OffsetDateTime>>>,
+    ticket: Option<(queue::Kind, queue::Priority)>,
+}
+
+impl Gate {
+    /// Waits for a slot in the queue and marks the task as started. The slot
+    /// must be kept until the task is done.
+    pub async fn enter(self, system: Option<String>) -> Option<queue::Slot> {
+        let (kind, priority) = self.ticket?;
+        let slot = QUEUE.acquire(kind, system, priority).await;
+        let start = OffsetDateTime::now_utc();
+        *self.start.lock().unwrap() = Some(start);
+        let id = self.id;
+        // only the start time is written, so that a late update cannot
+        // overwrite the status set by the finisher
+        let _ = RUNTIME
+            .spawn_blocking(move || {
+                let mut conn = POOL.get().unwrap();
+                diesel::update(schema::tasks::table.find(id))
+                    .set(schema::tasks::time_started.eq(start.unix_timestamp()))
+                    .execute(&mut conn)
+            })
+            .await;
+        Some(slot)
+    }
+}
+
 impl models::Task {
     pub fn status_kind(&self) -> TaskStatusKind {
         self.status.try_into().unwrap()
@@ -39,25 +73,54 @@ impl Task {
         TASKS.cancel(self.task.id);
     }
 
-    pub fn log(&self, conn: &mut Conn) -> Result<Option<impl Stream<Item = String>>, Error> {
-        let stream = LOGS.listen(&self.task.id);
-        let stderr = schema::logs::dsl::logs
+    pub fn log(
+        &self,
+        conn: &mut Conn,
+        range: LogRange,
+    ) -> Result<Option<impl Stream<Item = String>>, Error> {
+        let tail = match range {
+            LogRange::Tail(n) => Some(n),
+            _ => None,
+        };
+        let stream = LOGS.listen(&self.task.id, tail);
+        let log = schema::logs::dsl::logs
             .find(self.task.log_id)
-            .select(schema::logs::stderr)
-            .first::<Option<String>>(conn)?;
+            .first::<models::Log>(conn)?;
         Ok(Some(async_stream::stream! {
             if let Some(stream) = stream {
-                for await line in stream {
+                for await line in logs::range::select_stream(stream, range) {
                     yield line;
                 }
-            } else if let Some(stderr) = stderr {
-                for line in stderr.split('\n') {
-                    yield line.to_string();
+            } else if let Some(path) = log.path {
+                for await line in logs::files::read(&path, range) {
+                    yield line;
+                }
+            } else if let Some(stderr) = log.stderr {
+                for line in logs::range::select(stderr.split('\n').map(String::from), range) {
+                    yield line;
                 }
             }
         }))
     }
 
+    /// The number of lines of the log
+    pub fn log_lines(&self, conn: &mut Conn) -> Result<u64, Error> {
+        if let Some(lines) = LOGS.lines(&self.task.id) {
+            return Ok(lines);
+        }
+        let log = schema::logs::dsl::logs
+            .find(self.task.log_id)
+            .first::<models::Log>(conn)?;
+        Ok(match (log.path, log.stderr) {
+            (Some(path), _) => logs::files::lines(&path).unwrap_or_else(|e| {
+                tracing::error!("failed to count the lines of log {}: {}", path, e);
+                0
+            }),
+            (None, Some(stderr)) => stderr.split('\n').count() as u64,
+            (None, None) => 0,
+        })
+    }
+
     pub fn new(conn: &mut Conn) -> Result<Self, Error> {
         let log = diesel::insert_into(schema::logs::dsl::logs)
             .values(models::NewLog { stderr: None })
@@ -72,26 +135,47 @@ impl Task {
         Ok(Task { task })
     }
 
+    /// Runs the task. When `ticket` is given, the task is queued: `run` must
+    /// enter the gate it is given before doing any actual work.
     pub fn run<
         T: Send + 'static,
         O: Future<Output = T> + Send + 'static,
-        F: (FnOnce(mpsc::UnboundedSender<String>) -> O) + Send + 'static,
+        F: (FnOnce(mpsc::UnboundedSender<String>, Gate) -> O) + Send + 'static,
         G: (FnOnce(Option<T>) -> (TaskStatusKind, Event)) + Send + Sync + 'static,
     >(
         &self,
         conn: &mut Conn,
+        ticket: Option<(queue::Kind, queue::Priority)>,
         run: F,
         finish: G,
     ) -> Result<(), Error> {
-        let start = Some(OffsetDateTime::now_utc());
+        let start = Arc::new(Mutex::new(match ticket {
+            Some(_) => None,
+            None => Some(OffsetDateTime::now_utc()),
+        }));
         let id = self.task.id;
 
-        self.set_status(conn, TaskStatus::Pending { start })?;
+        self.set_status(
+            conn,
+            TaskStatus::Pending {
+                start: *start.lock().unwrap(),
+            },
+        )?;
 
+        let gate = Gate {
+            id,
+            start: start.clone(),
+            ticket,
+        };
+        // the spool is compressed by the finisher, even if the task is
+        // canceled, and by the recovery if the server stops first
+        let spool = logs::files::Spool::create(self.task.log_id)
+            .map_err(|e| tracing::error!("failed to create log spool: {}", e))
+            .ok();
+        LOGS.init(&id, spool);
         let (sender, mut receiver) = mpsc::unbounded_channel();
         let run = async move {
-            LOGS.init(&id);
-            let (res, ()) = tokio::join!(run(sender), async move {
+            let (res, ()) = tokio::join!(run(sender, gate), async move {
                 while let Some(line) = receiver.recv().await {
                     LOGS.send_line(&id, line);
                 }
@@ -104,13 +188,29 @@ impl Task {
                 let mut conn = POOL.get().unwrap();
                 let (status_kind, event) = finish(res);
                 let time_finished = OffsetDateTime::now_utc();
-                let stderr = LOGS.remove(&id).unwrap_or(String::new()); // FIXME
+                let file = LOGS.remove(&id).map(|spool| spool.finish());
+                let start = match (*start.lock().unwrap(), status_kind) {
+                    (None, TaskStatusKind::Canceled) => None,
+                    // the task ended before getting a slot
+                    (start, _) => start.or(Some(time_finished)),
+                };
                 let status = status_kind.into_task_status(start, Some(time_finished));
                 task.set_status(&mut conn, status).unwrap();
-                diesel::update(schema::logs::table.filter(schema::logs::id.eq(task.task.log_id)))
-                    .set(schema::logs::stderr.eq(stderr))
-                    .execute(&mut conn)
-                    .unwrap(); // TODO: handle error properly
+                match file {
+                    Some(Ok((path, size))) => {
+                        diesel::update(
+                            schema::logs::table.filter(schema::logs::id.eq(task.task.log_id)),
+                        )
+                        .set((
+                            schema::logs::path.eq(path),
+                            schema::logs::size.eq(size as i64),
+                        ))
+                        .execute(&mut conn)
+                        .unwrap(); // TODO: handle error properly
+                    }
+                    Some(Err(e)) => tracing::error!("failed to write log of task {}: {}", id, e),
+                    None => (),
+                }
                 log_event(event);
                 None::<()>
             }
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use std::future::Future;
+use std::io;
+
+/// Limits on the running time of a task, in seconds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timeout {
+    /// The maximum running time
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// The maximum time without any output
+    #[serde(default)]
+    pub max_silent_time: Option<u64>,
+}
+
+impl Timeout {
+    /// Takes the limits of `self`, falling back to `default` for the ones
+    /// that are not set
+    pub fn or(self, default: Self) -> Self {
+        Self {
+            timeout: self.timeout.or(default.timeout),
+            max_silent_time: self.max_silent_time.or(default.max_silent_time),
+        }
+    }
+}
+
+/// The default limits of builds and actions. The limits of a build can be
+/// overridden by its job with `passthru.typhonTimeout`.
+#[derive(Clone, Debug, Default)]
+pub struct Timeouts {
+    pub actions: Timeout,
+    pub builds: Timeout,
+}
+
+#[derive(Debug)]
+pub struct TimedOut;
+
+async fn sleep(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The process group of a spawned command. `kill_on_drop` only kills the
+/// direct child, so the whole group is killed when this is dropped before
+/// `release`, e.g. when its task times out or is canceled.
+pub struct ProcessGroup(Option<i32>);
+
+impl ProcessGroup {
+    /// Spawns `command` as the leader of a new process group
+    pub fn spawn(command: &mut Command) -> io::Result<(Child, Self)> {
+        let child = command.process_group(0).spawn()?;
+        let pgid = child.id().map(|id| id as i32);
+        Ok((child, Self(pgid)))
+    }
+
+    /// Leaves the group alone, once its leader exited by itself
+    pub fn release(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        // the leader is not reaped yet, so its id cannot have been reused
+        if let Some(pgid) = self.0 {
+            unsafe {
+                libc::killpg(pgid, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Runs the future returned by `task`, forwarding its logs to `sender`. The
+/// future is dropped when it exceeds one of the limits of `timeout`, which
+/// kills the process groups it spawned.
+pub async fn run<T, F, Fut>(
+    timeout: Timeout,
+    sender: mpsc::UnboundedSender<String>,
+    task: F,
+) -> Result<T, TimedOut>
+where
+    F: FnOnce(mpsc::UnboundedSender<String>) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let (logs_send, mut logs_recv) = mpsc::unbounded_channel();
+    let future = task(logs_send);
+    tokio::pin!(future);
+    let start = Instant::now();
+    let deadline = timeout
+        .timeout
+        .map(|secs| start + Duration::from_secs(secs));
+    let mut last_output = start;
+    loop {
+        let silence_deadline = timeout
+            .max_silent_time
+            .map(|secs| last_output + Duration::from_secs(secs));
+        let next_deadline = deadline.into_iter().chain(silence_deadline).min();
+        tokio::select! {
+            res = &mut future => {
+                while let Ok(line) = logs_recv.try_recv() {
+                    let _ = sender.send(line);
+                }
+                return Ok(res);
+            }
+            Some(line) = logs_recv.recv() => {
+                last_output = Instant::now();
+                let _ = sender.send(line);
+            }
+            _ = sleep(next_deadline) => {
+                let reason = match deadline {
+                    Some(deadline) if deadline <= Instant::now() => "timeout",
+                    _ => "maximum silent time",
+                };
+                let _ = sender.send(format!("typhon: killed after exceeding its {}", reason));
+                return Err(TimedOut);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn lines(mut receiver: mpsc::UnboundedReceiver<String>) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(line) = receiver.recv().await {
+            lines.push(line);
+        }
+        lines
+    }
+
+    // the clock advances by itself while the tasks sleep
+    #[tokio::test(start_paused = true)]
+    async fn finished() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let timeout = Timeout {
+            timeout: Some(10),
+            max_silent_time: Some(10),
+        };
+        let res = run(timeout, sender, |logs| async move {
+            let _ = logs.send("building".to_string());
+            42
+        })
+        .await;
+        assert_eq!(res.ok(), Some(42));
+        assert_eq!(lines(receiver).await, vec!["building"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let timeout = Timeout {
+            timeout: Some(1),
+            max_silent_time: None,
+        };
+        // the logs keep the task from being silent
+        let res = run(timeout, sender, |logs| async move {
+            loop {
+                let _ = logs.send("building".to_string());
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+        assert!(res.is_err());
+        let lines = lines(receiver).await;
+        assert_eq!(
+            lines.last().unwrap(),
+            "typhon: killed after exceeding its timeout"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let timeout = Timeout {
+            timeout: None,
+            max_silent_time: Some(1),
+        };
+        let res = run(timeout, sender, |logs| async move {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let _ = logs.send("building".to_string());
+            }
+            std::future::pending::<()>().await
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(
+            lines(receiver).await,
+            vec![
+                "building",
+                "building",
+                "building",
+                "typhon: killed after exceeding its maximum silent time"
+            ]
+        );
+    }
+
+    /// Whether process `pid` is running, a zombie is not
+    fn alive(pid: &str) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            // the state follows the name of the command, in parentheses
+            Ok(stat) => !stat
+                .rsplit_once(") ")
+                .is_some_and(|(_, rest)| rest.starts_with('Z')),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn process_group() {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "sleep 60 & echo $!; wait"])
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true);
+        let (mut child, group) = ProcessGroup::spawn(&mut command).unwrap();
+        let stdout = tokio::io::BufReader::new(child.stdout.take().unwrap());
+        let pid = stdout.lines().next_line().await.unwrap().unwrap();
+        assert!(alive(&pid));
+        // the background process is not a child of ours
+        drop(group);
+        drop(child);
+        for _ in 0..50 {
+            if !alive(&pid) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("process {} survived its group", pid);
+    }
+}
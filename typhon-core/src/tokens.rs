@@ -0,0 +1,143 @@
+use crate::error::Error;
+use crate::models;
+use crate::schema;
+use crate::users;
+use crate::Conn;
+
+use typhon_types::*;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use diesel::prelude::*;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+/// Prefix of the secrets given to clients, to make them easy to recognize.
+const PREFIX: &str = "typhon_";
+
+#[derive(Clone)]
+pub struct Token {
+    pub token: models::Token,
+    pub project: Option<models::Project>,
+}
+
+// Tokens are random and long enough that a fast hash is sufficient, which
+// lets us look them up by their hash.
+fn hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+impl Token {
+    /// Finds the token matching a secret, if it exists and has not expired.
+    pub fn authenticate(conn: &mut Conn, secret: &str) -> Result<Option<Self>, Error> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let token = schema::tokens::table
+            .left_join(schema::projects::table)
+            .filter(schema::tokens::hash.eq(hash(secret)))
+            .filter(
+                schema::tokens::expiration
+                    .is_null()
+                    .or(schema::tokens::expiration.assume_not_null().gt(now)),
+            )
+            .select((
+                models::Token::as_select(),
+                Option::<models::Project>::as_select(),
+            ))
+            .first(conn)
+            .optional()?;
+        Ok(token.map(|(token, project)| Self { token, project }))
+    }
+
+    /// Creates a token and returns its secret, which is not stored and
+    /// cannot be retrieved afterwards.
+    pub fn create(
+        conn: &mut Conn,
+        user: &users::User,
+        name: &String,
+        decl: &requests::TokenDecl,
+    ) -> Result<String, Error> {
+        if Self::get(conn, user, name).is_ok() {
+            return Err(Error::TokenAlreadyExists(name.clone()));
+        }
+        let project_id = match &decl.scope.project {
+            Some(handle) => Some(crate::projects::Project::get(conn, handle)?.project.id),
+            None => None,
+        };
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let secret = format!("{}{}", PREFIX, hex::encode(bytes));
+        let new_token = models::NewToken {
+            expiration: decl.expiration.map(|time| time.unix_timestamp()),
+            hash: &hash(&secret),
+            name,
+            project_id,
+            role: decl.scope.role.map(i32::from),
+            user_id: user.user.id,
+        };
+        diesel::insert_into(schema::tokens::table)
+            .values(&new_token)
+            .execute(conn)?;
+        Ok(secret)
+    }
+
+    pub fn get(conn: &mut Conn, user: &users::User, name: &String) -> Result<Self, Error> {
+        let (token, project) = schema::tokens::table
+            .left_join(schema::projects::table)
+            .filter(schema::tokens::user_id.eq(user.user.id))
+            .filter(schema::tokens::name.eq(name))
+            .select((
+                models::Token::as_select(),
+                Option::<models::Project>::as_select(),
+            ))
+            .first(conn)
+            .optional()?
+            .ok_or(Error::TokenNotFound(name.clone()))?;
+        Ok(Self { token, project })
+    }
+
+    pub fn info(&self) -> Result<data::Token, Error> {
+        Ok(data::Token {
+            name: self.token.name.clone(),
+            expiration: self
+                .token
+                .expiration
+                .map(OffsetDateTime::from_unix_timestamp)
+                .transpose()?,
+            scope: self.scope(),
+        })
+    }
+
+    pub fn list(conn: &mut Conn, user: &users::User) -> Result<Vec<data::Token>, Error> {
+        schema::tokens::table
+            .left_join(schema::projects::table)
+            .filter(schema::tokens::user_id.eq(user.user.id))
+            .order(schema::tokens::name.asc())
+            .select((
+                models::Token::as_select(),
+                Option::<models::Project>::as_select(),
+            ))
+            .load::<(models::Token, Option<models::Project>)>(conn)?
+            .into_iter()
+            .map(|(token, project)| Self { token, project }.info())
+            .collect()
+    }
+
+    pub fn revoke(&self, conn: &mut Conn) -> Result<(), Error> {
+        diesel::delete(&self.token).execute(conn)?;
+        Ok(())
+    }
+
+    pub fn scope(&self) -> data::TokenScope {
+        data::TokenScope {
+            project: self
+                .project
+                .as_ref()
+                .map(|project| handles::project(project.name.clone())),
+            role: self.token.role.and_then(|role| role.try_into().ok()),
+        }
+    }
+
+    pub fn user(&self, conn: &mut Conn) -> Result<users::User, Error> {
+        let user = schema::users::table.find(self.token.user_id).first(conn)?;
+        Ok(users::User { user })
+    }
+}
@@ -0,0 +1,142 @@
+use crate::error::Error;
+use crate::models;
+use crate::schema;
+use crate::Conn;
+use crate::{log_event, Event};
+
+use typhon_types::*;
+
+use argon2::password_hash::{rand_core::OsRng, SaltString};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use diesel::prelude::*;
+
+#[derive(Clone)]
+pub struct User {
+    pub user: models::User,
+}
+
+fn hash_password(password: &String) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|_| Error::Todo)?
+        .to_string())
+}
+
+impl User {
+    pub fn create(conn: &mut Conn, name: &String, decl: &requests::UserDecl) -> Result<(), Error> {
+        match Self::get(conn, name) {
+            Ok(_) => Err(Error::UserAlreadyExists(name.clone())),
+            Err(_) => {
+                let password = hash_password(&decl.password)?;
+                let new_user = models::NewUser {
+                    admin: decl.admin,
+                    name,
+                    password: &password,
+                };
+                diesel::insert_into(schema::users::table)
+                    .values(&new_user)
+                    .execute(conn)?;
+                log_event(Event::UserUpdated(name.clone()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Creates or updates the administrator account from the password hash
+    /// given on the command line.
+    pub fn init_admin(conn: &mut Conn, password: &str) -> Result<(), Error> {
+        let new_user = models::NewUser {
+            admin: true,
+            name: "admin",
+            password,
+        };
+        // upserts are not available for all backends
+        conn.transaction::<(), Error, _>(|conn| {
+            let updated = diesel::update(schema::users::table)
+                .filter(schema::users::name.eq(new_user.name))
+                .set((
+                    schema::users::admin.eq(true),
+                    schema::users::password.eq(password),
+                ))
+                .execute(conn)?;
+            if updated == 0 {
+                diesel::insert_into(schema::users::table)
+                    .values(&new_user)
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn delete(&self, conn: &mut Conn) -> Result<(), Error> {
+        conn.transaction::<(), Error, _>(|conn| {
+            diesel::delete(schema::roles::table.filter(schema::roles::user_id.eq(self.user.id)))
+                .execute(conn)?;
+            diesel::delete(schema::tokens::table.filter(schema::tokens::user_id.eq(self.user.id)))
+                .execute(conn)?;
+            diesel::delete(&self.user).execute(conn)?;
+            Ok(())
+        })?;
+        log_event(Event::UserUpdated(self.user.name.clone()));
+        Ok(())
+    }
+
+    pub fn get(conn: &mut Conn, name: &String) -> Result<Self, Error> {
+        let user = schema::users::table
+            .filter(schema::users::name.eq(name))
+            .first(conn)
+            .optional()?
+            .ok_or(Error::UserNotFound(name.clone()))?;
+        Ok(Self { user })
+    }
+
+    pub fn info(&self, conn: &mut Conn) -> Result<data::User, Error> {
+        let roles = schema::roles::table
+            .inner_join(schema::projects::table)
+            .filter(schema::roles::user_id.eq(self.user.id))
+            .select((schema::projects::name, schema::roles::role))
+            .load::<(String, i32)>(conn)?
+            .into_iter()
+            .filter_map(|(name, role)| Some((handles::project(name), role.try_into().ok()?)))
+            .collect();
+        Ok(data::User {
+            name: self.user.name.clone(),
+            admin: self.user.admin,
+            roles,
+        })
+    }
+
+    /// The role of the user on a project, administrators are owners of every
+    /// project.
+    pub fn role(&self, conn: &mut Conn, project_id: i32) -> Result<Option<data::Role>, Error> {
+        if self.user.admin {
+            return Ok(Some(data::Role::Owner));
+        }
+        let role: Option<i32> = schema::roles::table
+            .filter(schema::roles::project_id.eq(project_id))
+            .filter(schema::roles::user_id.eq(self.user.id))
+            .select(schema::roles::role)
+            .first(conn)
+            .optional()?;
+        Ok(role.and_then(|role| role.try_into().ok()))
+    }
+
+    pub fn set_password(&self, conn: &mut Conn, password: &String) -> Result<(), Error> {
+        // it would be overwritten at the next start
+        if self.user.name == "admin" {
+            return Err(Error::AdminPasswordIsFixed);
+        }
+        let password = hash_password(password)?;
+        diesel::update(&self.user)
+            .set(schema::users::password.eq(password))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn verify_password(&self, password: &[u8]) -> bool {
+        PasswordHash::new(&self.user.password)
+            .map(|hash| Argon2::default().verify_password(password, &hash).is_ok())
+            .unwrap_or(false)
+    }
+}
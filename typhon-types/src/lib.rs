@@ -213,12 +213,213 @@ pub mod handles {
     }
 }
 pub mod data {
+    use crate::handles;
     pub use crate::task_status::TaskStatusKind;
     use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    /** The role of a user on a project. Roles are ordered: each role
+     * grants the permissions of the roles below it. */
+    #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    #[repr(u8)]
+    pub enum Role {
+        /** Can see the project */
+        Viewer = 0,
+        /** Can evaluate jobsets, cancel and rerun jobs */
+        Maintainer = 1,
+        /** Can change the declaration, refresh, delete the project and
+         * manage roles */
+        Owner = 2,
+    }
+
+    impl TryFrom<i32> for Role {
+        type Error = ();
+        fn try_from(n: i32) -> Result<Role, ()> {
+            let arr = [Self::Viewer, Self::Maintainer, Self::Owner];
+            arr.get(n as usize).ok_or(()).copied()
+        }
+    }
+    impl From<Role> for i32 {
+        fn from(x: Role) -> i32 {
+            (x as u8) as i32
+        }
+    }
+
+    impl std::fmt::Display for Role {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                Self::Viewer => write!(f, "viewer"),
+                Self::Maintainer => write!(f, "maintainer"),
+                Self::Owner => write!(f, "owner"),
+            }
+        }
+    }
 
+    /** The number of builds and actions running or waiting for a slot. */
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct QueueInfo {
+        pub running_actions: usize,
+        pub running_builds: usize,
+        pub waiting_actions: usize,
+        pub waiting_builds: usize,
+    }
+
+    /** The part of a log to read. Ranges are half-open, and a byte range
+     * selects the lines starting in it. */
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum LogRange {
+        #[default]
+        All,
+        Bytes {
+            start: u64,
+            end: Option<u64>,
+        },
+        Lines {
+            start: u64,
+            end: Option<u64>,
+        },
+        /** The last lines, followed by the new ones if the log is live */
+        Tail(u64),
+    }
+
+    /** What pruning removed, or would remove in a dry run. */
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct PruneInfo {
+        pub actions: usize,
+        pub builds: usize,
+        pub dry_run: bool,
+        pub evaluations: Vec<handles::Evaluation>,
+        pub jobs: usize,
+        pub logs: usize,
+        pub runs: usize,
+    }
+
+    /** When to poll a jobset for changes. */
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Schedule {
+        /** Every given number of seconds */
+        Interval(u64),
+        /** A cron expression with five fields, in UTC */
+        Cron(String),
+    }
+
+    impl std::fmt::Display for Schedule {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                Self::Interval(secs) => write!(f, "every {}s", secs),
+                Self::Cron(expr) => write!(f, "cron \"{}\"", expr),
+            }
+        }
+    }
+
+    /** How the failed runs of a job are retried. */
     #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-    pub enum User {
-        Admin,
+    pub struct RetryPolicy {
+        /** The maximum number of runs of a job, the first one included */
+        pub attempts: u32,
+        /** The delay before the first retry in seconds, it doubles at each
+         * subsequent retry */
+        #[serde(default)]
+        pub backoff: u64,
+    }
+
+    impl RetryPolicy {
+        /** The delay before retrying after the failure of run `num` */
+        pub fn delay(&self, num: u32) -> std::time::Duration {
+            let factor = 1u64 << num.saturating_sub(1).min(16);
+            std::time::Duration::from_secs(self.backoff.saturating_mul(factor))
+        }
+    }
+
+    /** What an API token is allowed to do on behalf of its user. */
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct TokenScope {
+        /** Restricts the token to a single project */
+        pub project: Option<handles::Project>,
+        /** The highest role the token can act with, a token without a role
+         * is read-only */
+        pub role: Option<Role>,
+    }
+
+    impl TokenScope {
+        /** Whether the scope allows an action requiring `role`, on `project`
+         * or outside of any project. Actions outside of projects need a
+         * token with an unrestricted owner scope. */
+        pub fn allows(&self, project: Option<&handles::Project>, role: Role) -> bool {
+            self.role >= Some(role)
+                && match (&self.project, project) {
+                    (None, _) => true,
+                    (Some(p1), Some(p2)) => p1 == p2,
+                    (Some(_), None) => false,
+                }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Token {
+        pub name: String,
+        #[serde(with = "time::serde::timestamp::option")]
+        pub expiration: Option<time::OffsetDateTime>,
+        pub scope: TokenScope,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct User {
+        pub name: String,
+        pub admin: bool,
+        pub roles: HashMap<handles::Project, Role>,
+    }
+
+    impl User {
+        /** The role of the user on a project. Administrators are owners
+         * of every project. */
+        pub fn role(&self, project: &handles::Project) -> Option<Role> {
+            if self.admin {
+                Some(Role::Owner)
+            } else {
+                self.roles.get(project).copied()
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::time::Duration;
+
+        #[test]
+        fn retry_delay() {
+            let policy = RetryPolicy {
+                attempts: 5,
+                backoff: 10,
+            };
+            assert_eq!(policy.delay(1), Duration::from_secs(10));
+            assert_eq!(policy.delay(2), Duration::from_secs(20));
+            assert_eq!(policy.delay(3), Duration::from_secs(40));
+        }
+
+        #[test]
+        fn retry_delay_bounds() {
+            let policy = RetryPolicy {
+                attempts: 100,
+                backoff: 1,
+            };
+            assert_eq!(policy.delay(0), Duration::from_secs(1));
+            assert_eq!(policy.delay(100), Duration::from_secs(1 << 16));
+            let policy = RetryPolicy {
+                attempts: 2,
+                backoff: u64::MAX,
+            };
+            assert_eq!(policy.delay(2), Duration::from_secs(u64::MAX));
+            let policy = RetryPolicy {
+                attempts: 2,
+                backoff: 0,
+            };
+            assert_eq!(policy.delay(1), Duration::ZERO);
+        }
     }
 }
 
@@ -311,22 +512,49 @@ pub mod requests {
 
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub enum Project {
-        //Delete,
+        Delete,
         Info,
         Refresh,
         SetDecl(ProjectDecl),
+        SetRole {
+            user: String,
+            role: Option<crate::data::Role>,
+        },
         UpdateJobsets,
     }
 
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct TokenDecl {
+        #[serde(with = "time::serde::timestamp::option")]
+        pub expiration: Option<time::OffsetDateTime>,
+        pub scope: crate::data::TokenScope,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct UserDecl {
+        pub admin: bool,
+        pub password: String,
+    }
+
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub enum Jobset {
         Evaluate(bool),
         Info,
+        /** The runs of a job across the evaluations of the jobset, from the
+         * most recent */
+        JobHistory {
+            system: String,
+            name: String,
+            limit: u8,
+            offset: u32,
+        },
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub enum Evaluation {
         Cancel,
+        /** Compares the evaluation with a base evaluation */
+        Compare(crate::handles::Evaluation),
         Info,
     }
 
@@ -348,7 +576,7 @@ pub mod requests {
 
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub enum Run {
-        //Cancel,
+        Cancel,
         Info,
     }
 
@@ -363,7 +591,15 @@ pub mod requests {
         Build(handles::Build, Build),
         Action(handles::Action, Action),
         Run(handles::Run, Run),
-        Login { password: String },
+        CreateUser { name: String, decl: UserDecl },
+        DeleteUser { name: String },
+        SetPassword { name: String, password: String },
+        CreateToken { name: String, decl: TokenDecl },
+        RevokeToken { name: String },
+        Tokens,
+        Queue,
+        Prune { dry_run: bool },
+        Login { name: String, password: String },
         User,
     }
 
@@ -389,7 +625,24 @@ pub mod requests {
                 Request::Build(h, req) => write!(f, "{:?} for build {}", req, h),
                 Request::Action(h, req) => write!(f, "{:?} for action {}", req, h),
                 Request::Run(h, req) => write!(f, "{:?} for run {}", req, h),
-                Request::Login { .. } => write!(f, "Log in"),
+                Request::CreateUser { name, decl } => write!(
+                    f,
+                    "Create{} user {}",
+                    if decl.admin { " admin" } else { "" },
+                    name
+                ),
+                Request::DeleteUser { name } => write!(f, "Delete user {}", name),
+                Request::SetPassword { name, .. } => write!(f, "Set password of user {}", name),
+                Request::CreateToken { name, .. } => write!(f, "Create token {}", name),
+                Request::RevokeToken { name } => write!(f, "Revoke token {}", name),
+                Request::Tokens => write!(f, "List tokens"),
+                Request::Queue => write!(f, "Queue info"),
+                Request::Prune { dry_run } => write!(
+                    f,
+                    "Prune old evaluations{}",
+                    if *dry_run { " (dry run)" } else { "" }
+                ),
+                Request::Login { name, .. } => write!(f, "Log in as {}", name),
                 Request::User => write!(f, "Get current user"),
             }
         }
@@ -423,6 +676,8 @@ pub mod responses {
         pub flake: bool,
         pub jobsets: Vec<String>,
         pub last_refresh: Option<TaskStatus>,
+        /** The build of the actions by the last refresh */
+        pub last_refresh_build: Option<handles::Build>,
         pub metadata: ProjectMetadata,
         pub public_key: String,
         pub url: String,
@@ -432,11 +687,39 @@ pub mod responses {
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub struct JobsetInfo {
         pub handle: handles::Jobset,
+        /** The most recent evaluations of the jobset, from the most recent */
+        pub evaluations: Vec<handles::Evaluation>,
         pub flake: bool,
+        /** The jobs of `evaluations`, sorted */
+        pub history: Vec<JobHistory>,
+        pub last_evaluation: Option<handles::Evaluation>,
+        /** The last evaluation that succeeded */
+        pub last_success: Option<handles::Evaluation>,
+        pub schedule: Option<data::Schedule>,
         pub url: String,
     }
 
-    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct JobHistory {
+        pub job: JobSystemName,
+        /** The status of the job in each of `JobsetInfo::evaluations`, or
+         * `None` when the job is not part of the evaluation */
+        pub statuses: Vec<Option<TaskStatus>>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct JobHistoryEntry {
+        pub handle: handles::Job,
+        pub drv: String,
+        /** The duration of the last run in seconds, once it is done */
+        pub duration: Option<i64>,
+        pub status: TaskStatus,
+        /** The creation time of the evaluation */
+        #[serde(with = "time::serde::timestamp")]
+        pub time_created: OffsetDateTime,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Hash)]
     pub struct JobSystemName {
         pub system: String,
         pub name: String,
@@ -450,6 +733,8 @@ pub mod responses {
         #[serde(with = "crate::helpers::serialize_jobs")]
         pub jobs: HashMap<JobSystemName, JobInfo>,
         pub jobset_name: String,
+        /** The previous evaluation of the same jobset */
+        pub previous: Option<handles::Evaluation>,
         pub project: handles::Project,
         pub status: TaskStatus,
         #[serde(with = "time::serde::timestamp")]
@@ -457,17 +742,63 @@ pub mod responses {
         pub url: String,
     }
 
+    /** The differences between an evaluation (`head`) and a `base`
+     * evaluation. The job lists are sorted. */
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct EvaluationComparison {
+        pub base: handles::Evaluation,
+        pub head: handles::Evaluation,
+        /** Jobs that are only in `head` */
+        pub added: Vec<JobSystemName>,
+        /** Jobs that are only in `base` */
+        pub removed: Vec<JobSystemName>,
+        /** Jobs whose derivation changed */
+        pub changed: Vec<JobSystemName>,
+        /** Jobs whose last run succeeded in `base` and failed in `head` */
+        pub failing: Vec<JobSystemName>,
+        /** Jobs whose last run failed in `base` and succeeded in `head` */
+        pub fixed: Vec<JobSystemName>,
+    }
+
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub struct JobInfo {
         pub handle: handles::Job,
         pub dist: bool,
         pub drv: String,
+        /** Set when the job failed to evaluate, it then has no runs */
+        pub error: Option<JobError>,
         pub out: String,
+        /** Failed runs are retried until `run_count` reaches
+         * `retry.attempts` */
+        pub retry: Option<data::RetryPolicy>,
         pub system: String,
-        pub last_run: RunInfo,
+        pub last_run: Option<RunInfo>,
         pub run_count: u32,
     }
 
+    impl JobInfo {
+        /** Whether the last run failed and a new one will be created
+         * automatically */
+        pub fn retrying(&self) -> bool {
+            let failed = matches!(
+                self.last_run.as_ref().map(TaskStatus::from),
+                Some(TaskStatus::Failure(_) | TaskStatus::TimedOut(_))
+            );
+            failed
+                && self
+                    .retry
+                    .is_some_and(|retry| self.run_count < retry.attempts)
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct JobError {
+        pub message: String,
+        /** The creation time of the evaluation */
+        #[serde(with = "time::serde::timestamp")]
+        pub time: OffsetDateTime,
+    }
+
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub struct BuildInfo {
         pub handle: handles::Build,
@@ -478,10 +809,18 @@ pub mod responses {
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub struct ActionInfo {
         pub handle: handles::Action,
+        /** `None` until the script exits, or if it was killed by a signal */
+        pub exit_code: Option<i32>,
         pub input: String,
         pub name: String,
+        /** The standard output of the script, kept even if it is invalid */
+        pub output: Option<String>,
+        /** Why the output did not match the expected format */
+        pub output_error: Option<String>,
         pub path: String,
         pub project: handles::Project,
+        /** The parsed output, when it is valid */
+        pub result: Option<serde_json::Value>,
         pub status: TaskStatus,
     }
 
@@ -491,6 +830,8 @@ pub mod responses {
         pub begin: Option<ActionInfo>,
         pub build: Option<BuildInfo>,
         pub end: Option<ActionInfo>,
+        /** Set when the run was created automatically after a failure */
+        pub retry_reason: Option<String>,
     }
 
     pub mod search {
@@ -519,12 +860,18 @@ pub mod responses {
         ProjectInfo(ProjectInfo),
         JobsetEvaluate(crate::handles::Evaluation),
         JobsetInfo(JobsetInfo),
+        JobHistory(Vec<JobHistoryEntry>),
         EvaluationInfo(EvaluationInfo),
+        EvaluationCompare(EvaluationComparison),
         JobInfo(JobInfo),
         BuildInfo(BuildInfo),
         ActionInfo(ActionInfo),
         RunInfo(RunInfo),
         User(Option<data::User>),
+        Token(String),
+        Tokens(Vec<data::Token>),
+        Queue(data::QueueInfo),
+        Prune(data::PruneInfo),
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -545,20 +892,103 @@ pub mod responses {
     }
 }
 
+/** The protocol between Typhon and its build agents. Messages are
+ * exchanged as JSON, one per line, over a TCP connection opened by the
+ * agent. */
+pub mod agent {
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub enum AgentMsg {
+        /** The first message sent by an agent */
+        Hello {
+            password: String,
+            systems: Vec<String>,
+            features: Vec<String>,
+            /** The number of builds the agent runs at once */
+            max_jobs: usize,
+        },
+        /** A line of the build log, in Nix's internal JSON log format */
+        Log { build: u64, line: String },
+        /** The outputs of the build, or `None` if it failed */
+        Done {
+            build: u64,
+            outputs: Option<HashMap<String, String>>,
+        },
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub enum ServerMsg {
+        /** The agent was accepted */
+        Welcome,
+        Build {
+            build: u64,
+            drv: String,
+        },
+        Cancel {
+            build: u64,
+        },
+    }
+}
+
+/** The protocol of the WebSocket endpoint, which multiplexes events and
+ * several logs over one connection. Messages are JSON text frames. */
+pub mod websocket {
+    use crate::{handles, LoggedEvent};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub enum ClientMsg {
+        /** Listens to the events logged after `since`, or to new events
+         * after a `Ping` */
+        Events { since: Option<i64> },
+        /** Listens to a log, from its first line */
+        Log(handles::Log),
+        /** Stops listening to a log */
+        Unlisten(handles::Log),
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub enum ServerMsg {
+        Event(LoggedEvent),
+        Line {
+            log: handles::Log,
+            line: String,
+        },
+        /** The log is complete, or does not exist */
+        End(handles::Log),
+    }
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub enum Event {
     Ping,
+    /** Events were missed because they are too old to be replayed, clients
+     * must drop what they cached */
+    Gap,
     ProjectNew(handles::Project),
-    //ProjectDeleted(handles::Project),
+    ProjectDeleted(handles::Project),
     ProjectUpdated(handles::Project),
-    EvaluationNew(handles::Evaluation),
-    EvaluationFinished(handles::Evaluation),
+    /** Events on evaluations and runs carry the jobset they belong to */
+    EvaluationNew(handles::Evaluation, handles::Jobset),
+    EvaluationFinished(handles::Evaluation, handles::Jobset),
     BuildNew(handles::Build),
     BuildFinished(handles::Build),
-    RunNew(handles::Run),
-    RunUpdated(handles::Run),
+    RunNew(handles::Run, handles::Jobset),
+    RunUpdated(handles::Run, handles::Jobset),
     ActionNew(handles::Action),
     ActionFinished(handles::Action),
+    UserUpdated(String),
+}
+
+/** An event and its position in the event log. Clients resume the log from
+ * the last `id` they have seen. A `Ping` carries the id of the last event
+ * logged before it. */
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LoggedEvent {
+    pub id: i64,
+    pub event: Event,
 }
 
 impl Event {
@@ -566,23 +996,63 @@ impl Event {
         use requests::{Request as Req, *};
         use Event as Ev;
         match (self, req) {
+            (Ev::Gap, _) => true,
             (_, Req::Search(requests::search::Request { kind, .. })) => {
                 use search::Kind as Search;
                 match (kind, self) {
-                    (Search::Projects, Ev::ProjectNew(_) | Ev::ProjectUpdated(_))
-                    | (Search::Evaluations(_), Ev::EvaluationNew(_) | Ev::EvaluationFinished(_))
-                    | (Search::Runs(_), Ev::RunUpdated(_) | Ev::RunNew(_))
+                    (
+                        Search::Projects,
+                        Ev::ProjectNew(_) | Ev::ProjectUpdated(_) | Ev::ProjectDeleted(_),
+                    )
+                    | (
+                        Search::Evaluations(_),
+                        Ev::EvaluationNew(..) | Ev::EvaluationFinished(..),
+                    )
+                    | (Search::Runs(_), Ev::RunUpdated(..) | Ev::RunNew(..))
                     | (Search::Builds(_), Ev::BuildNew(_) | Ev::BuildFinished(_))
-                    | (Search::Actions(_), Ev::ActionNew(_) | Ev::ActionFinished(_)) => true,
+                    | (Search::Actions(_), Ev::ActionNew(_) | Ev::ActionFinished(_))
+                    | (
+                        Search::Jobsets(_)
+                        | Search::Evaluations(_)
+                        | Search::Runs(_)
+                        | Search::Actions(_),
+                        Ev::ProjectDeleted(_),
+                    ) => true,
                     _ => false,
                 }
             }
             (Ev::ProjectUpdated(h1), Req::Project(h2, Project::Info)) => h1 == h2,
             (Ev::ProjectUpdated(h1), Req::Jobset(h2, Jobset::Info)) => *h1 == h2.project,
-            (Ev::EvaluationFinished(h1), Req::Evaluation(h2, Evaluation::Info)) => h1 == h2,
+            (Ev::ProjectDeleted(h1), Req::Project(h2, Project::Info)) => h1 == h2,
+            (Ev::ProjectDeleted(h1), Req::Jobset(h2, Jobset::Info)) => *h1 == h2.project,
+            (
+                Ev::EvaluationNew(_, h1)
+                | Ev::EvaluationFinished(_, h1)
+                | Ev::RunNew(_, h1)
+                | Ev::RunUpdated(_, h1),
+                Req::Jobset(h2, Jobset::Info),
+            ) => h1 == h2,
+            (
+                Ev::EvaluationNew(_, h1) | Ev::EvaluationFinished(_, h1),
+                Req::Jobset(h2, Jobset::JobHistory { .. }),
+            ) => h1 == h2,
+            (
+                Ev::RunNew(h1, j1) | Ev::RunUpdated(h1, j1),
+                Req::Jobset(j2, Jobset::JobHistory { system, name, .. }),
+            ) => j1 == j2 && h1.job.system == *system && h1.job.name == *name,
+            (Ev::EvaluationFinished(h1, _), Req::Evaluation(h2, Evaluation::Info)) => h1 == h2,
+            // jobs are created while the evaluation runs
+            (Ev::RunNew(h1, _) | Ev::RunUpdated(h1, _), Req::Evaluation(h2, Evaluation::Info)) => {
+                h1.job.evaluation == *h2
+            }
+            (
+                Ev::RunNew(h1, _) | Ev::RunUpdated(h1, _),
+                Req::Evaluation(h2, Evaluation::Compare(h3)),
+            ) => h1.job.evaluation == *h2 || h1.job.evaluation == *h3,
             (Ev::BuildFinished(h1), Req::Build(h2, Build::Info)) => h1 == h2,
-            (Ev::RunUpdated(h1), Req::Run(h2, Run::Info)) => h1 == h2,
+            (Ev::RunUpdated(h1, _), Req::Run(h2, Run::Info)) => h1 == h2,
             (Ev::ActionFinished(h1), Req::Action(h2, Action::Info)) => h1 == h2,
+            (Ev::UserUpdated(_) | Ev::ProjectDeleted(_), Req::User) => true,
             (_, _) => false,
         }
     }
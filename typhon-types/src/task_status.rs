@@ -15,6 +15,9 @@ pub enum TaskStatus {
     Success(TimeRange),
     /** The task is done and failed */
     Failure(TimeRange),
+    /** The task was killed after exceeding its timeout or its maximum
+     * silent time */
+    TimedOut(TimeRange),
     /** The task was canceled: either while running (then the payload
      * is a `Some(TimeRange {start,end})`) or before running. */
     // TODO: we should have either a TimeRange or a {end}, right?
@@ -40,6 +43,7 @@ pub enum TaskStatusKind {
     Success = 1,
     Failure = 2,
     Canceled = 3,
+    TimedOut = 4,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -63,6 +67,7 @@ impl From<&TaskStatus> for TaskStatusKind {
             TaskStatus::Success(..) => Self::Success,
             TaskStatus::Failure(..) => Self::Failure,
             TaskStatus::Canceled(..) => Self::Canceled,
+            TaskStatus::TimedOut(..) => Self::TimedOut,
         }
     }
 }
@@ -77,6 +82,8 @@ const SUCCESS_TIME_INVARIANT: &str =
     "a `TaskStatus::Success` requires a start time and an end time";
 const FAILURE_TIME_INVARIANT: &str =
     "a `TaskStatus::Failure` requires a start time and an end time";
+const TIMED_OUT_TIME_INVARIANT: &str =
+    "a `TaskStatus::TimedOut` requires a start time and an end time";
 impl TaskStatusKind {
     /** Promotes a `TaskStatusKind` to a `TaskStatus`, given a start
      * time and a finish time. Note those are optional: a success task
@@ -93,6 +100,7 @@ impl TaskStatusKind {
             Self::Success => TaskStatus::Success(range.expect(SUCCESS_TIME_INVARIANT)),
             Self::Failure => TaskStatus::Failure(range.expect(FAILURE_TIME_INVARIANT)),
             Self::Canceled => TaskStatus::Canceled(range),
+            Self::TimedOut => TaskStatus::TimedOut(range.expect(TIMED_OUT_TIME_INVARIANT)),
         }
     }
 }
@@ -102,9 +110,10 @@ impl TaskStatus {
     pub fn times(self) -> (Option<OffsetDateTime>, Option<OffsetDateTime>) {
         match self {
             Self::Pending { start } => (start, None),
-            Self::Success(range) | Self::Failure(range) | Self::Canceled(Some(range)) => {
-                (Some(range.start), Some(range.end))
-            }
+            Self::Success(range)
+            | Self::Failure(range)
+            | Self::TimedOut(range)
+            | Self::Canceled(Some(range)) => (Some(range.start), Some(range.end)),
             Self::Canceled(None) => (None, None),
         }
     }
@@ -121,6 +130,7 @@ impl TaskStatus {
             TaskStatusKind::Pending => Self::Pending { start },
             TaskStatusKind::Canceled => Self::Canceled(range),
             TaskStatusKind::Success => Self::Success(range.expect(SUCCESS_TIME_INVARIANT)),
+            TaskStatusKind::TimedOut => Self::TimedOut(range.expect(TIMED_OUT_TIME_INVARIANT)),
         }
     }
 }
@@ -128,7 +138,13 @@ impl TaskStatus {
 impl TryFrom<i32> for TaskStatusKind {
     type Error = ();
     fn try_from(n: i32) -> Result<TaskStatusKind, ()> {
-        let arr = [Self::Pending, Self::Success, Self::Failure, Self::Canceled];
+        let arr = [
+            Self::Pending,
+            Self::Success,
+            Self::Failure,
+            Self::Canceled,
+            Self::TimedOut,
+        ];
         arr.get(n as usize).ok_or(()).copied()
     }
 }
@@ -145,6 +161,7 @@ impl std::fmt::Display for TaskStatusKind {
             Self::Success => write!(f, "success"),
             Self::Failure => write!(f, "failure"),
             Self::Canceled => write!(f, "canceled"),
+            Self::TimedOut => write!(f, "timedout"),
         }
     }
 }
@@ -163,6 +180,8 @@ impl core::cmp::Ord for TaskStatusKind {
         match (self, rhs) {
             (TaskStatusKind::Failure, _) => Ordering::Greater,
             (_, TaskStatusKind::Failure) => Ordering::Less,
+            (TaskStatusKind::TimedOut, _) => Ordering::Greater,
+            (_, TaskStatusKind::TimedOut) => Ordering::Less,
             (TaskStatusKind::Pending, _) => Ordering::Greater,
             (_, TaskStatusKind::Pending) => Ordering::Less,
             (TaskStatusKind::Canceled, _) => Ordering::Greater,
@@ -186,9 +205,17 @@ impl From<&crate::responses::RunInfo> for TaskStatus {
             run.end.as_ref().map(|info| info.status.into()),
         );
         let kind = match kinds {
+            (Some(TaskStatusKind::Canceled), _, _)
+            | (_, Some(TaskStatusKind::Canceled), _)
+            | (_, _, Some(TaskStatusKind::Canceled)) => TaskStatusKind::Canceled,
             (None, _, _) | (_, None, _) | (_, _, None) | (_, _, Some(TaskStatusKind::Pending)) => {
                 TaskStatusKind::Pending
             }
+            // the run was canceled while its build kept going for other runs
+            (_, Some(TaskStatusKind::Pending), Some(_)) => TaskStatusKind::Canceled,
+            (Some(TaskStatusKind::TimedOut), _, _) | (_, Some(TaskStatusKind::TimedOut), _) => {
+                TaskStatusKind::TimedOut
+            }
             (
                 Some(TaskStatusKind::Success),
                 Some(TaskStatusKind::Success),
@@ -202,7 +229,14 @@ impl From<&crate::responses::RunInfo> for TaskStatus {
 
 impl From<&crate::responses::JobInfo> for TaskStatus {
     fn from(job: &crate::responses::JobInfo) -> TaskStatus {
-        (&job.last_run).into()
+        match (&job.last_run, &job.error) {
+            (Some(run), _) => run.into(),
+            (None, Some(error)) => TaskStatus::Failure(TimeRange {
+                start: error.time,
+                end: error.time,
+            }),
+            (None, None) => TaskStatus::default(),
+        }
     }
 }
 
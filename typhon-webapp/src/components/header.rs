@@ -132,7 +132,13 @@ pub fn Header(#[prop(into)] route: Signal<Option<routes::Root<routes::Empty>>>)
             }>
                 {move || {
                     match user() {
-                        Some(_) => view! { <login::Logout></login::Logout> }.into_view(),
+                        Some(user) => {
+                            view! {
+                                <span>{user.name}</span>
+                                <login::Logout></login::Logout>
+                            }
+                                .into_view()
+                        }
                         None => view! { <A href="/login">"Log In"</A> }.into_view(),
                     }
                 }}
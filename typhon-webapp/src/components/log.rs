@@ -5,7 +5,7 @@ use std::collections::HashSet;
 
 impl PartialEq for State {
     fn eq(&self, other: &Self) -> bool {
-        self.lines.len() == other.lines.len()
+        self.first == other.first && self.lines.len() == other.lines.len()
     }
 }
 
@@ -15,12 +15,16 @@ impl Default for State {
             lines: Vector::new(),
             group_stack: Vector::new(),
             group_id: 0,
+            first: 0,
+            parsed: 0,
         }
     }
 }
 impl State {
     fn parse_line(self, line: String) -> State {
         let mut state = self;
+        let n = state.first + state.parsed;
+        state.parsed += 1;
         struct Command {
             name: String,
             rest: String,
@@ -52,6 +56,7 @@ impl State {
                 let group_stack = state.group_stack.clone();
                 state.group_stack.push_front(state.group_id);
                 state.lines.push_back(Line {
+                    n,
                     contents: cmd.rest,
                     starts_group: Some(state.group_id),
                     group_stack,
@@ -62,6 +67,7 @@ impl State {
                 state.group_stack = Vector::new();
                 state.group_stack.push_front(state.group_id);
                 state.lines.push_back(Line {
+                    n,
                     contents: cmd.rest,
                     starts_group: Some(state.group_id),
                     group_stack: Vector::new(),
@@ -72,6 +78,7 @@ impl State {
                 state.group_stack.pop_front();
             }
             _ => state.lines.push_back(Line {
+                n,
                 contents: line,
                 starts_group: None,
                 group_stack: state.group_stack.clone(),
@@ -84,6 +91,8 @@ impl State {
 type GroupId = usize;
 #[derive(Debug, Clone)]
 struct Line {
+    /// The number of the line in the log
+    n: u64,
     contents: String,
     starts_group: Option<GroupId>,
     group_stack: Vector<GroupId>,
@@ -93,25 +102,102 @@ struct State {
     lines: Vector<Line>,
     group_stack: Vector<GroupId>,
     group_id: GroupId,
+    /// The number of the first line parsed
+    first: u64,
+    /// The number of lines parsed, including group ends
+    parsed: u64,
 }
 
+/// The lines of a log, from line number `first`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogLines {
+    pub first: u64,
+    pub lines: Vector<String>,
+}
+
+/// The number of lines fetched when loading earlier lines of a log
+const PAGE: u64 = 1000;
+
+/// The maximum number of lines rendered at once
+const ROWS: u64 = 3000;
+
+/// A followed log, whose earlier lines are loaded on demand. `url` is the
+/// endpoint of the log, `lines` its followed tail.
 #[component]
-pub fn LiveLog(#[prop(into)] lines: leptos::ReadSignal<Option<String>>) -> impl IntoView {
+pub fn LiveLog(
+    #[prop(into)] url: String,
+    #[prop(into)] lines: leptos::ReadSignal<Option<LogLines>>,
+) -> impl IntoView {
+    let (earlier, set_earlier) = create_signal(None::<LogLines>);
+    // the first line shown, `None` while following the end of the log
+    let (pinned, set_pinned) = create_signal(None::<u64>);
     let contents = Signal::derive(move || {
-        lines()
-            .map(|contents| {
-                strip_ansi_escapes::strip_str(contents)
-                    .split("\n")
-                    .map(|s| s.to_owned())
-                    .collect::<Vec<String>>()
+        let lines = lines().unwrap_or_default();
+        match earlier() {
+            Some(mut earlier) => {
+                earlier.lines.append(lines.lines);
+                earlier
+            }
+            None => lines,
+        }
+    });
+    let start = Signal::derive(move || {
+        pinned().unwrap_or_else(|| {
+            contents.with(|contents| {
+                let end = contents.first + contents.lines.len() as u64;
+                end.saturating_sub(ROWS).max(contents.first)
             })
-            .unwrap_or_else(|| vec![])
+        })
     });
-    view! { <Log contents/> }
+    let load = create_action(move |&start: &u64| {
+        let loaded = contents.with_untracked(|contents| contents.first);
+        let url = format!("{}?lines={}-{}", url, start, loaded);
+        async move {
+            if start < loaded {
+                #[cfg(feature = "hydrate")]
+                let fetched = crate::streams::fetch_lines(&url).await;
+                #[cfg(not(feature = "hydrate"))]
+                let fetched: Vec<String> = {
+                    let _ = url;
+                    Vec::new()
+                };
+                set_earlier.update(|earlier| {
+                    let mut lines: Vector<String> = fetched.into_iter().collect();
+                    let first = loaded - lines.len() as u64;
+                    if let Some(earlier) = earlier.take() {
+                        lines.append(earlier.lines);
+                    }
+                    *earlier = Some(LogLines { first, lines });
+                });
+            }
+            set_pinned.set(Some(start));
+        }
+    });
+    view! {
+        <Show when=move || { start() > 0 }>
+            <button
+                class="earlier"
+                disabled=load.pending()
+                on:click=move |_| load.dispatch(start().saturating_sub(PAGE))
+            >
+                "Load earlier lines"
+            </button>
+        </Show>
+        <Log contents start/>
+        <Show when=move || pinned().is_some()>
+            <button class="follow" on:click=move |_| set_pinned.set(None)>
+                "Follow the end of the log"
+            </button>
+        </Show>
+    }
 }
 
+/// Renders at most `ROWS` lines of `contents`, from line number `start`
 #[component]
-pub fn Log(#[prop(into)] contents: Signal<Vec<String>>) -> impl IntoView {
+pub fn Log(
+    #[prop(into)] contents: Signal<LogLines>,
+    #[prop(into)] start: Signal<u64>,
+) -> impl IntoView {
     let styler_class = style! {
         .log :deep(.line.hidden) {
             display: none;
@@ -145,23 +231,48 @@ pub fn Log(#[prop(into)] contents: Signal<Vec<String>>) -> impl IntoView {
         }
     };
     let (close, set_close) = create_signal(HashSet::<GroupId>::new());
+    // new lines are parsed as they arrive, the log is only parsed again
+    // when earlier lines are prepended
     let state: Memo<State> = create_memo(move |state: Option<&State>| {
-        let mut state = state.cloned().unwrap_or_default();
-        for line in contents.get() {
-            state = state.parse_line(line)
-        }
-        state
+        contents.with(|contents| {
+            let mut state = match state {
+                Some(state)
+                    if state.first == contents.first
+                        && state.parsed <= contents.lines.len() as u64 =>
+                {
+                    state.clone()
+                }
+                _ => State {
+                    first: contents.first,
+                    ..State::default()
+                },
+            };
+            for line in contents.lines.skip(state.parsed as usize) {
+                state = state.parse_line(strip_ansi_escapes::strip_str(line));
+            }
+            state
+        })
     });
     let is_hidden = move |line: &Line| line.group_stack.iter().any(|id| close().contains(id));
     view! { class=styler_class,
         <div class="log">
             <For
                 each=move || {
-                    state.get().lines.into_iter().take(3000).enumerate().collect::<Vec<_>>()
+                    let start = start();
+                    state
+                        .with(|state| {
+                            let i = state
+                                .lines
+                                .binary_search_by_key(&start, |line| line.n)
+                                .unwrap_or_else(|i| i);
+                            state.lines.skip(i).take(ROWS as usize)
+                        })
+                        .into_iter()
+                        .collect::<Vec<_>>()
                 }
 
-                key=move |(i, _)| *i
-                children=move |(i, line)| {
+                key=|line| line.n
+                children=move |line| {
                     view! {
                         <div
                             class="line"
@@ -182,7 +293,7 @@ pub fn Log(#[prop(into)] contents: Signal<Vec<String>>) -> impl IntoView {
                             }
                         >
 
-                            <span class="n">{i + 1}</span>
+                            <span class="n">{line.n + 1}</span>
                             <pre style=format!(
                                 "margin-left: {}px;",
                                 (line.group_stack.len() + 1) * 16,
@@ -9,7 +9,7 @@ pub mod uuid_label;
 
 //pub use header::Header;
 pub use evaluations::Evaluations;
-pub use log::LiveLog;
+pub use log::{LiveLog, LogLines};
 pub use pagination::Pagination;
 pub use status::{HybridStatus, HybridStatusKind, Status};
 pub use time_related::*;
@@ -6,7 +6,15 @@ use typhon_types::responses::TaskStatus;
 
 use std::collections::HashMap;
 
-fn fetch_log(log: handles::Log) -> ReadSignal<Option<String>> {
+fn log_url(log: &handles::Log) -> String {
+    match log {
+        handles::Log::Action(handle) => format!("/api/actions/{}/log", handle.uuid),
+        handles::Log::Build(handle) => format!("/api/builds/{}/log", handle.uuid),
+        handles::Log::Evaluation(handle) => format!("/api/evaluations/{}/log", handle.uuid),
+    }
+}
+
+fn fetch_log(log: &handles::Log) -> ReadSignal<Option<LogLines>> {
     #[cfg(feature = "ssr")]
     {
         let _ = log;
@@ -14,8 +22,9 @@ fn fetch_log(log: handles::Log) -> ReadSignal<Option<String>> {
     }
     #[cfg(feature = "hydrate")]
     {
-        use gloo_net::http::Request;
-        crate::streams::fetch_as_signal(Request::post("/api/log").json(&log).unwrap())
+        // earlier lines are loaded on demand
+        const TAIL: u64 = 1000;
+        crate::streams::log_signal(&format!("{}/sse?tail={}", log_url(log), TAIL))
     }
 }
 
@@ -151,6 +160,16 @@ pub fn JobSubpage(
         .active {
             padding-top: 10px;
         }
+        pre.error {
+            margin: 0;
+            white-space: pre-wrap;
+            color: #FF8182;
+        }
+        pre.output {
+            margin: 0;
+            white-space: pre-wrap;
+            color: var(--color-gray);
+        }
     };
     let href = {
         let eval_handle = job.handle.evaluation.clone();
@@ -166,28 +185,43 @@ pub fn JobSubpage(
         }
     };
 
-    let logs: Vec<_> = {
-        let run = job.last_run.clone();
-        use handles::Log::*;
-        vec![
-            run.begin
-                .map(|x| (Action(x.handle), x.status, "Begin", LogTab::Begin)),
-            run.build
-                .map(|x| (Build(x.handle), x.status, "Build", LogTab::Build)),
-            run.end
-                .map(|x| (Action(x.handle), x.status, "End", LogTab::End)),
-        ]
-        .into_iter()
-        .flatten()
-        .collect()
+    let logs: Vec<_> = match job.last_run.clone() {
+        Some(run) => {
+            use handles::Log::*;
+            vec![
+                run.begin
+                    .map(|x| (Action(x.handle), x.status, "Begin", LogTab::Begin)),
+                run.build
+                    .map(|x| (Build(x.handle), x.status, "Build", LogTab::Build)),
+                run.end
+                    .map(|x| (Action(x.handle), x.status, "End", LogTab::End)),
+            ]
+            .into_iter()
+            .flatten()
+            .collect()
+        }
+        // the job failed to evaluate
+        None => vec![],
     };
 
     let active_log = logs
         .iter()
         .find(|(.., tab)| tab == &log_tab)
         .map(|(handle, ..)| handle);
+    let active_action = job.last_run.clone().and_then(|run| match log_tab {
+        LogTab::Begin => run.begin,
+        LogTab::End => run.end,
+        LogTab::Build => None,
+    });
 
-    let run = job.last_run.clone();
+    let status = TaskStatus::from(&job);
+    let error = job.error.clone().map(|error| error.message);
+    let retrying = job.retrying();
+    let run_count = job.run_count;
+    let retry_reason = job
+        .last_run
+        .as_ref()
+        .and_then(|run| run.retry_reason.clone());
     view! { class=style,
         <div class="header">
             <div class="name">
@@ -198,10 +232,9 @@ pub fn JobSubpage(
                 <h2>
 
                     {
-                        let status = TaskStatus::from(run.clone());
                         let status_signal = create_signal(status.clone()).0;
                         let (_, end) = status.times();
-                        let make = move |label: &'static str| {
+                        let make = move |label: String| {
                             let end: Option<time::OffsetDateTime> = end.clone();
                             match end.clone() {
                                 Some(end) => {
@@ -222,14 +255,26 @@ pub fn JobSubpage(
                                     <>running for <TaskStatusDuration status=status_signal/></>
                                 }
                             }
-                            TaskStatus::Success(..) => make("succeeded"),
-                            TaskStatus::Failure(..) => make("failed"),
-                            TaskStatus::Canceled(Some(..)) => make("canceled"),
+                            TaskStatus::Success(..) => make("succeeded".into()),
+                            TaskStatus::Failure(..) if error.is_some() => {
+                                view! { <>failed to evaluate</> }
+                            }
+                            TaskStatus::Failure(..) if retrying => make("failed, retrying".into()),
+                            TaskStatus::Failure(..) if run_count > 1 => {
+                                make(format!("failed after {} attempts", run_count))
+                            }
+                            TaskStatus::Failure(..) => make("failed".into()),
+                            TaskStatus::TimedOut(..) if retrying => {
+                                make("timed out, retrying".into())
+                            }
+                            TaskStatus::TimedOut(..) => make("timed out".into()),
+                            TaskStatus::Canceled(Some(..)) => make("canceled".into()),
                             TaskStatus::Canceled(None) => view! { <>canceled</> },
                         }
                     }
 
                 </h2>
+                {retry_reason.map(|reason| view! { <h2>"retried: " {reason}</h2> })}
             </div>
             <Icon icon=icondata::BiRefreshRegular/>
             <Icon icon=icondata::BiCogRegular/>
@@ -253,7 +298,24 @@ pub fn JobSubpage(
                     .collect::<Vec<_>>()}
             </div>
             <div class="active">
-                {active_log.map(|handle| view! { <LiveLog lines=fetch_log(handle.clone())/> })}
+                {active_log
+                    .map(|handle| view! { <LiveLog url=log_url(handle) lines=fetch_log(handle)/> })}
+                {error.clone().map(|error| view! { <pre class="error">{error}</pre> })}
+                {active_action
+                    .map(|action| {
+                        let exit_code = action
+                            .exit_code
+                            .map_or("none".to_string(), |code| code.to_string());
+                        let result = match (action.result, action.output_error) {
+                            (Some(result), _) => serde_json::to_string_pretty(&result).unwrap(),
+                            (None, Some(error)) => format!("invalid output: {}", error),
+                            (None, None) => String::new(),
+                        };
+                        view! {
+                            <pre class="output">"exit code: " {exit_code}</pre>
+                            <pre class="output">{result}</pre>
+                        }
+                    })}
             </div>
         </div>
     }
@@ -393,6 +455,128 @@ fn Info(info: responses::EvaluationInfo) -> impl IntoView {
     }
 }
 
+fn compare_section(
+    title: &'static str,
+    evaluation: handles::Evaluation,
+    jobs: Vec<responses::JobSystemName>,
+) -> impl IntoView {
+    let count = jobs.len();
+    let items = jobs
+        .into_iter()
+        .map(|job| {
+            let href = routes::to_url(routes::EvaluationPage {
+                handle: evaluation.clone(),
+                tab: EvaluationTab::Job {
+                    handle: handles::Job {
+                        evaluation: evaluation.clone(),
+                        system: job.system.clone(),
+                        name: job.name.clone(),
+                    },
+                    log_tab: LogTab::default(),
+                },
+            });
+            view! {
+                <li>
+                    <A href>{job.name} " (" {job.system} ")"</A>
+                </li>
+            }
+        })
+        .collect::<Vec<_>>();
+    view! {
+        <section>
+            <h1>{title} " (" {count} ")"</h1>
+            <ul>{items}</ul>
+        </section>
+    }
+}
+
+#[component]
+fn Compare(head: handles::Evaluation, base: Option<handles::Evaluation>) -> impl IntoView {
+    let style = style! {
+        div {
+            padding: 5px;
+        }
+        div :deep(h1) {
+            font-size: var(--font-size-big);
+            font-weight: 400;
+            margin: 0;
+            padding-top: 10px;
+        }
+        div :deep(ul) {
+            margin: 5px 0;
+            padding-left: 20px;
+        }
+        .empty {
+            color: var(--color-gray);
+        }
+    };
+    let Some(base) = base else {
+        return view! { class=style,
+            <div class="empty">
+                This is the first evaluation of the jobset, there is nothing to compare it with.
+            </div>
+        }
+        .into_view();
+    };
+    let (error, comparison) = resource!(
+        Signal::derive(move || Request::Evaluation(
+            head.clone(),
+            requests::Evaluation::Compare(base.clone())
+        )),
+        |Response::EvaluationCompare(comparison)| comparison
+    );
+    view! { class=style,
+        <div>
+            <Trans error>
+                {move || {
+                    comparison()
+                        .map(|comparison| {
+                            let href = routes::to_url(routes::EvaluationPage {
+                                handle: comparison.base.clone(),
+                                tab: EvaluationTab::Info,
+                            });
+                            view! {
+                                <span>
+                                    Compared with evaluation
+                                    <A href>
+                                        <UuidLabel uuid=comparison.base.uuid/>
+                                    </A>
+                                </span>
+                                {compare_section(
+                                    "Newly failing",
+                                    comparison.head.clone(),
+                                    comparison.failing,
+                                )}
+                                {compare_section(
+                                    "Newly fixed",
+                                    comparison.head.clone(),
+                                    comparison.fixed,
+                                )}
+                                {compare_section(
+                                    "Added",
+                                    comparison.head.clone(),
+                                    comparison.added,
+                                )}
+                                {compare_section(
+                                    "Removed",
+                                    comparison.base.clone(),
+                                    comparison.removed,
+                                )}
+                                {compare_section(
+                                    "Changed",
+                                    comparison.head.clone(),
+                                    comparison.changed,
+                                )}
+                            }
+                        })
+                }}
+
+            </Trans>
+        </div>
+    }
+    .into_view()
+}
+
 #[component]
 fn Main(
     #[allow(unused)]
@@ -469,16 +653,14 @@ fn Main(
                         {jobs
                             .into_iter()
                             .map(|(name, info)| {
-                                let last_run = info.last_run.clone();
+                                let status = TaskStatus::from(info.clone());
                                 mk_item(
                                     EvaluationTab::Job {
                                         handle: info.handle.clone(),
                                         log_tab: LogTab::default(),
                                     },
                                     view! {
-                                        <Status status=move || {
-                                            TaskStatus::from(last_run.clone()).into()
-                                        }/>
+                                        <Status status=move || status.into()/>
                                     },
                                     view! { <span>{name}</span> }.into_view(),
                                 )
@@ -515,6 +697,11 @@ fn Main(
                         view! { <Icon icon=icondata::BiHomeAltRegular/> },
                         view! { Overview }.into_view(),
                     )}
+                    {mk_item(
+                        EvaluationTab::Compare { base: None },
+                        view! { <Icon icon=icondata::BiGitCompareRegular/> },
+                        view! { Compare }.into_view(),
+                    )}
 
                 </ul>
             </section>
@@ -533,6 +720,14 @@ fn Main(
                                 </div>
                             }
                         }
+                        EvaluationTab::Compare { base } => {
+                            let base = base.or(info.previous.clone());
+                            view! {
+                                <div>
+                                    <Compare head=info.handle.clone() base/>
+                                </div>
+                            }
+                        }
                         EvaluationTab::Job { handle, log_tab } => {
                             if let Some(job) = info
                                 .jobs
@@ -565,7 +760,7 @@ fn Main(
     let global_status: Signal<TaskStatus> = Signal::derive(move || {
         info.jobs
             .iter()
-            .map(|(_, info)| TaskStatus::from(info.last_run.clone()))
+            .map(|(_, info)| TaskStatus::from(info))
             .reduce(|a, b| a.union(&b))
             .unwrap_or_default()
     });
@@ -1,4 +1,115 @@
 use crate::prelude::*;
+use routes::{EvaluationTab, LogTab};
+
+fn evaluation_link(evaluation: Option<handles::Evaluation>) -> impl IntoView {
+    match evaluation {
+        Some(handle) => {
+            let href = routes::to_url(routes::EvaluationPage {
+                handle: handle.clone(),
+                tab: EvaluationTab::Info,
+            });
+            view! {
+                <A href>
+                    <UuidLabel uuid=handle.uuid/>
+                </A>
+            }
+            .into_view()
+        }
+        None => "none".into_view(),
+    }
+}
+
+#[component]
+fn History(info: responses::JobsetInfo) -> impl IntoView {
+    let style = style! {
+        table {
+            border-collapse: collapse;
+            margin: 10px 0;
+        }
+        th, td {
+            padding: 2px 6px;
+            text-align: center;
+        }
+        th.job, td.job {
+            text-align: left;
+        }
+        th {
+            font-family: var(--font-family-monospace), monospace;
+            font-weight: 400;
+        }
+        td.job .system {
+            color: var(--color-gray);
+        }
+    };
+    let evaluations = info.evaluations;
+    let header = evaluations
+        .iter()
+        .map(|handle| {
+            let href = routes::to_url(routes::EvaluationPage {
+                handle: handle.clone(),
+                tab: EvaluationTab::Info,
+            });
+            let label = handle.uuid.to_string().chars().take(8).collect::<String>();
+            view! {
+                <th>
+                    <A href>{label}</A>
+                </th>
+            }
+        })
+        .collect::<Vec<_>>();
+    let rows = info
+        .history
+        .into_iter()
+        .map(|history| {
+            let cells = evaluations
+                .iter()
+                .zip(history.statuses)
+                .map(|(evaluation, status)| {
+                    let Some(status) = status else {
+                        return view! { <td></td> };
+                    };
+                    let href = routes::to_url(routes::EvaluationPage {
+                        handle: evaluation.clone(),
+                        tab: EvaluationTab::Job {
+                            handle: handles::Job {
+                                evaluation: evaluation.clone(),
+                                system: history.job.system.clone(),
+                                name: history.job.name.clone(),
+                            },
+                            log_tab: LogTab::default(),
+                        },
+                    });
+                    view! {
+                        <td>
+                            <A href>
+                                <Status status=data::TaskStatusKind::from(status)/>
+                            </A>
+                        </td>
+                    }
+                })
+                .collect::<Vec<_>>();
+            view! {
+                <tr>
+                    <td class="job">
+                        {history.job.name} " " <span class="system">{history.job.system}</span>
+                    </td>
+                    {cells}
+                </tr>
+            }
+        })
+        .collect::<Vec<_>>();
+    (!rows.is_empty()).then(|| {
+        view! { class=style,
+            <table>
+                <tr>
+                    <th class="job">"Job"</th>
+                    {header}
+                </tr>
+                {rows}
+            </table>
+        }
+    })
+}
 
 #[component]
 pub fn Jobset(
@@ -85,12 +196,30 @@ pub fn Jobset(
                                         <td>"Flake"</td>
                                         <td>{info.flake}</td>
                                     </tr>
+                                    <tr>
+                                        <td>"Schedule"</td>
+                                        <td>
+                                            {info
+                                                .schedule
+                                                .map(|schedule| schedule.to_string())
+                                                .unwrap_or("none".to_string())}
+                                        </td>
+                                    </tr>
+                                    <tr>
+                                        <td>"Last evaluation"</td>
+                                        <td>{evaluation_link(info.last_evaluation)}</td>
+                                    </tr>
+                                    <tr>
+                                        <td>"Last success"</td>
+                                        <td>{evaluation_link(info.last_success)}</td>
+                                    </tr>
                                 </table>
                             }
                         })
                 }}
 
             </PageHeader>
+            {move || info().map(|info| view! { <History info/> })}
         </Trans>
         <Trans error=error_evaluations>
             <Evaluations
@@ -98,7 +227,11 @@ pub fn Jobset(
                 evaluations
                 buttons=Box::new(move || {
                     view! {
-                        <Show when=move || user().is_some()>
+                        <Show when=move || {
+                            user()
+                                .and_then(|user| user.role(&signal_handle().project))
+                                >= Some(data::Role::Maintainer)
+                        }>
                             <ActionForm action>
                                 <input
                                     type="hidden"
@@ -7,19 +7,22 @@ mod server_fn {
     use leptos::*;
 
     #[server(Login, "/leptos", "Url", "login")]
-    pub async fn login(password: String) -> Result<(), ServerFnError> {
+    pub async fn login(name: String, password: String) -> Result<(), ServerFnError> {
         use crate::prelude::*;
         use actix_session::Session;
         use leptos_actix::extract;
-        use typhon_types::data::User;
+        use typhon_core::User;
         let res = handle_request!(
-            requests::Request::Login { password },
+            requests::Request::Login {
+                name: name.clone(),
+                password
+            },
             |responses::Response::Ok| ()
         );
         match res {
             Ok(Ok(())) => {
                 let session: Session = extract().await?;
-                session.insert("user", User::Admin).map_err(|_| {
+                session.insert("user", User::Account(name)).map_err(|_| {
                     ServerFnError::<server_fn::error::NoCustomError>::ServerError(
                         "TODO".to_string(),
                     )
@@ -75,6 +78,10 @@ pub fn Login() -> impl IntoView {
             <Show when=move || user().is_none() fallback=|| view! { "You are logged in!" }>
                 <ActionForm action>
                     <h2>"Log In"</h2>
+                    <div>
+                        <label for="name">"Username"</label>
+                        <input type="text" placeholder="Username" name="name"/>
+                    </div>
                     <div>
                         <label for="password">"Password"</label>
                         <input type="password" placeholder="Password" name="password"/>
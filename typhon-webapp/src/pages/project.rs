@@ -41,6 +41,10 @@ pub(crate) fn Project(handle: handles::Project) -> impl IntoView {
             |responses::Response::ProjectInfo(info)| info
         )
     };
+    let role = {
+        let handle = handle.clone();
+        Signal::derive(move || user().and_then(|user| user.role(&handle)))
+    };
     let jobsets = Signal::derive(move || info().map(|x| x.jobsets).unwrap_or(Vec::new()));
     let jobsets = {
         let handle = handle.clone();
@@ -62,6 +66,19 @@ pub(crate) fn Project(handle: handles::Project) -> impl IntoView {
         handles::Project { name },
         requests::Project::Refresh,
     ));
+    let delete = request_action!(DeleteProject, |name: String| requests::Request::Project(
+        handles::Project { name },
+        requests::Project::Delete,
+    ));
+    let (confirm_delete, set_confirm_delete) = create_signal(false);
+    {
+        let navigate = leptos_router::use_navigate();
+        create_effect(move |_| {
+            if let Some(Ok(Ok(()))) = delete.value()() {
+                navigate("/", Default::default());
+            }
+        });
+    }
     let handle_name = {
         let handle_name = handle.name.clone();
         Signal::derive(move || handle_name.clone())
@@ -99,16 +116,35 @@ pub(crate) fn Project(handle: handles::Project) -> impl IntoView {
             </PageHeader>
             <div class="is-table">
                 <div class="header">
-                    <Show when=move || { user().is_some() }>
+                    <Show when=move || { role() >= Some(data::Role::Maintainer) }>
                         <ActionForm action=update_jobsets>
                             <input type="hidden" name="name" value=handle_name/>
                             <input type="submit" value="Update jobsets"/>
                         </ActionForm>
+                    </Show>
+                    <Show when=move || { role() >= Some(data::Role::Owner) }>
                         <ActionForm action=refresh>
                             <input type="hidden" name="name" value=handle_name/>
                             <input type="submit" value="Refresh"/>
 
                         </ActionForm>
+                        <Show
+                            when=move || !confirm_delete()
+                            fallback=move || {
+                                view! {
+                                    <ActionForm action=delete>
+                                        <input type="hidden" name="name" value=handle_name/>
+                                        <input type="submit" value="Confirm deletion"/>
+                                    </ActionForm>
+                                    <button on:click=move |_| set_confirm_delete(false)>
+                                        "Cancel"
+                                    </button>
+                                }
+                            }
+                        >
+
+                            <button on:click=move |_| set_confirm_delete(true)>"Delete"</button>
+                        </Show>
                     </Show>
                     {move || {
                         info()
@@ -121,6 +157,17 @@ pub(crate) fn Project(handle: handles::Project) -> impl IntoView {
                             })
                             .flatten()
                     }}
+                    {move || {
+                        info()
+                            .map(|info| {
+                                info.last_refresh_build
+                                    .map(|handle| {
+                                        let href = format!("/api/builds/{}/log", handle.uuid);
+                                        view! { <a href=href>"Actions build log"</a> }
+                                    })
+                            })
+                            .flatten()
+                    }}
 
                     <div class="header-columns"></div>
                 </div>
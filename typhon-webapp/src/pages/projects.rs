@@ -99,7 +99,7 @@ pub(crate) fn Projects() -> impl IntoView {
                 />
 
             </div>
-            <Show when=move || user().is_some()>
+            <Show when=move || user().map(|user| user.admin).unwrap_or(false)>
                 <ActionForm action>
                     <h2>"Add a project"</h2>
                     <div>
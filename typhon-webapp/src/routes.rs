@@ -176,6 +176,11 @@ impl std::fmt::Display for DashboardTab {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum EvaluationTab {
     Info,
+    /// The comparison with `base`, or with the previous evaluation of the
+    /// jobset when `base` is `None`
+    Compare {
+        base: Option<handles::Evaluation>,
+    },
     Job {
         handle: handles::Job,
         log_tab: LogTab,
@@ -252,6 +257,13 @@ impl TryFrom<Location> for Root {
                 ["evaluation", uuid, rest @ ..] if let Ok(uuid) = uuid::Uuid::from_str(uuid) => {
                     let handle = handles::evaluation(uuid);
                     let tab = match rest {
+                        ["compare"] => EvaluationTab::Compare { base: None },
+                        ["compare", base] => match uuid::Uuid::from_str(base) {
+                            Ok(base) => EvaluationTab::Compare {
+                                base: Some(handles::evaluation(base)),
+                            },
+                            Err(_) => Err(r)?,
+                        },
                         [system, name, log_tab @ ..] => {
                             let handle = handles::Job {
                                 evaluation: handle.clone(),
@@ -306,6 +318,8 @@ impl From<Root> for String {
                         )
                     }
                     EvaluationTab::Info => "".into(),
+                    EvaluationTab::Compare { base: None } => "compare".into(),
+                    EvaluationTab::Compare { base: Some(base) } => format!("compare/{}", base.uuid),
                 }
             ),
         }
@@ -1,63 +1,109 @@
 #![cfg(feature = "hydrate")]
 
+use crate::components::LogLines;
+
 use typhon_types::*;
 
 use async_stream::stream;
-use futures::future::FutureExt;
+use futures::channel::mpsc;
 use futures_core::stream::Stream;
-use futures_util::stream::StreamExt;
 use gloo_console::log;
-use gloo_net::http;
-//use gloo_utils::format::JsValueSerdeExt;
-//use js_sys::Promise;
-//use wasm_bindgen::prelude::*;
+use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use wasm_streams::readable::*;
 
-pub fn fetch_as_stream(req: http::Request) -> impl Stream<Item = String> {
-    async move {
-        let res = req
-            .send()
-            .await
-            .map_err(|e| gloo_console::log!(format!("network error {:?}", e)))
-            .unwrap();
-        let body = res.body();
-        let readable_stream: web_sys::ReadableStream = body.unwrap();
-        let readable_stream: sys::ReadableStream = readable_stream.unchecked_into();
-        let readable_stream: ReadableStream = ReadableStream::from_raw(readable_stream);
-        readable_stream
-            .into_stream()
-            .filter_map(|item| core::future::ready(item.ok()))
-            .map(|item| {
-                let text_decoder = web_sys::TextDecoder::new().unwrap();
-                let buffer = text_decoder
-                    .decode_with_buffer_source(&item.into())
-                    .unwrap();
-                buffer
-                    .strip_suffix("\n")
-                    .map(|s| s.to_owned())
-                    .unwrap_or(buffer)
-            })
+/// Closes the event source when the stream is dropped.
+struct Source(web_sys::EventSource);
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// A server-sent event
+pub struct Message {
+    pub id: String,
+    pub data: String,
+}
+
+/// Listens to the server-sent events at `url`. The browser reconnects by
+/// itself, sending the id of the last event received. The stream ends on an
+/// `end` event.
+pub fn event_source(url: &str) -> impl Stream<Item = Message> + 'static {
+    let (sender, mut receiver) = mpsc::unbounded();
+    let source = web_sys::EventSource::new(url).unwrap();
+    let on_message = {
+        let sender = sender.clone();
+        Closure::<dyn FnMut(web_sys::MessageEvent)>::new(move |e: web_sys::MessageEvent| {
+            if let Some(data) = e.data().as_string() {
+                let id = e.last_event_id();
+                let _ = sender.unbounded_send(Message { id, data });
+            }
+        })
+    };
+    let on_end = {
+        let source = source.clone();
+        Closure::<dyn FnMut(web_sys::MessageEvent)>::new(move |_| {
+            source.close();
+            sender.close_channel();
+        })
+    };
+    source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    source
+        .add_event_listener_with_callback("end", on_end.as_ref().unchecked_ref())
+        .unwrap();
+    stream! {
+        let _source = Source(source);
+        let _closures = (on_message, on_end);
+        while let Some(data) = futures::StreamExt::next(&mut receiver).await {
+            yield data;
+        }
     }
-    .into_stream()
-    .flatten()
 }
 
-pub fn fetch_as_signal(req: http::Request) -> leptos::ReadSignal<Option<String>> {
-    leptos::create_signal_from_stream(Box::pin(fetch_as_stream(req)))
+/// The lines of a followed log, updated as new lines arrive. Events are
+/// identified by the number of their line.
+pub fn log_signal(url: &str) -> leptos::ReadSignal<Option<LogLines>> {
+    let messages = event_source(url);
+    let lines = stream! {
+        let mut lines = LogLines::default();
+        for await message in messages {
+            if lines.lines.is_empty() {
+                lines.first = message.id.parse().unwrap_or(0);
+            }
+            lines.lines.push_back(message.data);
+            yield lines.clone();
+        }
+    };
+    leptos::create_signal_from_stream(Box::pin(lines))
+}
+
+/// Fetches lines of a log that are not followed
+pub async fn fetch_lines(url: &str) -> Vec<String> {
+    let text = match gloo_net::http::Request::get(url).send().await {
+        Ok(response) if response.ok() => response.text().await,
+        Ok(response) => {
+            log!(format!("failed to fetch log: status {}", response.status()));
+            return Vec::new();
+        }
+        Err(e) => Err(e),
+    };
+    match text {
+        // every line is terminated by a newline
+        Ok(text) => text.split_terminator('\n').map(String::from).collect(),
+        Err(e) => {
+            log!(format!("failed to fetch log: {:?}", e));
+            Vec::new()
+        }
+    }
 }
 
 pub fn events_stream() -> impl Stream<Item = Event> + Unpin + 'static {
-    let req = http::RequestBuilder::new("/api/events").method(http::Method::GET);
-    let req = req.build().unwrap();
     let s = stream! {
-        for await chunk in fetch_as_stream(req) {
-            let deserializer = serde_json::Deserializer::from_str(&chunk);
-            for maybe_event in deserializer.into_iter() {
-                match maybe_event {
-                    Ok(event) => yield event,
-                    Err(e) => log!(format!("failed to parse event: {:?}", e)),
-                }
+        for await message in event_source("/api/events/sse") {
+            match serde_json::from_str::<LoggedEvent>(&message.data) {
+                Ok(logged) => yield logged.event,
+                Err(e) => log!(format!("failed to parse event: {:?}", e)),
             }
         }
     };
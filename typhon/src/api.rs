@@ -1,7 +1,10 @@
+use crate::streams;
+
 use typhon_core::error;
 use typhon_core::handle_request;
 use typhon_core::User;
 use typhon_core::EVENT_LOGGER;
+use typhon_types::data::{LogRange, Role};
 use typhon_types::handles;
 use typhon_types::requests::*;
 use typhon_types::responses::{Response, ResponseError};
@@ -50,12 +53,18 @@ impl Responder for ResponseWrapper {
             ProjectInfo(payload) => web::Json(payload).respond_to(req),
             JobsetInfo(payload) => web::Json(payload).respond_to(req),
             JobsetEvaluate(payload) => web::Json(payload).respond_to(req),
+            JobHistory(payload) => web::Json(payload).respond_to(req),
             EvaluationInfo(payload) => web::Json(payload).respond_to(req),
+            EvaluationCompare(payload) => web::Json(payload).respond_to(req),
             JobInfo(payload) => web::Json(payload).respond_to(req),
             BuildInfo(payload) => web::Json(payload).respond_to(req),
             ActionInfo(payload) => web::Json(payload).respond_to(req),
             RunInfo(payload) => web::Json(payload).respond_to(req),
             User(payload) => web::Json(payload).respond_to(req),
+            Token(payload) => web::Json(payload).respond_to(req),
+            Tokens(payload) => web::Json(payload).respond_to(req),
+            Queue(payload) => web::Json(payload).respond_to(req),
+            Prune(payload) => web::Json(payload).respond_to(req),
         }
     }
 }
@@ -67,12 +76,19 @@ impl FromRequest for UserWrapper {
     type Future = Pin<Box<dyn Future<Output = Result<UserWrapper, actix_web::Error>>>>;
 
     fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
-        let maybe_user = req
+        let maybe_token = req
             .headers()
-            .get("password")
-            .map(|value| value.as_bytes())
-            .as_ref()
-            .map(|password| User::from_password(password));
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| User::Token(token.trim().to_string()));
+        let maybe_user = maybe_token.or_else(|| {
+            req.headers()
+                .get("password")
+                .map(|value| value.as_bytes())
+                .as_ref()
+                .map(|password| User::from_password(password))
+        });
         let session = Session::from_request(req, pl);
         Box::pin(async move {
             match maybe_user {
@@ -111,6 +127,24 @@ macro_rules! r {
     (  ) => {}
 }
 
+#[derive(serde::Deserialize)]
+struct HistoryQuery {
+    #[serde(default = "default_history_limit")]
+    limit: u8,
+    #[serde(default)]
+    offset: u32,
+}
+
+fn default_history_limit() -> u8 {
+    20
+}
+
+#[derive(serde::Deserialize)]
+struct PruneQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
 r!(
     search(body: web::Json<search::Request>) =>
         Request::Search(body.into_inner());
@@ -121,11 +155,11 @@ r!(
         Request::CreateProject { name, decl }
     };
 
-    //project_delete(path: web::Path<String>) =>
-    //    Request::Project(
-    //        handles::project(path.into_inner()),
-    //        Project::Delete,
-    //    );
+    project_delete(path: web::Path<String>) =>
+        Request::Project(
+            handles::project(path.into_inner()),
+            Project::Delete,
+        );
 
     project_info(path: web::Path<String>) =>
         Request::Project(
@@ -145,6 +179,14 @@ r!(
             Project::SetDecl(body.into_inner()),
         );
 
+    project_set_role(path: web::Path<(String,String)>, body: web::Json<Option<Role>>) => {
+        let (project, user) = path.into_inner();
+        Request::Project(
+            handles::project(project),
+            Project::SetRole { user, role: body.into_inner() },
+        )
+    };
+
     project_update_jobsets(path: web::Path<String>) =>
         Request::Project(
             handles::project(path.into_inner()),
@@ -163,6 +205,14 @@ r!(
             Jobset::Info,
         );
 
+    jobset_job_history(path: web::Path<(String,String,String,String)>, query: web::Query<HistoryQuery>) => {
+        let (project, jobset, system, name) = path.into_inner();
+        Request::Jobset(
+            handles::jobset((project, jobset)),
+            Jobset::JobHistory { system, name, limit: query.limit, offset: query.offset },
+        )
+    };
+
     evaluation_cancel(path: web::Path<Uuid>) =>
         Request::Evaluation(
             handles::evaluation(path.into_inner()),
@@ -175,17 +225,25 @@ r!(
             Evaluation::Info,
         );
 
+    evaluation_compare(path: web::Path<(Uuid,Uuid)>) => {
+        let (evaluation, base) = path.into_inner();
+        Request::Evaluation(
+            handles::evaluation(evaluation),
+            Evaluation::Compare(handles::evaluation(base)),
+        )
+    };
+
     job_info(path: web::Path<(Uuid,String,String)>) =>
         Request::Job(
             handles::job(path.into_inner()),
             Job::Info,
         );
 
-    //run_cancel(path: web::Path<(Uuid,String,String,u32)>) =>
-    //    Request::Run(
-    //        handles::run(path.into_inner()),
-    //        Run::Cancel,
-    //    );
+    run_cancel(path: web::Path<(Uuid,String,String,u32)>) =>
+        Request::Run(
+            handles::run(path.into_inner()),
+            Run::Cancel,
+        );
 
     run_info(path: web::Path<(Uuid,String,String,u32)>) =>
         Request::Run(
@@ -211,8 +269,35 @@ r!(
             Action::Info,
         );
 
+    create_user(path: web::Path<String>, body: web::Json<UserDecl>) =>
+        Request::CreateUser { name: path.into_inner(), decl: body.into_inner() };
+
+    delete_user(path: web::Path<String>) =>
+        Request::DeleteUser { name: path.into_inner() };
+
+    set_password(path: web::Path<String>, body: web::Json<String>) =>
+        Request::SetPassword { name: path.into_inner(), password: body.into_inner() };
+
+    user_login(path: web::Path<String>, body: web::Json<String>) =>
+        Request::Login { name: path.into_inner(), password: body.into_inner() };
+
+    create_token(path: web::Path<String>, body: web::Json<TokenDecl>) =>
+        Request::CreateToken { name: path.into_inner(), decl: body.into_inner() };
+
+    revoke_token(path: web::Path<String>) =>
+        Request::RevokeToken { name: path.into_inner() };
+
+    tokens() =>
+        Request::Tokens;
+
+    queue() =>
+        Request::Queue;
+
+    prune(query: web::Query<PruneQuery>) =>
+        Request::Prune { dry_run: query.dry_run };
+
     login(body: web::Json<String>) =>
-        Request::Login { password: body.into_inner() };
+        Request::Login { name: "admin".to_string(), password: body.into_inner() };
 );
 
 async fn dist(
@@ -253,21 +338,119 @@ mod log_routes {
     use super::*;
     use handles::Log;
 
-    async fn serve(log: Log) -> Response {
-        let maybe_stream = web::block(move || typhon_core::log(log)).await??;
+    /// Ranges are given as `?lines=start-end`, `?bytes=start-end` (the end
+    /// is optional) or `?tail=n`
+    #[derive(serde::Deserialize)]
+    pub struct RangeQuery {
+        bytes: Option<String>,
+        lines: Option<String>,
+        tail: Option<u64>,
+    }
+
+    impl RangeQuery {
+        fn range(&self) -> Result<LogRange, ResponseErrorWrapper> {
+            let parse = |s: &str| -> Result<(u64, Option<u64>), ResponseErrorWrapper> {
+                let bad =
+                    || ResponseErrorWrapper(ResponseError::BadRequest(format!("bad range {}", s)));
+                let (start, end) = s.split_once('-').ok_or_else(bad)?;
+                let start = start.parse().map_err(|_| bad())?;
+                let end = match end {
+                    "" => None,
+                    end => Some(end.parse().map_err(|_| bad())?),
+                };
+                Ok((start, end))
+            };
+            Ok(match (&self.bytes, &self.lines, self.tail) {
+                (None, None, None) => LogRange::All,
+                (Some(bytes), None, None) => {
+                    let (start, end) = parse(bytes)?;
+                    LogRange::Bytes { start, end }
+                }
+                (None, Some(lines), None) => {
+                    let (start, end) = parse(lines)?;
+                    LogRange::Lines { start, end }
+                }
+                (None, None, Some(n)) => LogRange::Tail(n),
+                _ => Err(ResponseErrorWrapper(ResponseError::BadRequest(
+                    "only one of bytes, lines and tail can be given".to_string(),
+                )))?,
+            })
+        }
+    }
+
+    async fn serve(log: Log, query: web::Query<RangeQuery>) -> Response {
+        let range = query.range()?;
+        let maybe_stream = web::block(move || typhon_core::log(log, range)).await??;
         Ok(maybe_stream.map(streaming_response))
     }
-    pub async fn evaluation(path: web::Path<Uuid>) -> Response {
-        serve(Log::Evaluation(handles::evaluation(path.into_inner()))).await
+    pub async fn evaluation(path: web::Path<Uuid>, query: web::Query<RangeQuery>) -> Response {
+        serve(
+            Log::Evaluation(handles::evaluation(path.into_inner())),
+            query,
+        )
+        .await
+    }
+    pub async fn build(path: web::Path<Uuid>, query: web::Query<RangeQuery>) -> Response {
+        serve(Log::Build(handles::build(path.into_inner())), query).await
+    }
+    pub async fn action(path: web::Path<Uuid>, query: web::Query<RangeQuery>) -> Response {
+        serve(Log::Action(handles::action(path.into_inner())), query).await
     }
-    pub async fn build(path: web::Path<Uuid>) -> Response {
-        serve(Log::Build(handles::build(path.into_inner()))).await
+    pub async fn generic(path: web::Json<Log>, query: web::Query<RangeQuery>) -> Response {
+        serve(path.into_inner(), query).await
     }
-    pub async fn action(path: web::Path<Uuid>) -> Response {
-        serve(Log::Action(handles::action(path.into_inner()))).await
+
+    /// Followed logs are read by line ranges, tails are turned into one when
+    /// the client connects. A reconnecting client resumes after the last line
+    /// it received.
+    async fn serve_sse(log: Log, query: web::Query<RangeQuery>, req: HttpRequest) -> Response {
+        let resume = streams::last_event_id(&req).map(|id| (id + 1).max(0) as u64);
+        let (start, end) = match query.range()? {
+            LogRange::All => (0, None),
+            LogRange::Lines { start, end } => (start, end),
+            LogRange::Tail(_) if resume.is_some() => (0, None),
+            LogRange::Tail(n) => {
+                let log = log.clone();
+                let lines = web::block(move || typhon_core::log_lines(log)).await??;
+                (lines.saturating_sub(n), None)
+            }
+            LogRange::Bytes { .. } => Err(ResponseErrorWrapper(ResponseError::BadRequest(
+                "byte ranges cannot be followed".to_string(),
+            )))?,
+        };
+        let start = resume.map_or(start, |resume| resume.max(start));
+        let range = LogRange::Lines { start, end };
+        let maybe_stream = web::block(move || typhon_core::log(log, range)).await??;
+        Ok(Some(streams::sse_response(streams::log_events(
+            maybe_stream,
+            start,
+        ))))
+    }
+    pub async fn evaluation_sse(
+        path: web::Path<Uuid>,
+        query: web::Query<RangeQuery>,
+        req: HttpRequest,
+    ) -> Response {
+        serve_sse(
+            Log::Evaluation(handles::evaluation(path.into_inner())),
+            query,
+            req,
+        )
+        .await
     }
-    pub async fn generic(path: web::Json<Log>) -> Response {
-        serve(path.into_inner()).await
+    pub async fn build_sse(
+        path: web::Path<Uuid>,
+        query: web::Query<RangeQuery>,
+        req: HttpRequest,
+    ) -> Response {
+        serve_sse(Log::Build(handles::build(path.into_inner())), query, req).await
+    }
+    pub async fn action_sse(
+        path: web::Path<Uuid>,
+        query: web::Query<RangeQuery>,
+        req: HttpRequest,
+    ) -> Response {
+        serve_sse(Log::Action(handles::action(path.into_inner())), query, req).await
     }
 }
 
@@ -278,20 +461,29 @@ async fn raw_request(
     web::Json(handle_request(user.0, body.into_inner()).await)
 }
 
+#[derive(serde::Deserialize)]
+struct EventsQuery {
+    since: Option<i64>,
+}
+
 async fn events() -> Option<HttpResponse> {
     use futures::StreamExt;
-    EVENT_LOGGER.listen().map(|stream| {
+    EVENT_LOGGER.listen(None).map(|stream| {
         HttpResponse::Ok()
             .content_type(actix_web::http::header::ContentType::plaintext())
-            .streaming(stream.map(|x: typhon_types::Event| {
+            .streaming(stream.map(|x: typhon_types::LoggedEvent| {
                 Ok::<_, actix_web::Error>(actix_web::web::Bytes::from(format!(
                     "{}\n",
-                    serde_json::to_string(&x).unwrap()
+                    serde_json::to_string(&x.event).unwrap()
                 )))
             }))
     })
 }
 
+async fn events_sse(req: HttpRequest, query: web::Query<EventsQuery>) -> Option<HttpResponse> {
+    streams::events(streams::last_event_id(&req).or(query.since))
+}
+
 async fn webhook(
     path: web::Path<String>,
     req: HttpRequest,
@@ -332,33 +524,43 @@ pub fn config(cfg: &mut web::ServiceConfig) {
         web::scope("/api")
             .route("", web::post().to(raw_request))
             .route("/events", web::get().to(events))
+            .route("/events/sse", web::get().to(events_sse))
+            .route("/ws", web::get().to(streams::websocket))
             .route("/search", web::post().to(search))
             .route("/log", web::post().to(log_routes::generic))
             .service(
                 web::scope("/builds/{build}")
                     .route("", web::get().to(build_info))
-                    .route("/log", web::get().to(log_routes::build)),
+                    .route("/log", web::get().to(log_routes::build))
+                    .route("/log/sse", web::get().to(log_routes::build_sse)),
             )
             .service(
                 web::scope("/projects/{project}")
                     .route("", web::get().to(project_info))
                     .route("/create", web::post().to(create_project))
-                    //.route("/delete", web::post().to(project_delete))
+                    .route("/delete", web::post().to(project_delete))
                     .route("/refresh", web::post().to(project_refresh))
                     .route("/update_jobsets", web::post().to(project_update_jobsets))
                     .route("/set_decl", web::post().to(project_set_decl))
                     .route("/webhook", web::post().to(webhook))
+                    .route("/roles/{user}", web::post().to(project_set_role))
                     .service(
                         web::scope("/jobsets/{jobset}")
                             .route("", web::get().to(jobset_info))
-                            .route("/evaluate", web::post().to(jobset_evaluate)),
+                            .route("/evaluate", web::post().to(jobset_evaluate))
+                            .route(
+                                "/jobs/{system}/{job}/history",
+                                web::get().to(jobset_job_history),
+                            ),
                     ),
             )
             .service(
                 web::scope("/evaluations/{evaluation}")
                     .route("", web::get().to(evaluation_info))
                     .route("/cancel", web::post().to(evaluation_cancel))
+                    .route("/compare/{base}", web::get().to(evaluation_compare))
                     .route("/log", web::get().to(log_routes::evaluation))
+                    .route("/log/sse", web::get().to(log_routes::evaluation_sse))
                     .service(
                         web::scope("/jobs/{system}/{job}")
                             .route("", web::get().to(job_info))
@@ -366,7 +568,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
                             .route("/dist/{path:.*}", web::get().to(dist))
                             .service(
                                 web::scope("/runs/{run}")
-                                    //.route("/cancel", web::post().to(run_cancel))
+                                    .route("/cancel", web::post().to(run_cancel))
                                     .route("", web::get().to(run_info)),
                             ),
                     ),
@@ -374,7 +576,23 @@ pub fn config(cfg: &mut web::ServiceConfig) {
             .service(
                 web::scope("/actions/{action}")
                     .route("", web::get().to(action_info))
-                    .route("/log", web::get().to(log_routes::action)),
+                    .route("/log", web::get().to(log_routes::action))
+                    .route("/log/sse", web::get().to(log_routes::action_sse)),
+            )
+            .service(
+                web::scope("/users/{user}")
+                    .route("/create", web::post().to(create_user))
+                    .route("/delete", web::post().to(delete_user))
+                    .route("/login", web::post().to(user_login))
+                    .route("/set_password", web::post().to(set_password)),
+            )
+            .route("/queue", web::get().to(queue))
+            .route("/prune", web::post().to(prune))
+            .route("/tokens", web::get().to(tokens))
+            .service(
+                web::scope("/tokens/{token}")
+                    .route("/create", web::post().to(create_token))
+                    .route("/revoke", web::post().to(revoke_token)),
             )
             .route("/login", web::post().to(login))
             .route(
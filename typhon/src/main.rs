@@ -1,4 +1,5 @@
 mod api;
+mod streams;
 
 use actix_files::Files;
 use actix_session::storage::CookieSessionStore;
@@ -13,6 +14,13 @@ use typhon_webapp::App;
 
 const RANDOM_KEY: &str = "random";
 
+fn parse_system_limit(s: &str) -> Result<(String, usize), String> {
+    let (system, n) = s
+        .split_once('=')
+        .ok_or(format!("expected `system=n`, got `{}`", s))?;
+    Ok((system.to_string(), n.parse().map_err(|e| format!("{}", e))?))
+}
+
 /// Typhon, Nix-based continuous integration
 #[derive(Parser)]
 #[command(name = "Typhon")]
@@ -31,6 +39,95 @@ pub struct Args {
     }}, default_value=RANDOM_KEY, env)]
     pub cookie_secret: Key,
 
+    /// Maximum number of concurrent builds
+    #[arg(long, env)]
+    pub max_builds: Option<usize>,
+
+    /// Maximum number of concurrent builds for a system, as `system=n`
+    #[arg(long, value_parser=parse_system_limit, value_delimiter=',', env)]
+    pub max_builds_per_system: Vec<(String, usize)>,
+
+    /// Maximum number of concurrent actions
+    #[arg(long, env)]
+    pub max_actions: Option<usize>,
+
+    /// Number of parallel workers evaluating the jobs of a jobset
+    #[arg(long, env, default_value_t = 1)]
+    pub eval_workers: usize,
+
+    /// Memory limit of an evaluation worker in MiB, after which it is restarted
+    #[arg(long, env)]
+    pub eval_max_memory_size: Option<usize>,
+
+    /// Keep at least this many evaluations per jobset when pruning
+    #[arg(long, env)]
+    pub keep_evaluations: Option<u32>,
+
+    /// Keep evaluations younger than this many days when pruning
+    #[arg(long, env)]
+    pub keep_days: Option<u32>,
+
+    /// Kill builds running for longer than this many seconds
+    #[arg(long, env)]
+    pub build_timeout: Option<u64>,
+
+    /// Kill builds that produce no output for this many seconds
+    #[arg(long, env)]
+    pub build_max_silent_time: Option<u64>,
+
+    /// Kill actions running for longer than this many seconds
+    #[arg(long, env)]
+    pub action_timeout: Option<u64>,
+
+    /// Kill actions that produce no output for this many seconds
+    #[arg(long, env)]
+    pub action_max_silent_time: Option<u64>,
+
+    /// How actions are isolated: `bwrap`, `systemd-run` or `none`
+    #[arg(long, env, default_value = "bwrap")]
+    pub sandbox: typhon_core::SandboxBackend,
+
+    /// Cut actions from the network (bwrap only)
+    #[arg(long, env)]
+    pub sandbox_no_network: bool,
+
+    /// Extra paths bound read-only in the sandbox of actions (bwrap only)
+    #[arg(long, value_delimiter = ',', env)]
+    pub sandbox_bind: Vec<String>,
+
+    /// Size of the tmpfs mounted on `/tmp` for actions, in bytes (bwrap only)
+    #[arg(long, env)]
+    pub sandbox_tmpfs_size: Option<u64>,
+
+    /// Uid and gid of actions inside of the sandbox (bwrap only)
+    #[arg(long, env)]
+    pub sandbox_uid: Option<u32>,
+
+    /// Memory limit of an action in bytes, enforced by a systemd scope
+    #[arg(long, env)]
+    pub sandbox_memory_max: Option<u64>,
+
+    /// CPU quota of an action in percent of one CPU, enforced by a systemd scope
+    #[arg(long, env)]
+    pub sandbox_cpu_quota: Option<u32>,
+
+    /// Start the runs and evaluations interrupted by a restart again
+    #[arg(long, env)]
+    pub resume_interrupted: bool,
+
+    /// The directory where Typhon stores its files, such as logs
+    #[arg(long, env, default_value = ".")]
+    pub state_dir: std::path::PathBuf,
+
+    /// Unix socket on which to accept build agents, e.g.
+    /// `/run/typhon/agents.sock`. Remote agents connect through an SSH tunnel.
+    #[arg(long, env, requires = "agents_password")]
+    pub agents_socket: Option<String>,
+
+    /// The Argon2id hash of the password of build agents
+    #[arg(long, env)]
+    pub agents_password: Option<String>,
+
     /// Silence all output
     #[arg(long, short, env)]
     pub quiet: bool,
@@ -46,7 +143,51 @@ async fn main() -> std::io::Result<()> {
 
     let args = Args::parse();
 
-    typhon_core::init(&args.password);
+    typhon_core::init(
+        typhon_core::Settings {
+            password: typhon_core::password_hash(&args.password),
+            evaluator: typhon_core::Evaluator {
+                workers: args.eval_workers,
+                max_memory_size: args.eval_max_memory_size,
+            },
+            limits: typhon_core::Limits {
+                actions: args.max_actions,
+                builds: args.max_builds,
+                systems: args.max_builds_per_system.iter().cloned().collect(),
+            },
+            retention: typhon_core::Retention {
+                evaluations: args.keep_evaluations,
+                days: args.keep_days,
+            },
+            sandbox: typhon_core::Sandbox {
+                backend: args.sandbox,
+                policy: typhon_core::SandboxPolicy {
+                    network: !args.sandbox_no_network,
+                    binds: args.sandbox_bind.clone(),
+                    tmpfs_size: args.sandbox_tmpfs_size,
+                    uid: args.sandbox_uid,
+                    memory_max: args.sandbox_memory_max,
+                    cpu_quota: args.sandbox_cpu_quota,
+                },
+            },
+            state_dir: args.state_dir.clone(),
+            timeouts: typhon_core::Timeouts {
+                actions: typhon_core::Timeout {
+                    timeout: args.action_timeout,
+                    max_silent_time: args.action_max_silent_time,
+                },
+                builds: typhon_core::Timeout {
+                    timeout: args.build_timeout,
+                    max_silent_time: args.build_max_silent_time,
+                },
+            },
+        },
+        args.resume_interrupted,
+    );
+
+    if let (Some(path), Some(password)) = (&args.agents_socket, &args.agents_password) {
+        typhon_core::listen_agents(path, password);
+    }
 
     // Run actix server
     let conf = get_configuration(None).await.unwrap();
@@ -0,0 +1,242 @@
+use typhon_core::EVENT_LOGGER;
+use typhon_types::data::LogRange;
+use typhon_types::handles;
+use typhon_types::websocket::{ClientMsg, ServerMsg};
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{Codec, Frame, Message};
+use actix_web::body::{BodyStream, MessageBody};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::stream::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+const HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// A message of a `text/event-stream` response
+pub struct Sse {
+    pub id: Option<String>,
+    pub event: Option<&'static str>,
+    pub data: String,
+}
+
+impl Sse {
+    fn to_bytes(&self) -> Bytes {
+        let mut msg = String::new();
+        if let Some(id) = &self.id {
+            msg.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(event) = self.event {
+            msg.push_str(&format!("event: {}\n", event));
+        }
+        // carriage returns would end the line
+        for line in self.data.replace('\r', "").split('\n') {
+            msg.push_str(&format!("data: {}\n", line));
+        }
+        msg.push('\n');
+        Bytes::from(msg)
+    }
+}
+
+/// Serves a stream of server-sent events, with heartbeats so that proxies do
+/// not close idle connections.
+pub fn sse_response(stream: impl Stream<Item = Sse> + 'static) -> HttpResponse {
+    let stream = async_stream::stream! {
+        let mut stream = Box::pin(stream);
+        let mut heartbeat = tokio::time::interval(HEARTBEAT);
+        loop {
+            let bytes = tokio::select! {
+                _ = heartbeat.tick() => Bytes::from(": heartbeat\n\n"),
+                msg = stream.next() => match msg {
+                    Some(msg) => msg.to_bytes(),
+                    None => break,
+                },
+            };
+            yield Ok::<_, actix_web::Error>(bytes);
+        }
+    };
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream)
+}
+
+/// The id of the last event received by a reconnecting client
+pub fn last_event_id(req: &HttpRequest) -> Option<i64> {
+    req.headers()
+        .get("Last-Event-ID")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// The lines of a log as server-sent events, identified by their number, the
+/// first one being `start`. The stream ends with an `end` event, after which
+/// clients must not reconnect.
+pub fn log_events(
+    lines: Option<impl Stream<Item = String> + 'static>,
+    start: u64,
+) -> impl Stream<Item = Sse> {
+    async_stream::stream! {
+        if let Some(lines) = lines {
+            let mut lines = Box::pin(lines.enumerate());
+            while let Some((i, line)) = lines.next().await {
+                yield Sse {
+                    id: Some((start + i as u64).to_string()),
+                    event: None,
+                    data: line,
+                };
+            }
+        }
+        yield Sse {
+            id: None,
+            event: Some("end"),
+            data: String::new(),
+        };
+    }
+}
+
+pub fn events(since: Option<i64>) -> Option<HttpResponse> {
+    EVENT_LOGGER.listen(since).map(|stream| {
+        sse_response(stream.map(|event| Sse {
+            id: Some(event.id.to_string()),
+            event: None,
+            data: serde_json::to_string(&event).unwrap(),
+        }))
+    })
+}
+
+fn send(sender: &mpsc::UnboundedSender<Message>, msg: ServerMsg) -> bool {
+    let text = serde_json::to_string(&msg).unwrap();
+    sender.send(Message::Text(text.into())).is_ok()
+}
+
+fn listen_log(log: handles::Log, sender: mpsc::UnboundedSender<Message>) -> JoinHandle<()> {
+    actix_web::rt::spawn(async move {
+        let lines = {
+            let log = log.clone();
+            web::block(move || typhon_core::log(log, LogRange::All)).await
+        };
+        if let Ok(Ok(Some(lines))) = lines {
+            let mut lines = Box::pin(lines);
+            while let Some(line) = lines.next().await {
+                let log = log.clone();
+                if !send(&sender, ServerMsg::Line { log, line }) {
+                    return;
+                }
+            }
+        }
+        send(&sender, ServerMsg::End(log));
+    })
+}
+
+fn listen_events(since: Option<i64>, sender: mpsc::UnboundedSender<Message>) -> JoinHandle<()> {
+    actix_web::rt::spawn(async move {
+        if let Some(events) = EVENT_LOGGER.listen(since) {
+            let mut events = Box::pin(events);
+            while let Some(event) = events.next().await {
+                if !send(&sender, ServerMsg::Event(event)) {
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Reads the frames sent by the client until it closes the connection.
+async fn serve(mut payload: web::Payload, sender: mpsc::UnboundedSender<Message>) {
+    let mut codec = Codec::new();
+    let mut buffer = BytesMut::new();
+    let mut events: Option<JoinHandle<()>> = None;
+    let mut logs: HashMap<handles::Log, JoinHandle<()>> = HashMap::new();
+    let heartbeat = {
+        let sender = sender.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT);
+            loop {
+                interval.tick().await;
+                if sender.send(Message::Ping(Bytes::new())).is_err() {
+                    break;
+                }
+            }
+        })
+    };
+    'connection: while let Some(Ok(chunk)) = payload.next().await {
+        buffer.extend_from_slice(&chunk);
+        loop {
+            let frame = match codec.decode(&mut buffer) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(_) => break 'connection,
+            };
+            match frame {
+                Frame::Text(text) => match serde_json::from_slice(&text) {
+                    Ok(ClientMsg::Events { since }) => {
+                        if let Some(handle) = events.replace(listen_events(since, sender.clone())) {
+                            handle.abort();
+                        }
+                    }
+                    Ok(ClientMsg::Log(log)) => {
+                        let handle = listen_log(log.clone(), sender.clone());
+                        if let Some(handle) = logs.insert(log, handle) {
+                            handle.abort();
+                        }
+                    }
+                    Ok(ClientMsg::Unlisten(log)) => {
+                        if let Some(handle) = logs.remove(&log) {
+                            handle.abort();
+                        }
+                    }
+                    Err(e) => tracing::debug!("unexpected WebSocket message: {}", e),
+                },
+                Frame::Ping(bytes) => {
+                    let _ = sender.send(Message::Pong(bytes));
+                }
+                Frame::Close(reason) => {
+                    let _ = sender.send(Message::Close(reason));
+                    break 'connection;
+                }
+                _ => (),
+            }
+        }
+    }
+    heartbeat.abort();
+    if let Some(handle) = events {
+        handle.abort();
+    }
+    for handle in logs.values() {
+        handle.abort();
+    }
+}
+
+/// Serves a WebSocket, see `typhon_types::websocket` for the protocol.
+pub async fn websocket(
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut res = actix_http::ws::handshake(req.head())?;
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    actix_web::rt::spawn(serve(payload, sender));
+    let stream = async_stream::stream! {
+        let mut codec = Codec::new();
+        while let Some(msg) = receiver.recv().await {
+            let close = matches!(msg, Message::Close(_));
+            let mut buffer = BytesMut::new();
+            if codec.encode(msg, &mut buffer).is_err() {
+                break;
+            }
+            yield Ok::<_, std::convert::Infallible>(buffer.freeze());
+            if close {
+                break;
+            }
+        }
+    };
+    let res = res.message_body(BodyStream::new(stream).boxed())?;
+    Ok(HttpResponse::from(res))
+}